@@ -1,3 +1,33 @@
+use std::fmt;
+
+/// Errors returned when constructing an `Array2`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Array2Error {
+    /// The number of elements given does not match `width * height`
+    DimensionMismatch {
+        width: usize,
+        height: usize,
+        len: usize,
+    },
+}
+
+impl fmt::Display for Array2Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Array2Error::DimensionMismatch { width, height, len } => write!(
+                f,
+                "Invalid number of elements: a {}x{} array needs {} but {} were given",
+                width,
+                height,
+                *width as u128 * *height as u128,
+                len
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Array2Error {}
+
 pub struct Array2<T> {
     pub width: usize,
     pub height: usize,
@@ -10,10 +40,14 @@ impl<T: Clone> Array2<T> {
         width: usize,
         height: usize,
         elements: Vec<T>,
-    ) -> Result<Self, &'static str> {
+    ) -> Result<Self, Array2Error> {
         // ensure the board has valid number of elements
-        if elements.len() != width * height {
-            return Err("Invalid number of elements");
+        if width.checked_mul(height) != Some(elements.len()) {
+            return Err(Array2Error::DimensionMismatch {
+                width,
+                height,
+                len: elements.len(),
+            });
         }
 
         // fill a vec with elements in row-major order
@@ -38,10 +72,14 @@ impl<T: Clone> Array2<T> {
         width: usize,
         height: usize,
         elements: Vec<T>,
-    ) -> Result<Self, &'static str> {
+    ) -> Result<Self, Array2Error> {
         // ensure the board has valid number of elements
-        if elements.len() != width * height {
-            return Err("Invalid number of elements");
+        if width.checked_mul(height) != Some(elements.len()) {
+            return Err(Array2Error::DimensionMismatch {
+                width,
+                height,
+                len: elements.len(),
+            });
         }
 
         // fill a vec with elements in column-major order
@@ -113,4 +151,26 @@ impl<T: Clone> Array2<T> {
     pub fn data(&self) -> &Vec<T> {
         &self.data
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrong_element_count_is_an_error() {
+        let err = Array2::from_row_major(3, 2, vec![0; 5]).err().unwrap();
+        assert_eq!(err, Array2Error::DimensionMismatch { width: 3, height: 2, len: 5 });
+        assert_eq!(err.to_string(), "Invalid number of elements: a 3x2 array needs 6 but 5 were given");
+        assert!(Array2::from_col_major(2, 3, vec![0; 7]).is_err());
+        assert!(Array2::from_row_major(3, 2, vec![0; 6]).is_ok());
+    }
+
+    #[test]
+    fn huge_dimensions_do_not_overflow() {
+        let err = Array2::from_row_major(usize::MAX, 2, vec![0_u8; 2]).err().unwrap();
+        assert_eq!(err, Array2Error::DimensionMismatch { width: usize::MAX, height: 2, len: 2 });
+        let needed = usize::MAX as u128 * 2;
+        assert!(err.to_string().contains(&needed.to_string()));
+    }
+}
@@ -1,3 +1,29 @@
+use std::fmt;
+
+/// Errors returned when a value cannot be stored in a bit field
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitpackError {
+    /// The unsigned `value` does not fit in `width` unsigned bits
+    UnsignedOverflow { width: u64, value: u64 },
+    /// The signed `value` does not fit in `width` signed bits
+    SignedOverflow { width: u64, value: i64 },
+}
+
+impl fmt::Display for BitpackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BitpackError::UnsignedOverflow { width, value } => {
+                write!(f, "{} does not fit in {} unsigned bits", value, width)
+            }
+            BitpackError::SignedOverflow { width, value } => {
+                write!(f, "{} does not fit in {} signed bits", value, width)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BitpackError {}
+
 
 /// Returns true iff the signed value `n` fits into `width` signed bits.
 /// 
@@ -35,7 +61,7 @@ pub fn gets(word: u64, width: u64, lsb: u64) -> i64 {
     if value & sign_bit == 0 {
         value as i64
     } else {
-        ((value as i64) - (1_i64 << width)) as i64
+        (value as i64) - (1_i64 << width)
     }
 }
 
@@ -54,7 +80,7 @@ pub fn getu(word: u64, width: u64, lsb: u64) -> u64 {
 /// Return a modified version of the unsigned `word`,
 /// which has been updated so that the `width` bits beginning at
 /// least-significant bit `lsb` now contain the unsigned `value`.
-/// Returns an `Option` which will be None iff the value does not fit
+/// Returns a `BitpackError::UnsignedOverflow` iff the value does not fit
 /// in `width` unsigned bits.
 /// 
 /// # Arguments:
@@ -62,24 +88,23 @@ pub fn getu(word: u64, width: u64, lsb: u64) -> u64 {
 /// * `width`: the width of a bit field
 /// * `lsb`: the least-significant bit of the bit field
 /// * `value`: the unsigned value to place into that bit field
-pub fn newu(word: u64, width: u64, lsb: u64, value: u64) -> Option<u64> {
+pub fn newu(word: u64, width: u64, lsb: u64, value: u64) -> Result<u64, BitpackError> {
     let max_value = (1_u64 << width) - 1;
 
     if value <= max_value {
         let mask = max_value << lsb;
         let cleared_word: u64 = word & !mask;
         let packed_word: u64 = cleared_word | ((value & ((1u64 << width) - 1)) << lsb);
-        Some(packed_word)
+        Ok(packed_word)
     } else {
-        println!("{}", value);
-        None
+        Err(BitpackError::UnsignedOverflow { width, value })
     }
 }
 
 /// Return a modified version of the unsigned `word`,
 /// which has been updated so that the `width` bits beginning at
 /// least-significant bit `lsb` now contain the signed `value`.
-/// Returns an `Option` which will be None iff the value does not fit
+/// Returns a `BitpackError::SignedOverflow` iff the value does not fit
 /// in `width` signed bits.
 /// 
 /// # Arguments:
@@ -87,7 +112,7 @@ pub fn newu(word: u64, width: u64, lsb: u64, value: u64) -> Option<u64> {
 /// * `width`: the width of a bit field
 /// * `lsb`: the least-significant bit of the bit field
 /// * `value`: the signed value to place into that bit field
-pub fn news(word: u64, width: u64, lsb: u64, value: i64) -> Option<u64> {
+pub fn news(word: u64, width: u64, lsb: u64, value: i64) -> Result<u64, BitpackError> {
     let min_value = -(1_i64 << (width - 1));
     let max_value = (1_i64 << (width - 1)) - 1;
 
@@ -96,9 +121,8 @@ pub fn news(word: u64, width: u64, lsb: u64, value: i64) -> Option<u64> {
         let mask = ((1u64 << width) - 1) << lsb;
         let cleared_word = word & !mask;
         let packed_word = cleared_word | (signed_value_to_pack << lsb);
-        Some(packed_word)
+        Ok(packed_word)
     } else {
-        println!("{}", value);
-        None
+        Err(BitpackError::SignedOverflow { width, value })
     }
 }
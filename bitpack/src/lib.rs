@@ -3,7 +3,7 @@ pub mod bitpack;
 
 #[cfg(test)]
 mod tests {
-    use crate::bitpack::{fitss, fitsu, gets, getu, newu, news};
+    use crate::bitpack::{fitss, fitsu, gets, getu, newu, news, BitpackError};
 
     #[test]
     fn test_fitss() {
@@ -28,12 +28,20 @@ mod tests {
 
     #[test]
     fn test_newu() {
-        assert_eq!(newu(0_u64, 4, 0, 10), Some(10_u64));
-        assert_eq!(newu(0b100, 3, 8, 0b110), Some(0b011000000100));
+        assert_eq!(newu(0_u64, 4, 0, 10), Ok(10_u64));
+        assert_eq!(newu(0b100, 3, 8, 0b110), Ok(0b011000000100));
+        assert_eq!(
+            newu(0, 3, 0, 8),
+            Err(BitpackError::UnsignedOverflow { width: 3, value: 8 })
+        );
     }
 
     #[test]
     fn test_news() {
-        assert_eq!(news(0b0, 3, 5, -3), Some(0b000010100000));
+        assert_eq!(news(0b0, 3, 5, -3), Ok(0b000010100000));
+        assert_eq!(
+            news(0, 3, 0, 4),
+            Err(BitpackError::SignedOverflow { width: 3, value: 4 })
+        );
     }
 }
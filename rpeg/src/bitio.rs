@@ -0,0 +1,100 @@
+use bitpack::bitpack::{gets, news, BitpackError};
+
+/// Accumulates fields of arbitrary width into a byte stream, most-significant bit first
+#[derive(Default)]
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    n: u32,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        BitWriter::default()
+    }
+
+    /// Appends the low `width` bits of `value`
+    pub fn put(&mut self, value: u64, width: u32) {
+        for i in (0..width).rev() {
+            self.acc = self.acc << 1 | (value >> i & 1);
+            self.n += 1;
+            if self.n == 8 {
+                self.bytes.push(self.acc as u8);
+                self.acc = 0;
+                self.n = 0;
+            }
+        }
+    }
+
+    /// Appends the signed `value` as a `width`-bit two's complement field
+    pub fn put_signed(&mut self, value: i64, width: u32) -> Result<(), BitpackError> {
+        let word = news(0, width as u64, 0, value)?;
+        self.put(word, width);
+        Ok(())
+    }
+
+    /// Pads the last byte with zeros and returns the stream
+    pub fn finish(mut self) -> Vec<u8> {
+        if self.n > 0 {
+            self.bytes.push((self.acc << (8 - self.n)) as u8);
+        }
+        self.bytes
+    }
+}
+
+/// Reads back fields written by a `BitWriter`
+pub struct BitReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        BitReader { bytes, pos: 0 }
+    }
+
+    /// Reads the next `width` bits as an unsigned value, or `None` past the end of the stream
+    pub fn get(&mut self, width: u32) -> Option<u64> {
+        let mut value = 0_u64;
+        for _ in 0..width {
+            let byte = self.bytes.get(self.pos / 8)?;
+            value = value << 1 | (*byte as u64 >> (7 - self.pos % 8) & 1);
+            self.pos += 1;
+        }
+        Some(value)
+    }
+
+    /// Number of bytes the fields read so far occupy, counting a partly read byte
+    pub fn bytes_read(&self) -> usize {
+        self.pos.div_ceil(8)
+    }
+
+    /// Reads the next `width` bits as a two's complement value
+    pub fn get_signed(&mut self, width: u32) -> Option<i64> {
+        if width == 0 {
+            return Some(0);
+        }
+        self.get(width).map(|v| gets(v, width as u64, 0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_and_read_back() {
+        let mut writer = BitWriter::new();
+        writer.put(0b101, 3);
+        writer.put_signed(-3, 4).unwrap();
+        writer.put(0xabcd, 16);
+        let bytes = writer.finish();
+        assert_eq!(bytes.len(), 3);
+
+        let mut reader = BitReader::new(&bytes);
+        assert_eq!(reader.get(3), Some(0b101));
+        assert_eq!(reader.get_signed(4), Some(-3));
+        assert_eq!(reader.get(16), Some(0xabcd));
+        assert_eq!(reader.get(8), None);
+    }
+}
@@ -0,0 +1,69 @@
+/// CRC-32 lookup table for the reflected IEEE polynomial, as used by zlib and PNG
+const TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { 0xedb88320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Computes a CRC-32 over data supplied in pieces
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Crc32 { state: 0xffffffff }
+    }
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32::default()
+    }
+
+    /// Adds `bytes` to the checksum
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.state = TABLE[((self.state ^ byte as u32) & 0xff) as usize] ^ (self.state >> 8);
+        }
+    }
+
+    /// Returns the checksum of everything added so far
+    pub fn finish(&self) -> u32 {
+        !self.state
+    }
+}
+
+/// Computes the CRC-32 of `bytes`
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(bytes);
+    crc.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_checksums() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), 0xcbf43926);
+    }
+}
@@ -1,121 +1,955 @@
+use std::io::{Read, Write};
+
 use array2::Array2;
-use csc411_image;
-use csc411_image::{Read, Rgb, RgbImage, Write};
-use csc411_rpegio::{output_rpeg_data, input_rpeg_data};
-
-use crate::{bitpack, pack_2x2_elements, unpack_2x2_pixels};
-//use crate::compute_cv_byte;
-use crate::quantize::{encodes, encodeu};
-use crate::to_component_video::to_ypbpr;
-use crate::to_component_video::{from_ypbpr};
-use crate::to_rgb_float::{to_rgbf32, from_rgb32};
-use crate::trim_to_even_dimensions;
+use csc411_image::{Gray, GrayImage, Rgb, RgbImage};
+
+use crate::dct::{self, DctTables};
+use crate::dither::{dither_plane, Dither};
+use crate::entropy::{self, Coding};
+use crate::error::RpegError;
+use crate::filter::{deblock, upsample_chroma, ChromaUpsampling};
+use crate::fused;
+use crate::format::{
+    bytes_to_words, join_alpha, join_tiles, read_rpeg, split_alpha, split_chroma, split_tiles, words_to_bytes,
+    write_rpeg, Header,
+};
+use crate::layout::WordLayout;
+use crate::lossless;
+use crate::padding::{crop, pad_to_multiple, Padding};
+use crate::parallel::{map_array, map_bands};
+use crate::pnm::{Image, RgbaImage};
+use crate::progressive::{from_progressive, to_progressive};
+use crate::quantize::{ChromaCurve, MIN_CHROMA_BITS};
+use crate::quality::{gray_layout_for_quality, layout_for_quality, tables_for_quality, MAX_QUALITY, MIN_QUALITY};
+use crate::{bitpack, pack_2x2_elements, unpack_2x2_pixels_from_float_with_threads};
+use crate::subsampling::{decode_extra_chroma, encode_extra_chroma, Subsampling};
+use crate::tile::{blank_image, crop_gray, crop_rgb, crop_rgba, paste, tiles, Region};
+use crate::to_component_video::{ColorSpace, YCoCgR, YPbPr};
+use crate::to_rgb_float::{from_rgb32_with_denominator, to_rgbf32_with_denominator};
+use crate::transform::Transform;
 
 use crate::unpack_bits;
 
-/// Performs all functions to compress an image, including trimming the image, converting to RgbF32, then to component video,
-/// then packing the pixels into 2x2 groups, quantizing, and then bitpacking.
-/// 
+/// Settings that control how an image is compressed
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Options {
+    pub layout: WordLayout,
+    pub padding: Padding,
+    pub transform: Transform,
+    /// The quantization tables used by `Transform::Dct8x8`
+    pub tables: DctTables,
+    /// A quality in 1..=100 that, when set, replaces `layout` and `tables` with the ones
+    /// `quality::layout_for_quality` and `quality::tables_for_quality` derive from it
+    pub quality: Option<u8>,
+    /// How the 2x2 blocks are written; Huffman coding is lossless, so it only changes the size
+    pub coding: Coding,
+    /// The colour space the blocks are coded in; grayscale images ignore it, and
+    /// `Transform::Lossless` always uses YCoCg-R
+    pub color: ColorSpace,
+    /// The chroma subsampling, or `None` for the transform's native scheme; only the 2x2
+    /// transform can keep fewer chroma samples than pixels
+    pub subsampling: Option<Subsampling>,
+    /// How the 2x2 transform quantizes chroma, or `None` to use the companded curve when the
+    /// layout's pb and pr fields are wider than the chroma table's 4 bits, and the table otherwise
+    pub chroma: Option<ChromaCurve>,
+    /// How the 2x2 transform dithers a and the chroma samples before quantizing them
+    pub dither: Dither,
+    /// The seed that makes dithering reproducible
+    pub seed: u64,
+    /// The most threads to split the rows of blocks across; 0 and 1 both mean the calling
+    /// thread only. The output is the same for any number of threads. Only the colour
+    /// conversion and the 2x2 transform use them; Huffman coding, the DCT, and the lossless
+    /// transform always run on the calling thread.
+    pub threads: usize,
+    /// The width and height of the tiles to split the image into, each coded as an image of
+    /// its own so `decode_region` can decode just the tiles it needs, or `None` to code the
+    /// whole image at once. It must be a multiple of the transform's block size.
+    pub tile_size: Option<u32>,
+    /// Whether to write the 2x2 blocks in progressive order, so `decompress_preview` can
+    /// make a half-scale preview from the start of the file. It needs fixed-size words and
+    /// cannot be combined with tiles or alpha.
+    pub progressive: bool,
+}
+
+/// Settings that control how an image is decompressed. They only change how the decoded
+/// blocks are post-processed, so any file can be decoded with any of them; lossless
+/// images ignore them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DecodeOptions {
+    /// Whether to smooth the seams between blocks with `filter::deblock`
+    pub deblock: bool,
+    /// How the chroma of 2x2 images with subsampled chroma is filled in between samples
+    pub chroma_upsampling: ChromaUpsampling,
+    /// The most threads to decode with, as for `Options::threads`
+    pub threads: usize,
+}
+
+/// Compresses an image into the bytes of an rpeg file.
+/// # Returns:
+/// * `Result<Vec<u8>, RpegError>`: the compressed image
+///
 /// # Arguments:
-/// * `filename`: an option &str that is the filename of the ppm image to compress
-pub fn compress(filename: Option<&str>) {
+/// * `img`: the image to compress
+/// * `options`: the settings to compress it with
+pub fn compress_image(img: &RgbImage, options: &Options) -> Result<Vec<u8>, RpegError> {
+    let mut bytes = vec![];
+    compress_to(img, options, &mut bytes)?;
+    Ok(bytes)
+}
+
+/// Performs all functions to compress an image, including padding the image, converting to RgbF32, then to component video,
+/// then transforming each block, quantizing, and then bitpacking. The compressed image is written to `out`.
+///
+/// # Arguments:
+/// * `img`: the image to compress
+/// * `options`: the settings to compress it with
+/// * `out`: where to write the compressed image
+pub fn compress_to<W: Write>(img: &RgbImage, options: &Options, mut out: W) -> Result<(), RpegError> {
+    let (header, payload) = match tile_size_for(options)? {
+        Some(size) => encode_tiled(img.width, img.height, size, options, |tile, options| {
+            encode_rgb(&crop_rgb(img, tile), 3, options)
+        })?,
+        None => encode_rgb(img, 3, options)?,
+    };
+    write_rpeg(&mut out, &header, &payload)?;
+    Ok(())
+}
+
+/// Compresses an image with an alpha channel into the bytes of an rpeg file.
+/// # Returns:
+/// * `Result<Vec<u8>, RpegError>`: the compressed image
+///
+/// # Arguments:
+/// * `img`: the image to compress
+/// * `options`: the settings to compress it with
+pub fn compress_rgba_image(img: &RgbaImage, options: &Options) -> Result<Vec<u8>, RpegError> {
+    let mut bytes = vec![];
+    compress_rgba_to(img, options, &mut bytes)?;
+    Ok(bytes)
+}
+
+/// Compresses an image with an alpha channel. The colour is compressed as `compress_to`
+/// does, while the alpha plane is coded losslessly with `entropy::encode_samples`, so
+/// the decompressed image has exactly the original alpha.
+///
+/// # Arguments:
+/// * `img`: the image to compress
+/// * `options`: the settings to compress it with
+/// * `out`: where to write the compressed image
+pub fn compress_rgba_to<W: Write>(img: &RgbaImage, options: &Options, mut out: W) -> Result<(), RpegError> {
+    let (header, payload) = match tile_size_for(options)? {
+        Some(size) => encode_tiled(img.color.width, img.color.height, size, options, |tile, options| {
+            encode_rgba(&crop_rgba(img, tile), options)
+        })?,
+        None => encode_rgba(img, options)?,
+    };
+    write_rpeg(&mut out, &header, &payload)?;
+    Ok(())
+}
+
+/// Compresses the colour and then the alpha of a transparent image
+/// # Returns:
+/// * `Result<(Header, Vec<u8>), RpegError>`: the header and the payload
+fn encode_rgba(img: &RgbaImage, options: &Options) -> Result<(Header, Vec<u8>), RpegError> {
+    let color = &img.color;
+    if img.alpha.len() != color.pixels.len() {
+        return Err(RpegError::BadImage(format!(
+            "{} alpha samples for {} pixels",
+            img.alpha.len(),
+            color.pixels.len()
+        )));
+    }
+    let (header, blocks) = encode_rgb(color, 4, options)?;
+
+    let alpha: Vec<u16> = img.alpha.iter().map(|&a| a.min(color.denominator)).collect();
+    let alpha = entropy::encode_samples(&alpha, alpha_bits(color.denominator));
+    Ok((header, join_alpha(&alpha, &blocks)))
+}
+
+/// Number of bits an alpha sample of an image with the given denominator needs
+fn alpha_bits(denominator: u16) -> u32 {
+    u16::BITS - denominator.leading_zeros()
+}
+
+/// Pads a colour image, converts it to component video, and compresses its blocks. The 2x2
+/// transform does all of that block by block with `fused::encode_rgb_2x2`, unless the
+/// image is dithered, which needs whole planes.
+/// # Returns:
+/// * `Result<(Header, Vec<u8>), RpegError>`: the header and the compressed blocks
+fn encode_rgb(img: &RgbImage, channels: u8, options: &Options) -> Result<(Header, Vec<u8>), RpegError> {
+    if img.denominator == 0 {
+        return Err(RpegError::UnsupportedDenominator(img.denominator));
+    }
+    if options.transform == Transform::Haar2x2 && options.dither == Dither::None {
+        let header = lossy_header(img.width, img.height, img.denominator, channels, options)?;
+        let payload = fused::encode_rgb_2x2(img, &header, options)?;
+        return Ok((header, payload));
+    }
+
     // Construct an Array2
-    let img = RgbImage::read(filename).unwrap();
-    let height = img.height.try_into().unwrap();
-    let width = img.width.try_into().unwrap();
-    let usize_vec: Vec<csc411_image::Rgb> = img.pixels.clone();
-    let arr: Array2<Rgb> = Array2::from_row_major(width, height, usize_vec).unwrap();
+    let height = img.height as usize;
+    let width = img.width as usize;
+    let arr: Array2<Rgb> = Array2::from_row_major(width, height, img.pixels.clone())?;
+
+    // Pad rows and/or cols to be a multiple of the block size
+    let black = Rgb { red: 0, green: 0, blue: 0 };
+    let arr_padded = pad_to_multiple(&arr, options.transform.block_size(), options.padding, black);
 
-    // Trim rows and/or cols to be an even number
-    let arr_trimmed = trim_to_even_dimensions(&arr);
+    if options.transform == Transform::Lossless {
+        subsampling_for(options)?;
+        progressive_for(options, channels)?;
+        // YCoCg-R on the integer samples keeps the colour conversion reversible
+        let mut planes = [vec![], vec![], vec![]];
+        for p in arr_padded.data() {
+            let (y, co, cg) = YCoCgR::forward_int(p.red as i32, p.green as i32, p.blue as i32);
+            planes[0].push(y);
+            planes[1].push(co);
+            planes[2].push(cg);
+        }
+        let planes = planes
+            .into_iter()
+            .map(|plane| Array2::from_row_major(arr_padded.width(), arr_padded.height(), plane))
+            .collect::<Result<Vec<_>, _>>()?;
+        return Ok(encode_lossless(&planes, img.width, img.height, img.denominator, channels));
+    }
 
     // Convert pixels to a triplet of f32s
-    let arr_f = to_rgbf32(&arr_trimmed);
+    let arr_f = to_rgbf32_with_denominator(&arr_padded, img.denominator);
 
     // Convert to component video
-    let arr_cv = to_ypbpr(&arr_f);
+    let transform = options.color.transform();
+    let arr_cv = map_array(&arr_f, options.threads, |p| transform.forward(p));
 
-    // testing to see if float values are printed (they are)
+    encode_component_video(arr_cv, img.width, img.height, img.denominator, channels, options)
+}
 
-    // set array to 2x2 pixels and values we need
-    let packed_arr = pack_2x2_elements(arr_cv);
+/// Compresses a grayscale image into the bytes of an rpeg file.
+/// # Returns:
+/// * `Result<Vec<u8>, RpegError>`: the compressed image
+///
+/// # Arguments:
+/// * `img`: the image to compress
+/// * `options`: the settings to compress it with
+pub fn compress_gray_image(img: &GrayImage, options: &Options) -> Result<Vec<u8>, RpegError> {
+    let mut bytes = vec![];
+    compress_gray_to(img, options, &mut bytes)?;
+    Ok(bytes)
+}
 
-    let mut empty_vec = vec![];
-    for (_x, _y, &ref element) in packed_arr.iter_row_major() {
-        let qa = encodeu(element.0, 9, 0.3);
-        let qb = encodes(element.1, 5, 0.3);
-        let qc = encodes(element.2, 5, 0.3);
-        let qd = encodes(element.3, 5, 0.3);
+/// Compresses a grayscale image, coding only the luminance of each block. With the 2x2
+/// transform the blocks are packed with `options.layout` if it has no chroma fields, and
+/// with `WordLayout::gray()` otherwise; a quality picks `quality::gray_layout_for_quality`.
+///
+/// # Arguments:
+/// * `img`: the image to compress
+/// * `options`: the settings to compress it with
+/// * `out`: where to write the compressed image
+pub fn compress_gray_to<W: Write>(img: &GrayImage, options: &Options, mut out: W) -> Result<(), RpegError> {
+    let (header, payload) = match tile_size_for(options)? {
+        Some(size) => encode_tiled(img.width, img.height, size, options, |tile, options| {
+            encode_gray(&crop_gray(img, tile), options)
+        })?,
+        None => encode_gray(img, options)?,
+    };
+    write_rpeg(&mut out, &header, &payload)?;
+    Ok(())
+}
 
-        /*println!(
-            "{}, {}, {}, {}, {}, {}, {}, {}",
-            element.0, qa, element.1, qb, element.2, qc, element.3, qd
-        );*/
+/// Pads a grayscale image and compresses the luminance of its blocks
+/// # Returns:
+/// * `Result<(Header, Vec<u8>), RpegError>`: the header and the compressed blocks
+fn encode_gray(img: &GrayImage, options: &Options) -> Result<(Header, Vec<u8>), RpegError> {
+    if img.denominator == 0 {
+        return Err(RpegError::UnsupportedDenominator(img.denominator));
+    }
+    if options.transform == Transform::Haar2x2 && options.dither == Dither::None {
+        let header = lossy_header(img.width, img.height, img.denominator, 1, options)?;
+        let payload = fused::encode_gray_2x2(img, &header, options)?;
+        return Ok((header, payload));
+    }
 
-        let test = bitpack(qa, qb, qc, qd, element.4 as u32, element.5 as u32).unwrap();
-        empty_vec.push(test);
+    let arr = Array2::from_row_major(img.width as usize, img.height as usize, img.pixels.clone())?;
+    let arr_padded = pad_to_multiple(&arr, options.transform.block_size(), options.padding, Gray { value: 0 });
+
+    if options.transform == Transform::Lossless {
+        subsampling_for(options)?;
+        progressive_for(options, 1)?;
+        let plane = arr_padded.data().iter().map(|p| p.value as i32).collect();
+        let plane = Array2::from_row_major(arr_padded.width(), arr_padded.height(), plane)?;
+        return Ok(encode_lossless(&[plane], img.width, img.height, img.denominator, 1));
     }
 
-    let compressed_data: Vec<[u8; 4]> = empty_vec.into_iter().map(u32::to_be_bytes).collect();
+    // a gray pixel is all luminance
+    let scale = img.denominator as f32;
+    let data = arr_padded
+        .data()
+        .iter()
+        .map(|p| YPbPr::new(p.value as f32 / scale, 0.0, 0.0))
+        .collect();
+    let arr_cv = Array2::from_row_major(arr_padded.width(), arr_padded.height(), data)?;
 
-    output_rpeg_data(&compressed_data, width, height).unwrap();
+    encode_component_video(arr_cv, img.width, img.height, img.denominator, 1, options)
 }
 
+/// Splits an image into tiles and compresses each as an image of its own
+/// # Returns:
+/// * `Result<(Header, Vec<u8>), RpegError>`: the header of the tiled image, and the tile
+///   index followed by the payload of each tile
+///
+/// # Arguments:
+/// * `width`, `height`: the dimensions of the image
+/// * `size`: the width and height of the tiles
+/// * `options`: the settings to compress it with
+/// * `encode`: compresses the part of the image in a tile, with `options` untiled
+fn encode_tiled<F>(
+    width: u32,
+    height: u32,
+    size: u32,
+    options: &Options,
+    encode: F,
+) -> Result<(Header, Vec<u8>), RpegError>
+where
+    F: Fn(Region, &Options) -> Result<(Header, Vec<u8>), RpegError>,
+{
+    let untiled = Options { tile_size: None, ..*options };
+    let whole = Region { x: 0, y: 0, width, height };
+    let mut payloads = vec![];
+    let mut header = None;
+    for tile in tiles(width, height, size) {
+        let (tile_header, payload) = encode(tile, &untiled)?;
+        header.get_or_insert(tile_header);
+        payloads.push(payload);
+    }
+    // an empty image has no tiles, but still needs a header
+    let header = match header {
+        Some(header) => header,
+        None => encode(whole, &untiled)?.0,
+    };
+    Ok((Header { width, height, tile_size: Some(size), ..header }, join_tiles(&payloads)))
+}
 
-/// Performs all functions to decompress an image, including unpacking the Array2, converting to component video, and then to RGB.
-/// 
+/// Transforms, quantizes, and packs padded component video
+/// # Returns:
+/// * `Result<(Header, Vec<u8>), RpegError>`: the header and the packed blocks
+///
 /// # Arguments:
-/// * `filename`: an option &str that is the filename of the rpeg compressed data to decompress
-pub fn decompress(filename: Option<&str>) {
-    let file = input_rpeg_data(filename);
-    let file = file.unwrap();
+/// * `arr_cv`: the padded image
+/// * `width`, `height`: the dimensions of the image before padding
+/// * `denominator`: the denominator of the original image
+/// * `channels`: 1 to code only the luminance, 3 or 4 to code the chroma as well
+/// * `options`: the settings to compress it with
+fn encode_component_video(
+    arr_cv: Array2<YPbPr>,
+    width: u32,
+    height: u32,
+    denominator: u16,
+    channels: u8,
+    options: &Options,
+) -> Result<(Header, Vec<u8>), RpegError> {
+    let header = lossy_header(width, height, denominator, channels, options)?;
+    let payload = match header.transform {
+        Transform::Haar2x2 if header.progressive => {
+            to_progressive(&header, &encode_2x2(arr_cv, &header.layout, options, header.subsampling)?)?
+        }
+        Transform::Haar2x2 => encode_2x2(arr_cv, &header.layout, options, header.subsampling)?,
+        Transform::Dct8x8 => dct::encode(&arr_cv, &header.tables, header.color_planes()),
+        Transform::Lossless => unreachable!("lossless images are coded by encode_lossless"),
+    };
+    Ok((header, payload))
+}
+
+/// Resolves the layout, tables, chroma curve, and subsampling `options` ask for and checks
+/// that they can be used together
+/// # Returns:
+/// * `Result<Header, RpegError>`: the header of the compressed image
+///
+/// # Arguments:
+/// * `width`, `height`: the dimensions of the image before padding
+/// * `denominator`: the denominator of the original image
+/// * `channels`: 1 to code only the luminance, 3 or 4 to code the chroma as well
+/// * `options`: the settings to compress it with, whose transform must not be lossless
+pub(crate) fn lossy_header(
+    width: u32,
+    height: u32,
+    denominator: u16,
+    channels: u8,
+    options: &Options,
+) -> Result<Header, RpegError> {
+    let gray = channels == 1;
+    let (layout, tables) = match options.quality {
+        Some(q) if !(MIN_QUALITY..=MAX_QUALITY).contains(&q) => return Err(RpegError::InvalidQuality(q)),
+        Some(q) if gray => (gray_layout_for_quality(q), tables_for_quality(q)),
+        Some(q) => (layout_for_quality(q), tables_for_quality(q)),
+        None if gray && options.layout.has_chroma() => (WordLayout::gray(), options.tables),
+        None => (options.layout, options.tables),
+    };
+    let layout = WordLayout { chroma: chroma_curve_for(options, &layout), ..layout };
+    layout.validate().map_err(RpegError::Layout)?;
+    tables.validate().map_err(RpegError::Layout)?;
+    if !gray && !layout.has_chroma() {
+        return Err(RpegError::Layout("a colour image needs a layout with chroma fields".to_string()));
+    }
+    if options.coding == Coding::Huffman && options.transform == Transform::Dct8x8 {
+        return Err(RpegError::Unsupported("entropy coding is only available for the 2x2 transform".to_string()));
+    }
+    let subsampling = subsampling_for(options)?;
+    if options.dither != Dither::None && options.transform != Transform::Haar2x2 {
+        return Err(RpegError::Unsupported(format!("{} dithering needs the 2x2 transform", options.dither)));
+    }
+
+    // the header records the original dimensions so the decoder can crop the padding
+    Ok(Header {
+        width,
+        height,
+        layout,
+        transform: options.transform,
+        tables,
+        coding: options.coding,
+        color: if gray { ColorSpace::default() } else { options.color },
+        subsampling,
+        denominator,
+        channels,
+        tile_size: tile_size_for(options)?,
+        progressive: progressive_for(options, channels)?,
+    })
+}
 
-    let width = file.1 / 2 as usize;
-    let height = file.2 / 2 as usize;
+/// Resolves the chroma curve of `options` for a layout, defaulting to the companded curve
+/// when the pb and pr fields have room for more than the table's 16 levels
+fn chroma_curve_for(options: &Options, layout: &WordLayout) -> ChromaCurve {
+    match options.chroma {
+        Some(curve) => curve,
+        None if layout.has_chroma() && layout.pb.width > MIN_CHROMA_BITS && layout.pr.width > MIN_CHROMA_BITS => {
+            ChromaCurve::Companded
+        }
+        None => ChromaCurve::Table,
+    }
+}
 
-    let mut decompressed_vec = vec![];
+/// Checks that the tiles `options` asks for are a whole number of blocks wide
+fn tile_size_for(options: &Options) -> Result<Option<u32>, RpegError> {
+    let block = options.transform.block_size() as u32;
+    match options.tile_size {
+        Some(size) if size == 0 || size % block != 0 => Err(RpegError::Unsupported(format!(
+            "tiles of {} pixels are not a whole number of {} transform blocks",
+            size, options.transform
+        ))),
+        Some(_) if options.progressive => Err(RpegError::Unsupported("progressive order cannot be tiled".to_string())),
+        tile_size => Ok(tile_size),
+    }
+}
 
-    for word in file.0 {
-        let vals = unpack_bits(u32::from_be_bytes(word));
-        decompressed_vec.push(vals);
+/// Checks that the blocks can be written in progressive order if `options` asks for it,
+/// which needs fixed-size 2x2 words and no alpha section ahead of them
+fn progressive_for(options: &Options, channels: u8) -> Result<bool, RpegError> {
+    if !options.progressive {
+        return Ok(false);
     }
+    if options.transform != Transform::Haar2x2 || options.coding != Coding::Fixed {
+        return Err(RpegError::Unsupported(
+            "progressive order needs the 2x2 transform with fixed-size words".to_string(),
+        ));
+    }
+    if channels == 4 {
+        return Err(RpegError::Unsupported("progressive order cannot code alpha".to_string()));
+    }
+    Ok(true)
+}
 
-    let decompressed_arr = Array2 {
+/// Resolves the chroma subsampling of `options`, which must be 4:4:4 for every transform
+/// but the 2x2 one
+fn subsampling_for(options: &Options) -> Result<Subsampling, RpegError> {
+    match options.subsampling {
+        Some(s) if s != Subsampling::S444 && options.transform != Transform::Haar2x2 => Err(RpegError::Unsupported(
+            format!("{} subsampling is only available for the 2x2 transform", s),
+        )),
+        Some(s) => Ok(s),
+        None => Ok(Subsampling::native(options.transform)),
+    }
+}
+
+/// Codes padded integer planes with `lossless::encode`. The layout and tables in the
+/// header are unused, and the quality, if any, is ignored.
+/// # Returns:
+/// * `(Header, Vec<u8>)`: the header and the coded planes
+///
+/// # Arguments:
+/// * `planes`: Y, Co, and Cg, or only the gray samples
+/// * `width`, `height`: the dimensions of the image before padding
+/// * `denominator`: the denominator of the original image
+/// * `channels`: the number of channels the header records
+fn encode_lossless(planes: &[Array2<i32>], width: u32, height: u32, denominator: u16, channels: u8) -> (Header, Vec<u8>) {
+    let header = Header {
         width,
         height,
-        data: decompressed_vec,
+        layout: WordLayout::default(),
+        transform: Transform::Lossless,
+        tables: DctTables::default(),
+        coding: Coding::Huffman,
+        color: if channels == 1 { ColorSpace::default() } else { ColorSpace::YCoCgR },
+        subsampling: Subsampling::S444,
+        denominator,
+        channels,
+        tile_size: None,
+        progressive: false,
+    };
+    (header, lossless::encode(planes))
+}
+
+/// Quantizes the 2x2 blocks of a component video Array2 with even dimensions, and either packs
+/// each block into one word or Huffman codes them. The first chroma sample of each block
+/// goes in its word and any others follow the blocks. With `options.dither`, a and the
+/// chroma samples are dithered to the levels the layout can reproduce first; b, c, and d
+/// describe detail within a block, so they are left alone.
+pub(crate) fn encode_2x2(
+    arr_cv: Array2<YPbPr>,
+    layout: &WordLayout,
+    options: &Options,
+    subsampling: Subsampling,
+) -> Result<Vec<u8>, RpegError> {
+    let width = arr_cv.width() / 2;
+    let mut chroma = map_bands(arr_cv.height() / 2, options.threads, |rows| {
+        rows.flat_map(|y| (0..width).map(move |x| (2 * x, 2 * y)))
+            .map(|(x, y)| {
+                let group = [arr_cv.get(x, y), arr_cv.get(x + 1, y), arr_cv.get(x, y + 1), arr_cv.get(x + 1, y + 1)];
+                subsampling.sample(group)
+            })
+            .collect()
+    });
+
+    // set array to 2x2 pixels and values we need
+    let mut packed_arr = pack_2x2_elements(&arr_cv, options.threads);
+    if options.dither != Dither::None {
+        dither_2x2(&mut packed_arr, &mut chroma, layout, subsampling, options);
+    }
+    let blocks = map_bands(packed_arr.height(), options.threads, |rows| {
+        rows.flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let &(a, b, c, d, _, _) = packed_arr.get(x, y);
+                let samples = &chroma[y * width + x];
+                layout.quantize((a, b, c, d, samples[0].0, samples[0].1))
+            })
+            .collect()
+    });
+
+    let mut payload = if options.coding == Coding::Huffman {
+        entropy::encode_blocks(layout, &blocks)
+    } else {
+        let words = map_bands(blocks.len(), options.threads, |range| {
+            blocks[range]
+                .iter()
+                .map(|&(qa, qb, qc, qd, pb, pr)| bitpack(layout, qa, qb, qc, qd, pb as u32, pr as u32))
+                .collect()
+        });
+        words_to_bytes(layout, &words.into_iter().collect::<Result<Vec<_>, _>>()?)
+    };
+
+    if layout.has_chroma() {
+        let extra: Vec<_> = chroma.iter().flat_map(|samples| samples[1..].iter().copied()).collect();
+        payload.extend(encode_extra_chroma(layout, &extra));
+    }
+    Ok(payload)
+}
+
+/// Dithers a in each of the packed 2x2 blocks, and each chroma sample, on their own planes:
+/// one value per block for a, and for the chroma a plane of samples laid out as they are
+/// in the image.
+///
+/// # Arguments:
+/// * `packed`: the blocks from `pack_2x2_elements`
+/// * `chroma`: the chroma samples of each block from `Subsampling::sample`
+/// * `layout`: the layout the blocks will be quantized with
+/// * `subsampling`: how the chroma samples were taken
+/// * `options`: the dithering method and seed
+fn dither_2x2(
+    packed: &mut Array2<(f32, f32, f32, f32, f32, f32)>,
+    chroma: &mut [Vec<(f32, f32)>],
+    layout: &WordLayout,
+    subsampling: Subsampling,
+    options: &Options,
+) {
+    let (width, height) = (packed.width(), packed.height());
+    let mut a = Array2::blank_state(width, height, 0.0);
+    for (x, y, block) in packed.iter_row_major() {
+        *a.get_mut(x, y) = block.0;
+    }
+    dither_plane(&mut a, &layout.luma_levels(), options.dither, options.seed);
+    for (x, y, &value) in a.iter_row_major() {
+        packed.get_mut(x, y).0 = value;
+    }
+
+    if !layout.has_chroma() {
+        return;
+    }
+    let (columns, rows) = subsampling.grid();
+    let position = |i: usize, k: usize| (i % width * columns + k % columns, i / width * rows + k / columns);
+    let mut planes = [0, 1].map(|_| Array2::blank_state(width * columns, height * rows, 0.0));
+    for (i, samples) in chroma.iter().enumerate() {
+        for (k, &(pb, pr)) in samples.iter().enumerate() {
+            let (x, y) = position(i, k);
+            *planes[0].get_mut(x, y) = pb;
+            *planes[1].get_mut(x, y) = pr;
+        }
+    }
+    for (n, (plane, levels)) in planes.iter_mut().zip(layout.chroma_levels()).enumerate() {
+        // a different seed for each plane, so that pb and pr do not round in step
+        dither_plane(plane, &levels, options.dither, options.seed.wrapping_add(n as u64 + 1));
+    }
+    for (i, samples) in chroma.iter_mut().enumerate() {
+        for (k, sample) in samples.iter_mut().enumerate() {
+            let (x, y) = position(i, k);
+            *sample = (*planes[0].get(x, y), *planes[1].get(x, y));
+        }
+    }
+}
+
+/// Reverses `encode_2x2`, producing an Array2 whose dimensions are rounded up to even numbers
+fn decode_2x2(header: &Header, payload: &[u8], threads: usize) -> Result<Array2<YPbPr>, RpegError> {
+    let width = (header.width as usize).div_ceil(2);
+    let height = (header.height as usize).div_ceil(2);
+    let reordered;
+    let payload = if header.progressive {
+        reordered = from_progressive(header, payload)?;
+        &reordered
+    } else {
+        payload
+    };
+    let (payload, extra) = split_chroma(header, payload)?;
+
+    let blocks: Vec<_> = match header.coding {
+        Coding::Fixed => bytes_to_words(&header.layout, payload)
+            .into_iter()
+            .map(|word| unpack_bits(&header.layout, word))
+            .collect(),
+        Coding::Huffman => entropy::decode_blocks(&header.layout, payload, header.blocks_2x2())?,
     };
+    let decompressed_vec = map_bands(blocks.len(), threads, |range| {
+        blocks[range].iter().map(|&block| header.layout.dequantize(block)).collect()
+    });
+    let first_chroma: Vec<_> = decompressed_vec.iter().map(|block| (block.4, block.5)).collect();
+
+    let decompressed_arr = Array2::from_row_major(width, height, decompressed_vec)?;
+    let mut unpacked = unpack_2x2_pixels_from_float_with_threads(decompressed_arr, threads);
+
+    let per_block = header.subsampling.samples_per_block() - 1;
+    if per_block == 0 || header.extra_chroma_len() == 0 {
+        return Ok(unpacked);
+    }
+    let extra = decode_extra_chroma(&header.layout, extra, first_chroma.len() * per_block)?;
+    for (i, (first, rest)) in first_chroma.iter().zip(extra.chunks_exact(per_block)).enumerate() {
+        let samples: Vec<_> = std::iter::once(*first).chain(rest.iter().copied()).collect();
+        let (x, y) = (i % width * 2, i / width * 2);
+        let pixels = [(x, y), (x + 1, y), (x, y + 1), (x + 1, y + 1)];
+        for ((px, py), (pb, pr)) in pixels.into_iter().zip(header.subsampling.spread(&samples)) {
+            let pixel = unpacked.get_mut(px, py);
+            pixel.pb = pb;
+            pixel.pr = pr;
+        }
+    }
+    Ok(unpacked)
+}
+
+/// Statistics gathered while decompressing an image
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DecodeStats {
+    /// Number of samples in the image
+    pub samples: usize,
+    /// Number of samples that fell outside [0, denominator] and were clamped
+    pub clipped: usize,
+}
+
+/// Decompresses the bytes of an rpeg file into an image. Grayscale images come back as
+/// colour images whose three samples are equal.
+/// # Returns:
+/// * `Result<RgbImage, RpegError>`: the decompressed image
+///
+/// # Arguments:
+/// * `bytes`: the contents of an rpeg file
+pub fn decompress_bytes(bytes: &[u8]) -> Result<RgbImage, RpegError> {
+    decompress_with_stats(bytes).map(|(img, _)| img)
+}
+
+/// Decompresses the bytes of an rpeg file into an image, also reporting how many samples
+/// the colour conversion pushed out of range
+/// # Returns:
+/// * `Result<(RgbImage, DecodeStats), RpegError>`: the decompressed image and its statistics
+///
+/// # Arguments:
+/// * `bytes`: the contents of an rpeg file
+pub fn decompress_with_stats(bytes: &[u8]) -> Result<(RgbImage, DecodeStats), RpegError> {
+    decompress_image(bytes).map(|(img, stats)| (img.into_rgb(), stats))
+}
+
+/// Decompresses the bytes of an rpeg file into a colour, grayscale, or transparent image,
+/// whichever was compressed
+/// # Returns:
+/// * `Result<(Image, DecodeStats), RpegError>`: the decompressed image and its statistics
+///
+/// # Arguments:
+/// * `bytes`: the contents of an rpeg file
+pub fn decompress_image(bytes: &[u8]) -> Result<(Image, DecodeStats), RpegError> {
+    decompress_image_with(bytes, &DecodeOptions::default())
+}
+
+/// Decompresses the bytes of an rpeg file like `decompress_image`, filtering the decoded
+/// blocks as `options` asks before converting them back to RGB
+/// # Returns:
+/// * `Result<(Image, DecodeStats), RpegError>`: the decompressed image and its statistics
+///
+/// # Arguments:
+/// * `bytes`: the contents of an rpeg file
+/// * `options`: the filters to apply
+pub fn decompress_image_with(bytes: &[u8], options: &DecodeOptions) -> Result<(Image, DecodeStats), RpegError> {
+    let (header, payload) = read_rpeg(bytes)?;
+    let whole = Region { x: 0, y: 0, width: header.width, height: header.height };
+    decode_tiles(&header, payload, whole, options)
+}
+
+/// Decompresses a rectangle of an rpeg file. Only the tiles that overlap it are decoded,
+/// so with a tiled file the time taken depends on the size of the rectangle rather than
+/// of the image; an untiled file is decoded whole and cropped.
+/// # Returns:
+/// * `Result<(Image, DecodeStats), RpegError>`: the pixels in the rectangle, and the
+///   statistics of the tiles that were decoded
+///
+/// # Arguments:
+/// * `bytes`: the contents of an rpeg file
+/// * `x`, `y`: the top-left corner of the rectangle
+/// * `width`, `height`: the dimensions of the rectangle, which must lie within the image
+pub fn decode_region(bytes: &[u8], x: u32, y: u32, width: u32, height: u32) -> Result<(Image, DecodeStats), RpegError> {
+    decode_region_with(bytes, Region { x, y, width, height }, &DecodeOptions::default())
+}
 
-    let unpacked_arr = unpack_2x2_pixels(decompressed_arr);
-    let returned_cv_arr = from_ypbpr(&unpacked_arr);
+/// Decompresses a rectangle of an rpeg file like `decode_region`, filtering the decoded
+/// blocks as `options` asks. Each tile is filtered on its own, so the seams between tiles
+/// are not smoothed.
+/// # Returns:
+/// * `Result<(Image, DecodeStats), RpegError>`: the pixels in the rectangle and statistics
+///
+/// # Arguments:
+/// * `bytes`: the contents of an rpeg file
+/// * `region`: the rectangle, which must lie within the image
+/// * `options`: the filters to apply
+pub fn decode_region_with(
+    bytes: &[u8],
+    region: Region,
+    options: &DecodeOptions,
+) -> Result<(Image, DecodeStats), RpegError> {
+    let (header, payload) = read_rpeg(bytes)?;
+    let right = region.x.checked_add(region.width).filter(|&right| right <= header.width);
+    let bottom = region.y.checked_add(region.height).filter(|&bottom| bottom <= header.height);
+    if right.is_none() || bottom.is_none() {
+        return Err(RpegError::BadRegion(format!(
+            "{}x{} at ({}, {}) does not fit in the {}x{} image",
+            region.width, region.height, region.x, region.y, header.width, header.height
+        )));
+    }
+    decode_tiles(&header, payload, region, options)
+}
+
+/// Decodes the tiles of an image that overlap a region and pastes them together
+fn decode_tiles(
+    header: &Header,
+    payload: &[u8],
+    region: Region,
+    options: &DecodeOptions,
+) -> Result<(Image, DecodeStats), RpegError> {
+    let payloads = split_tiles(header, payload)?;
+    if header.tile_size.is_none() && (region.width, region.height) == (header.width, header.height) {
+        return decode_payload(header, payload, options);
+    }
 
-    let returned_float_arr = from_rgb32(&returned_cv_arr);
+    let mut img = blank_image(header.channels, header.denominator, region.width, region.height);
+    let mut stats = DecodeStats::default();
+    for (i, tile) in header.tiles_over(region) {
+        let (decoded, tile_stats) = decode_payload(&header.tile_header(tile), payloads[i], options)?;
+        paste(&mut img, region, &decoded, tile);
+        stats.samples += tile_stats.samples;
+        stats.clipped += tile_stats.clipped;
+    }
+    Ok((img, stats))
+}
 
-    for (x, y, &ref element) in returned_cv_arr.iter_row_major() {
-        let modified_element = element.clone();
+/// Decodes the payload of an untiled image, or of one tile
+fn decode_payload(header: &Header, payload: &[u8], options: &DecodeOptions) -> Result<(Image, DecodeStats), RpegError> {
+    let (alpha, payload) = split_alpha(header, payload)?;
 
-        println!("{}, {}, {}", modified_element.red, modified_element.green, modified_element.blue);
+    let (img, stats) = decode_color(header, payload, options)?;
+    match img {
+        Image::Rgb(color) if header.has_alpha() => {
+            let alpha = decode_alpha(header, alpha)?;
+            Ok((Image::Rgba(RgbaImage { color, alpha }), stats))
+        }
+        img => Ok((img, stats)),
     }
+}
 
-    let image = from_array2(&returned_float_arr);
-    let _ = RgbImage::write(&image, None);
+/// Decodes the compressed blocks of an image into a colour or grayscale image
+fn decode_color(header: &Header, payload: &[u8], options: &DecodeOptions) -> Result<(Image, DecodeStats), RpegError> {
+    let (width, height) = (header.width as usize, header.height as usize);
 
+    let mut unpacked_arr = match header.transform {
+        Transform::Haar2x2 => decode_2x2(header, payload, options.threads)?,
+        Transform::Dct8x8 => dct::decode(payload, width, height, &header.tables, header.color_planes())?,
+        Transform::Lossless => return decode_lossless(header, payload),
+    };
+    // the filters run on the padded blocks, so the edges of the image are filtered like the rest
+    if options.chroma_upsampling == ChromaUpsampling::Bilinear && header.transform == Transform::Haar2x2 {
+        upsample_chroma(&mut unpacked_arr, header.subsampling);
+    }
+    if options.deblock {
+        deblock(&mut unpacked_arr, header.transform.block_size());
+    }
+    Ok(image_from_component_video(header, &unpacked_arr, options.threads))
+}
+
+/// Crops decoded component video to the dimensions in `header` and converts it to a
+/// grayscale image for 1 channel, or to a colour image with the header's colour transform
+/// # Returns:
+/// * `(Image, DecodeStats)`: the image and how many of its samples were clamped
+///
+/// # Arguments:
+/// * `header`: the dimensions, channels, colour transform, and denominator of the image
+/// * `arr`: the component video, at least as large as the image
+/// * `threads`: the most threads to convert the colours with
+pub(crate) fn image_from_component_video(header: &Header, arr: &Array2<YPbPr>, threads: usize) -> (Image, DecodeStats) {
+    let (width, height) = (header.width as usize, header.height as usize);
+    if header.channels == 1 {
+        let cropped_arr = crop(arr, width, height);
+        let (pixels, clipped) = gray_from_luma(&cropped_arr, header.denominator);
+        let stats = DecodeStats { samples: pixels.len(), clipped };
+        let img = GrayImage { width: header.width, height: header.height, denominator: header.denominator, pixels };
+        return (Image::Gray(img), stats);
+    }
 
-    //let mut vec = vec![];
+    let transform = header.color.transform();
+    let returned_cv_arr = map_array(arr, threads, |p| transform.inverse(*p));
+
+    let cropped_arr = crop(&returned_cv_arr, width, height);
+    let (returned_arr, clipped) = from_rgb32_with_denominator(&cropped_arr, header.denominator);
+
+    let stats = DecodeStats { samples: 3 * returned_arr.data().len(), clipped };
+    (Image::Rgb(from_array2(&returned_arr, header.denominator)), stats)
+}
+
+/// Reverses `encode_lossless`. Planes whose samples are out of range can only come from a
+/// damaged file, so nothing is ever clipped.
+fn decode_lossless(header: &Header, payload: &[u8]) -> Result<(Image, DecodeStats), RpegError> {
+    let (width, height) = (header.width as usize, header.height as usize);
+    let planes = lossless::decode(payload, width.div_ceil(2) * 2, height.div_ceil(2) * 2, header.color_planes())?;
+
+    let max = header.denominator as i32;
+    let out_of_range = || RpegError::BadPayload("lossless sample is out of range".to_string());
+    let sample = |plane: usize, x: usize, y: usize, min: i32| {
+        let value = *planes[plane].get(x, y);
+        if (min..=max).contains(&value) {
+            Ok(value)
+        } else {
+            Err(out_of_range())
+        }
+    };
+
+    let stats = DecodeStats { samples: header.color_planes() * width * height, clipped: 0 };
+    let (w, h, denominator) = (header.width, header.height, header.denominator);
+    let coords = (0..height).flat_map(|y| (0..width).map(move |x| (x, y)));
+
+    if header.channels == 1 {
+        let pixels = coords
+            .map(|(x, y)| Ok(Gray { value: sample(0, x, y, 0)? as u16 }))
+            .collect::<Result<_, RpegError>>()?;
+        return Ok((Image::Gray(GrayImage { width: w, height: h, denominator, pixels }), stats));
+    }
+
+    let pixels = coords
+        .map(|(x, y)| {
+            // Co and Cg are differences, so they range down to -denominator
+            let (luma, co, cg) = (sample(0, x, y, 0)?, sample(1, x, y, -max)?, sample(2, x, y, -max)?);
+            let (r, g, b) = YCoCgR::inverse_int(luma, co, cg);
+            if [r, g, b].iter().any(|v| !(0..=max).contains(v)) {
+                return Err(out_of_range());
+            }
+            Ok(Rgb { red: r as u16, green: g as u16, blue: b as u16 })
+        })
+        .collect::<Result<_, RpegError>>()?;
+    Ok((Image::Rgb(RgbImage { width: w, height: h, denominator, pixels }), stats))
+}
+
+/// Decodes the alpha samples of an image, which must not exceed its denominator
+fn decode_alpha(header: &Header, alpha: &[u8]) -> Result<Vec<u16>, RpegError> {
+    let count = header.width as usize * header.height as usize;
+    let alpha = entropy::decode_samples(alpha, count, alpha_bits(header.denominator))?;
+    if alpha.iter().any(|&a| a > header.denominator) {
+        return Err(RpegError::BadPayload("alpha sample is larger than the denominator".to_string()));
+    }
+    Ok(alpha)
+}
 
-    /*for (x, y, &ref element) in arr_rgb.iter_row_major() {
+/// Converts luminance in [0, 1] to gray samples in [0, `denominator`], rounding to the
+/// nearest integer and clamping, like `from_rgb32_with_denominator`
+/// # Returns:
+/// * `(Vec<Gray>, usize)`: the pixels in row-major order, and the number of clipped samples
+pub(crate) fn gray_from_luma(arr: &Array2<YPbPr>, denominator: u16) -> (Vec<Gray>, usize) {
+    let scale = denominator as f32;
+    let mut clipped = 0;
+    let pixels = arr
+        .iter_row_major()
+        .map(|(_, _, e)| {
+            let value = (e.y * scale).round();
+            if !(0.0..=scale).contains(&value) {
+                clipped += 1;
+            }
+            Gray { value: value.clamp(0.0, scale) as u16 }
+        })
+        .collect();
+    (pixels, clipped)
+}
 
-        let modified_element = element.clone();
+/// Checks that the bytes of an rpeg file are intact without decompressing the image: the
+/// header must parse, the checksum must match, and the payload must have the right size.
+/// Huffman-coded blocks are also decoded, since their size is only known that way.
+/// # Returns:
+/// * `Result<Header, RpegError>`: the header of the file
+///
+/// # Arguments:
+/// * `bytes`: the contents of an rpeg file
+pub fn verify_bytes(bytes: &[u8]) -> Result<Header, RpegError> {
+    let (header, payload) = read_rpeg(bytes)?;
+    let payloads = split_tiles(&header, payload)?;
+    for (tile, payload) in header.tiles().zip(payloads) {
+        verify_payload(&header.tile_header(tile), payload)?;
+    }
+    Ok(header)
+}
 
-        println!("{}, {}, : {:?}", x, y, modified_element);
-    }*/
+/// Decodes whatever parts of the payload of an untiled image, or of one tile, have a size
+/// that is only known once they are decoded
+fn verify_payload(header: &Header, payload: &[u8]) -> Result<(), RpegError> {
+    let (alpha, payload) = split_alpha(header, payload)?;
+    if header.has_alpha() {
+        decode_alpha(header, alpha)?;
+    }
+    if header.transform == Transform::Lossless {
+        decode_lossless(header, payload)?;
+    } else if header.coding == Coding::Huffman {
+        let (blocks, _) = split_chroma(header, payload)?;
+        entropy::decode_blocks(&header.layout, blocks, header.blocks_2x2())?;
+    }
+    Ok(())
+}
 
+/// Performs all functions to decompress an image, including unpacking the Array2, converting to component video, and then to RGB.
+///
+/// # Arguments:
+/// * `input`: where to read the rpeg compressed data from
+pub fn decompress_from<R: Read>(mut input: R) -> Result<RgbImage, RpegError> {
+    let mut bytes = vec![];
+    input.read_to_end(&mut bytes)?;
+    decompress_bytes(&bytes)
 }
 
-fn from_array2(arr: &Array2<Rgb>) -> RgbImage {
+fn from_array2(arr: &Array2<Rgb>, denominator: u16) -> RgbImage {
     let width = arr.width() as u32;
     let height = arr.height() as u32;
-    let denominator = 255;
     let pixels: Vec<Rgb> = arr.data().to_vec();
 
     RgbImage {
@@ -125,3 +959,530 @@ fn from_array2(arr: &Array2<Rgb>) -> RgbImage {
         pixels,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkerboard(width: u32, height: u32) -> RgbImage {
+        let pixels = (0..width * height)
+            .map(|i| {
+                let on = (i % width + i / width).is_multiple_of(2);
+                let v = if on { 200 } else { 50 };
+                Rgb { red: v, green: v / 2, blue: 255 - v }
+            })
+            .collect();
+        RgbImage { width, height, denominator: 255, pixels }
+    }
+
+    #[test]
+    fn compress_and_decompress_in_memory() {
+        let img = checkerboard(6, 4);
+        for layout in [WordLayout::default(), WordLayout::wide()] {
+            let bytes = compress_image(&img, &Options { layout, ..Default::default() }).unwrap();
+            let out = decompress_bytes(&bytes).unwrap();
+            assert_eq!((out.width, out.height, out.pixels.len()), (6, 4, 24));
+        }
+    }
+
+    #[test]
+    fn odd_dimensions_survive_round_trip() {
+        let img = checkerboard(5, 3);
+        for padding in [Padding::Edge, Padding::Mirror, Padding::Zero] {
+            let options = Options { padding, ..Default::default() };
+            let out = decompress_bytes(&compress_image(&img, &options).unwrap()).unwrap();
+            assert_eq!((out.width, out.height, out.pixels.len()), (5, 3, 15));
+        }
+    }
+
+    #[test]
+    fn dct_round_trip() {
+        let img = checkerboard(11, 9);
+        let options = Options { transform: Transform::Dct8x8, ..Default::default() };
+        let bytes = compress_image(&img, &options).unwrap();
+        let out = decompress_bytes(&bytes).unwrap();
+        assert_eq!((out.width, out.height, out.pixels.len()), (11, 9, 99));
+    }
+
+    #[test]
+    fn generic_reader_and_writer() {
+        let img = checkerboard(4, 4);
+        let mut compressed = vec![];
+        compress_to(&img, &Options::default(), &mut compressed).unwrap();
+        assert_eq!(compressed, compress_image(&img, &Options::default()).unwrap());
+
+        let out = decompress_from(&compressed[..]).unwrap();
+        assert_eq!(out.pixels.len(), 16);
+    }
+
+    #[test]
+    fn malformed_input_is_an_error() {
+        assert!(matches!(
+            decompress_bytes(b"not an rpeg file\n"),
+            Err(RpegError::BadHeader(_))
+        ));
+        assert!(matches!(
+            decompress_bytes(b"Compressed image format 2\n4 4\n\x00\x00\x00\x00"),
+            Err(RpegError::Truncated { expected: 16, actual: 4 })
+        ));
+    }
+
+    #[test]
+    fn unsupported_denominator() {
+        let mut img = checkerboard(2, 2);
+        img.denominator = 0;
+        assert!(matches!(
+            compress_image(&img, &Options::default()),
+            Err(RpegError::UnsupportedDenominator(0))
+        ));
+    }
+
+    #[test]
+    fn quality_out_of_range() {
+        let options = Options { quality: Some(0), ..Default::default() };
+        assert!(matches!(
+            compress_image(&checkerboard(2, 2), &options),
+            Err(RpegError::InvalidQuality(0))
+        ));
+    }
+
+    #[test]
+    fn huffman_matches_fixed_words() {
+        let img = checkerboard(63, 47);
+        for layout in [WordLayout::default(), WordLayout::wide()] {
+            let fixed = compress_image(&img, &Options { layout, ..Default::default() }).unwrap();
+            let options = Options { layout, coding: Coding::Huffman, ..Default::default() };
+            let huffman = compress_image(&img, &options).unwrap();
+
+            let payload_len = |bytes: &[u8]| read_rpeg(bytes).unwrap().1.len();
+            assert!(payload_len(&huffman) < payload_len(&fixed) / 4);
+            let channels = |img: RgbImage| -> Vec<_> { img.pixels.iter().map(|p| (p.red, p.green, p.blue)).collect() };
+            assert_eq!(channels(decompress_bytes(&huffman).unwrap()), channels(decompress_bytes(&fixed).unwrap()));
+        }
+
+        let options = Options { coding: Coding::Huffman, transform: Transform::Dct8x8, ..Default::default() };
+        assert!(matches!(compress_image(&img, &options), Err(RpegError::Unsupported(_))));
+    }
+
+    #[test]
+    fn damaged_files_are_reported() {
+        let mut bytes = compress_image(&checkerboard(6, 4), &Options::default()).unwrap();
+        assert_eq!(verify_bytes(&bytes).unwrap().width, 6);
+
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 0x10;
+        assert!(matches!(verify_bytes(&bytes), Err(RpegError::Corrupt { .. })));
+        assert!(matches!(decompress_bytes(&bytes), Err(RpegError::Corrupt { .. })));
+    }
+
+    #[test]
+    fn forged_block_counts_are_refused() {
+        let options = Options { coding: Coding::Huffman, ..Default::default() };
+        let bytes = compress_image(&checkerboard(6, 4), &options).unwrap();
+        let (header, payload) = read_rpeg(&bytes).unwrap();
+        let mut forged = vec![];
+        write_rpeg(&mut forged, &Header { width: u32::MAX, height: u32::MAX, ..header }, payload).unwrap();
+        assert!(matches!(verify_bytes(&forged), Err(RpegError::BadPayload(_))));
+        assert!(matches!(decompress_image(&forged), Err(RpegError::BadPayload(_))));
+    }
+
+    #[test]
+    fn clipped_samples_are_counted() {
+        // a saturated checkerboard overshoots once its blocks are averaged and quantized
+        let red = Rgb { red: 255, green: 0, blue: 0 };
+        let blue = Rgb { red: 0, green: 0, blue: 255 };
+        let pixels = (0..64).map(|i| if (i % 8 + i / 8) % 2 == 0 { red.clone() } else { blue.clone() }).collect();
+        let img = RgbImage { width: 8, height: 8, denominator: 255, pixels };
+        let bytes = compress_image(&img, &Options::default()).unwrap();
+
+        let (out, stats) = decompress_with_stats(&bytes).unwrap();
+        assert_eq!(stats.samples, 3 * out.pixels.len());
+        assert!(stats.clipped > 0);
+    }
+
+    #[test]
+    fn denominator_is_restored() {
+        // the checkerboard loses its detail in the 2x2 blocks, but no more so at other depths
+        let reference = checkerboard(6, 4);
+        let bytes = compress_image(&reference, &Options::default()).unwrap();
+        let expected = crate::quality::rmse(&reference, &decompress_bytes(&bytes).unwrap());
+        for denominator in [15, 1000, 65535] {
+            let mut img = checkerboard(6, 4);
+            for p in img.pixels.iter_mut() {
+                for sample in [&mut p.red, &mut p.green, &mut p.blue] {
+                    *sample = (*sample as u32 * denominator as u32 / 255) as u16;
+                }
+            }
+            img.denominator = denominator;
+
+            let out = decompress_bytes(&compress_image(&img, &Options::default()).unwrap()).unwrap();
+            assert_eq!(out.denominator, denominator);
+            assert!((crate::quality::rmse(&img, &out) - expected).abs() < 0.02);
+        }
+    }
+
+    #[test]
+    fn gray_round_trip() {
+        let ramp = || {
+            let pixels = (0..35 * 21).map(|i| Gray { value: ((i % 35) * 7 + i / 35) as u16 }).collect();
+            GrayImage { width: 35, height: 21, denominator: 300, pixels }
+        };
+        let img = ramp();
+        let rgb = Image::Gray(ramp()).into_rgb();
+
+        for transform in [Transform::Haar2x2, Transform::Dct8x8] {
+            let options = Options { transform, ..Default::default() };
+            let gray_bytes = compress_gray_image(&img, &options).unwrap();
+            let rgb_bytes = compress_image(&rgb, &options).unwrap();
+            // the 2x2 gray layout spends the same 32 bits on luminance alone
+            assert!(gray_bytes.len() <= rgb_bytes.len());
+
+            let (Image::Gray(out), _) = decompress_image(&gray_bytes).unwrap() else {
+                panic!("expected a grayscale image");
+            };
+            assert_eq!((out.width, out.height, out.denominator), (35, 21, 300));
+            let error = crate::quality::rmse(&rgb, &Image::Gray(out).into_rgb());
+            let rgb_error = crate::quality::rmse(&rgb, &decompress_bytes(&rgb_bytes).unwrap());
+            assert!(error <= rgb_error, "{:?}: {} > {}", transform, error, rgb_error);
+        }
+
+        let options = Options { layout: WordLayout::gray(), ..Default::default() };
+        assert!(matches!(compress_image(&rgb, &options), Err(RpegError::Layout(_))));
+    }
+
+    #[test]
+    fn alpha_is_preserved() {
+        let color = checkerboard(13, 9);
+        let alpha = (0..13 * 9).map(|i| if i % 13 < 4 { 0 } else { (i * 3 % 256) as u16 }).collect();
+        let img = RgbaImage { color, alpha };
+
+        for transform in [Transform::Haar2x2, Transform::Dct8x8] {
+            let options = Options { transform, ..Default::default() };
+            let bytes = compress_rgba_image(&img, &options).unwrap();
+            assert_eq!(verify_bytes(&bytes).unwrap().channels, 4);
+
+            let (Image::Rgba(out), _) = decompress_image(&bytes).unwrap() else {
+                panic!("expected an image with alpha");
+            };
+            assert_eq!(out.alpha, img.alpha);
+            let rgb = decompress_bytes(&compress_image(&img.color, &options).unwrap()).unwrap();
+            assert_eq!(crate::quality::rmse(&out.color, &rgb), 0.0);
+        }
+    }
+
+    #[test]
+    fn every_color_space_round_trips() {
+        let img = checkerboard(10, 6);
+        for transform in [Transform::Haar2x2, Transform::Dct8x8] {
+            let reference = Options { transform, ..Default::default() };
+            let expected = crate::quality::rmse(&img, &decompress_bytes(&compress_image(&img, &reference).unwrap()).unwrap());
+            for color in [ColorSpace::Bt709, ColorSpace::YCoCgR] {
+                let bytes = compress_image(&img, &Options { color, ..reference }).unwrap();
+                assert_eq!(verify_bytes(&bytes).unwrap().color, color);
+                let error = crate::quality::rmse(&img, &decompress_bytes(&bytes).unwrap());
+                assert!(error < expected + 0.02, "{:?} {}: {} vs {}", transform, color, error, expected);
+            }
+        }
+    }
+
+    // Writes a netpbm image of noise, a gradient, or a flat colour with a simple LCG
+    fn generated_pnm(width: u32, height: u32, denominator: u16, kind: u32, channels: usize) -> Vec<u8> {
+        let mut state = 0x2545f491_u32.wrapping_add(width * 31 + height * 17 + kind);
+        let count = (width * height) as usize;
+        let mut sample = |i: usize, c: usize| -> u16 {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            let value = match kind {
+                0 => state >> 8,
+                1 => (i % width as usize * 3 + i / width as usize * 5 + c * 40) as u32,
+                _ => 77,
+            };
+            (value % (denominator as u32 + 1)) as u16
+        };
+        let img = match channels {
+            1 => Image::Gray(GrayImage {
+                width,
+                height,
+                denominator,
+                pixels: (0..count).map(|i| Gray { value: sample(i, 0) }).collect(),
+            }),
+            _ => {
+                let pixels: Vec<Rgb> = (0..count)
+                    .map(|i| Rgb { red: sample(i, 0), green: sample(i, 1), blue: sample(i, 2) })
+                    .collect();
+                let color = RgbImage { width, height, denominator, pixels };
+                if channels == 3 {
+                    Image::Rgb(color)
+                } else {
+                    Image::Rgba(RgbaImage { color, alpha: (0..count).map(|i| sample(i, 3)).collect() })
+                }
+            }
+        };
+        let mut bytes = vec![];
+        crate::pnm::write_pnm(&mut bytes, &img).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn lossless_round_trips_exactly() {
+        let options = Options { transform: Transform::Lossless, ..Default::default() };
+        for (width, height) in [(1, 1), (2, 2), (5, 3), (17, 9), (64, 48)] {
+            for denominator in [1, 255, 1023, 65535] {
+                for kind in 0..3 {
+                    for channels in [1, 3, 4] {
+                        let original = generated_pnm(width, height, denominator, kind, channels);
+                        let bytes = match crate::pnm::read_pnm(&original).unwrap() {
+                            Image::Rgb(img) => compress_image(&img, &options),
+                            Image::Gray(img) => compress_gray_image(&img, &options),
+                            Image::Rgba(img) => compress_rgba_image(&img, &options),
+                        }
+                        .unwrap();
+                        assert_eq!(verify_bytes(&bytes).unwrap().transform, Transform::Lossless);
+
+                        let (img, stats) = decompress_image(&bytes).unwrap();
+                        assert_eq!(stats.clipped, 0);
+                        let mut decoded = vec![];
+                        crate::pnm::write_pnm(&mut decoded, &img).unwrap();
+                        assert!(decoded == original, "{}x{} / {} kind {} channels {}", width, height, denominator, kind, channels);
+                    }
+                }
+            }
+        }
+
+        // smooth images should actually get smaller
+        let gradient = generated_pnm(64, 48, 255, 1, 3);
+        let compressed = compress_image(&crate::pnm::read_ppm(&gradient).unwrap(), &options).unwrap();
+        assert!(compressed.len() < gradient.len() / 2);
+    }
+
+    #[test]
+    fn subsampling_keeps_colour_edges() {
+        // one-pixel columns of red and blue, the worst case for averaged chroma
+        let pixels = (0..16 * 8)
+            .map(|i| Rgb { red: if i % 2 == 0 { 220 } else { 40 }, green: 40, blue: if i % 2 == 0 { 40 } else { 220 } })
+            .collect();
+        let img = RgbImage { width: 16, height: 8, denominator: 255, pixels };
+
+        for coding in [Coding::Fixed, Coding::Huffman] {
+            let mut results = vec![];
+            for subsampling in [Subsampling::S420, Subsampling::S422, Subsampling::S444] {
+                let options = Options { subsampling: Some(subsampling), coding, ..Default::default() };
+                let bytes = compress_image(&img, &options).unwrap();
+                assert_eq!(verify_bytes(&bytes).unwrap().subsampling, subsampling);
+                results.push((bytes.len(), crate::quality::rmse(&img, &decompress_bytes(&bytes).unwrap())));
+            }
+
+            let [(size_420, error_420), (size_422, error_422), (size_444, error_444)] = results[..] else {
+                unreachable!()
+            };
+            assert!(size_420 < size_422 && size_422 < size_444, "{:?}", coding);
+            // the columns are already uniform, so 4:4:4 has nothing left to add
+            assert!(error_422 < error_420 / 2.0 && error_444 <= error_422, "{:?}: {:?}", coding, results);
+        }
+
+        let subsampling = Some(Subsampling::S422);
+        let options = Options { subsampling, transform: Transform::Dct8x8, ..Default::default() };
+        assert!(matches!(compress_image(&img, &options), Err(RpegError::Unsupported(_))));
+    }
+
+    #[test]
+    fn dithering_smooths_gradients() {
+        // a gentle gray ramp, with a coarse enough a field that it bands into steps
+        let (width, height) = (64, 32);
+        let pixels = (0..width * height)
+            .map(|i| {
+                let v = 96 + (i % width) as u16 / 4;
+                Rgb { red: v, green: v, blue: v }
+            })
+            .collect();
+        let img = RgbImage { width: width as u32, height: height as u32, denominator: 255, pixels };
+        let layout = WordLayout::from_widths(32, [6, 6, 6, 6, 4, 4], 0.3).unwrap();
+
+        // how far the average of each 4-column stripe strays from the original's
+        let banding = |dither: Dither, seed: u64| {
+            let options = Options { layout, dither, seed, ..Default::default() };
+            let bytes = compress_image(&img, &options).unwrap();
+            let decoded = decompress_bytes(&bytes).unwrap();
+            let stripe = |pixels: &[Rgb], x0: usize| {
+                let sum: u32 = (0..height)
+                    .flat_map(|y| (x0..x0 + 4).map(move |x| y * width + x))
+                    .map(|i| pixels[i].green as u32)
+                    .sum();
+                sum as f64 / (4 * height) as f64
+            };
+            let error = (0..width)
+                .step_by(4)
+                .map(|x0| (stripe(&decoded.pixels, x0) - stripe(&img.pixels, x0)).abs())
+                .sum::<f64>();
+            (bytes, error / (width / 4) as f64)
+        };
+
+        let (plain, plain_error) = banding(Dither::None, 0);
+        for dither in [Dither::Ordered, Dither::Diffusion] {
+            let (bytes, error) = banding(dither, 0);
+            assert_eq!(bytes.len(), plain.len());
+            assert!(error < plain_error / 2.0, "{}: {} vs {}", dither, error, plain_error);
+            assert_eq!(bytes, banding(dither, 0).0);
+            assert_ne!(bytes, banding(dither, 5).0);
+        }
+
+        let options = Options { transform: Transform::Dct8x8, dither: Dither::Ordered, ..Default::default() };
+        assert!(matches!(compress_image(&img, &options), Err(RpegError::Unsupported(_))));
+    }
+
+    #[test]
+    fn decoder_filters_help_smooth_images() {
+        let (width, height) = (96, 64);
+        let pixels = (0..width * height)
+            .map(|i| {
+                let (x, y) = ((i % width) as f32, (i / width) as f32);
+                let wave = |v: f32, amplitude: f32| (127.0 + amplitude * v) as u16;
+                Rgb {
+                    red: wave((x / 23.0 + y / 41.0).sin(), 100.0),
+                    green: wave((y / 17.0).cos(), 90.0),
+                    blue: wave(((x + y) / 31.0).sin(), 80.0),
+                }
+            })
+            .collect();
+        let img = RgbImage { width: width as u32, height: height as u32, denominator: 255, pixels };
+        let error = |bytes: &[u8], options: DecodeOptions| {
+            let (decoded, _) = decompress_image_with(bytes, &options).unwrap();
+            crate::quality::rmse(&img, &decoded.into_rgb())
+        };
+        let deblock = DecodeOptions { deblock: true, ..Default::default() };
+        let bilinear = DecodeOptions { chroma_upsampling: ChromaUpsampling::Bilinear, ..Default::default() };
+
+        let bytes = compress_image(&img, &Options::default()).unwrap();
+        let plain = error(&bytes, DecodeOptions::default());
+        assert_eq!(plain, crate::quality::rmse(&img, &decompress_bytes(&bytes).unwrap()));
+        assert!(error(&bytes, deblock) < plain);
+        assert!(error(&bytes, bilinear) < plain);
+
+        let options = Options { transform: Transform::Dct8x8, quality: Some(10), ..Default::default() };
+        let bytes = compress_image(&img, &options).unwrap();
+        assert!(error(&bytes, deblock) < error(&bytes, DecodeOptions::default()));
+    }
+
+    #[test]
+    fn threads_do_not_change_the_output() {
+        let original = generated_pnm(37, 23, 255, 1, 4);
+        let img = match crate::pnm::read_pnm(&original).unwrap() {
+            Image::Rgba(img) => img,
+            _ => unreachable!(),
+        };
+        let configurations = [
+            Options::default(),
+            Options { coding: Coding::Huffman, subsampling: Some(Subsampling::S422), ..Default::default() },
+            Options { dither: Dither::Diffusion, layout: WordLayout::wide(), ..Default::default() },
+            Options { transform: Transform::Dct8x8, color: ColorSpace::Bt709, ..Default::default() },
+        ];
+        for options in configurations {
+            let serial = compress_rgba_image(&img, &options).unwrap();
+            let decode = |threads: usize| {
+                let options = DecodeOptions { threads, deblock: true, ..Default::default() };
+                let mut out = vec![];
+                crate::pnm::write_pnm(&mut out, &decompress_image_with(&serial, &options).unwrap().0).unwrap();
+                out
+            };
+            for threads in [2, 3, 8, 64] {
+                let parallel = compress_rgba_image(&img, &Options { threads, ..options }).unwrap();
+                assert!(parallel == serial, "{:?} with {} threads", options, threads);
+                assert!(decode(threads) == decode(1), "{:?} with {} threads", options, threads);
+            }
+        }
+    }
+
+    #[test]
+    fn native_chroma_beats_the_table_with_wide_fields() {
+        // a slow sweep of chroma at constant brightness, where the table's 16 levels band
+        let pixels = (0..64 * 16)
+            .map(|i| {
+                let t = (i % 64) as u16 * 2;
+                Rgb { red: 64 + t, green: 128, blue: 192 - t }
+            })
+            .collect();
+        let img = RgbImage { width: 64, height: 16, denominator: 255, pixels };
+
+        let error = |chroma: Option<ChromaCurve>| {
+            let options = Options { layout: WordLayout::wide(), chroma, ..Default::default() };
+            let bytes = compress_image(&img, &options).unwrap();
+            let curve = verify_bytes(&bytes).unwrap().layout.chroma;
+            (curve, crate::quality::rmse(&img, &decompress_bytes(&bytes).unwrap()))
+        };
+        let (_, table) = error(Some(ChromaCurve::Table));
+        let (curve, companded) = error(None);
+        let (_, uniform) = error(Some(ChromaCurve::Uniform));
+        assert_eq!(curve, ChromaCurve::Companded);
+        assert!(companded < table && uniform < table, "{} {} {}", table, companded, uniform);
+
+        let narrow = WordLayout::from_widths(32, [9, 5, 5, 5, 3, 3], 0.3).unwrap();
+        let options = Options { layout: narrow, chroma: Some(ChromaCurve::Uniform), ..Default::default() };
+        assert!(matches!(compress_image(&img, &options), Err(RpegError::Layout(_))));
+        let options = Options { layout: narrow, ..Default::default() };
+        assert_eq!(verify_bytes(&compress_image(&img, &options).unwrap()).unwrap().layout.chroma, ChromaCurve::Table);
+    }
+
+    #[test]
+    fn tiles_decode_on_their_own() {
+        let crop = |img: &Image, region: Region| match img {
+            Image::Rgb(img) => Image::Rgb(crop_rgb(img, region)),
+            Image::Gray(img) => Image::Gray(crop_gray(img, region)),
+            Image::Rgba(img) => Image::Rgba(crop_rgba(img, region)),
+        };
+        let compress = |img: &Image, options: &Options| match img {
+            Image::Rgb(img) => compress_image(img, options),
+            Image::Gray(img) => compress_gray_image(img, options),
+            Image::Rgba(img) => compress_rgba_image(img, options),
+        };
+        let pnm = |img: &Image| {
+            let mut bytes = vec![];
+            crate::pnm::write_pnm(&mut bytes, img).unwrap();
+            bytes
+        };
+
+        for transform in [Transform::Haar2x2, Transform::Dct8x8, Transform::Lossless] {
+            for channels in [1, 3, 4] {
+                let img = crate::pnm::read_pnm(&generated_pnm(37, 23, 255, 1, channels)).unwrap();
+                let options = Options { transform, tile_size: Some(16), ..Default::default() };
+                let bytes = compress(&img, &options).unwrap();
+                let header = verify_bytes(&bytes).unwrap();
+                assert_eq!((header.tile_size, header.tile_count()), (Some(16), 3 * 2));
+
+                // each tile decodes to what its pixels would on their own
+                let (whole, _) = decompress_image(&bytes).unwrap();
+                for tile in header.tiles() {
+                    let alone = compress(&crop(&img, tile), &Options { tile_size: None, ..options }).unwrap();
+                    let (region, _) = decode_region(&bytes, tile.x, tile.y, tile.width, tile.height).unwrap();
+                    assert!(pnm(&region) == pnm(&decompress_image(&alone).unwrap().0), "{} {:?}", transform, tile);
+                }
+                let corner = Region { x: 36, y: 22, width: 1, height: 1 };
+                for region in [Region { x: 3, y: 5, width: 20, height: 15 }, corner] {
+                    let (decoded, _) = decode_region(&bytes, region.x, region.y, region.width, region.height).unwrap();
+                    assert!(pnm(&decoded) == pnm(&crop(&whole, region)), "{} {:?}", transform, region);
+                }
+            }
+        }
+
+        // untiled files are decoded whole and cropped
+        let img = checkerboard(9, 7);
+        let bytes = compress_image(&img, &Options::default()).unwrap();
+        let (Image::Rgb(region), _) = decode_region(&bytes, 2, 3, 4, 4).unwrap() else { unreachable!() };
+        let expected = crop_rgb(&decompress_bytes(&bytes).unwrap(), Region { x: 2, y: 3, width: 4, height: 4 });
+        assert_eq!(crate::quality::rmse(&region, &expected), 0.0);
+        assert!(matches!(decode_region(&bytes, 6, 0, 4, 1), Err(RpegError::BadRegion(_))));
+        assert!(matches!(decode_region(&bytes, 0, u32::MAX, 1, 2), Err(RpegError::BadRegion(_))));
+
+        for (transform, tile_size) in [(Transform::Haar2x2, 0), (Transform::Haar2x2, 5), (Transform::Dct8x8, 12)] {
+            let options = Options { transform, tile_size: Some(tile_size), ..Default::default() };
+            assert!(matches!(compress_image(&img, &options), Err(RpegError::Unsupported(_))));
+        }
+
+        // a forged header claims 2^62 tiles, which must be refused before any are listed
+        let bytes = compress_image(&img, &Options { tile_size: Some(2), ..Default::default() }).unwrap();
+        let (header, payload) = read_rpeg(&bytes).unwrap();
+        let mut forged = vec![];
+        write_rpeg(&mut forged, &Header { width: u32::MAX, height: u32::MAX, ..header }, payload).unwrap();
+        assert!(matches!(verify_bytes(&forged), Err(RpegError::Truncated { .. })));
+        assert!(matches!(decompress_image(&forged), Err(RpegError::Truncated { .. })));
+        assert!(matches!(decode_region(&forged, 0, 0, 1, 1), Err(RpegError::Truncated { .. })));
+    }
+}
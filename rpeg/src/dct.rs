@@ -0,0 +1,383 @@
+use std::fmt;
+use std::str::FromStr;
+
+use array2::Array2;
+
+use crate::bitio::{BitReader, BitWriter};
+use crate::error::RpegError;
+use crate::to_component_video::YPbPr;
+
+/// Width and height of a DCT block
+pub const N: usize = 8;
+
+/// The example luminance quantization table from the JPEG standard (Annex K), row-major,
+/// for samples in 0..=255
+const JPEG_LUMA: [u16; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61,
+    12, 12, 14, 19, 26, 58, 60, 55,
+    14, 13, 16, 24, 40, 57, 69, 56,
+    14, 17, 22, 29, 51, 87, 80, 62,
+    18, 22, 37, 56, 68, 109, 103, 77,
+    24, 35, 55, 64, 81, 104, 113, 92,
+    49, 64, 78, 87, 103, 121, 120, 101,
+    72, 92, 95, 98, 112, 100, 103, 99,
+];
+
+/// The example chrominance quantization table from the JPEG standard (Annex K)
+const JPEG_CHROMA: [u16; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99,
+    18, 21, 26, 66, 99, 99, 99, 99,
+    24, 26, 56, 99, 99, 99, 99, 99,
+    47, 66, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+];
+
+/// Per-coefficient quantization steps and the number of bits each quantized coefficient
+/// occupies in the compressed file. A width of 0 drops the coefficient entirely.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QuantTable {
+    pub steps: [f32; 64],
+    pub widths: [u32; 64],
+}
+
+impl QuantTable {
+    /// Builds a table from quantization steps, giving each coefficient just enough bits
+    /// to represent values up to `dc_clamp` (for the DC coefficient) or
+    /// `ac_clamp / (1 + u + v)` (for the AC coefficient at horizontal frequency u and
+    /// vertical frequency v) in magnitude. Natural images have little energy at high
+    /// frequencies, so those coefficients get fewer bits.
+    ///
+    /// # Arguments:
+    /// * `steps`: the quantization step of each coefficient, row-major
+    /// * `dc_clamp`: the largest DC magnitude that can be represented
+    /// * `ac_clamp`: the largest magnitude of the lowest-frequency AC coefficients
+    pub fn from_steps(steps: [f32; 64], dc_clamp: f32, ac_clamp: f32) -> Self {
+        let mut widths = [0; 64];
+        for (k, width) in widths.iter_mut().enumerate() {
+            let clamp = if k == 0 { dc_clamp } else { ac_clamp / (1 + k % N + k / N) as f32 };
+            let levels = (clamp / steps[k]).floor() as u32;
+            // a sign bit plus enough bits for `levels`
+            *width = if levels == 0 { 0 } else { 33 - levels.leading_zeros() };
+        }
+        QuantTable { steps, widths }
+    }
+
+    /// Number of bits one quantized block occupies
+    pub fn bits(&self) -> u32 {
+        self.widths.iter().sum()
+    }
+
+    /// Quantizes the coefficients of one block
+    pub fn quantize(&self, coeffs: &[f32; 64]) -> [i32; 64] {
+        let mut q = [0; 64];
+        for k in 0..64 {
+            if self.widths[k] > 0 {
+                let max = (1 << (self.widths[k] - 1)) - 1;
+                q[k] = ((coeffs[k] / self.steps[k]).round() as i32).clamp(-max, max);
+            }
+        }
+        q
+    }
+
+    /// Reverses `quantize`
+    pub fn dequantize(&self, q: &[i32; 64]) -> [f32; 64] {
+        let mut coeffs = [0.0; 64];
+        for k in 0..64 {
+            coeffs[k] = q[k] as f32 * self.steps[k];
+        }
+        coeffs
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.steps.iter().any(|&s| !(s > 0.0 && s.is_finite())) {
+            return Err("quantization steps must be positive".to_string());
+        }
+        if self.widths.iter().any(|&w| w > 31) {
+            return Err("coefficient widths must be at most 31 bits".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// The quantization tables used for the luminance and the two chroma planes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DctTables {
+    pub luma: QuantTable,
+    pub chroma: QuantTable,
+}
+
+impl Default for DctTables {
+    /// The JPEG example tables, rescaled to samples in [0, 1]
+    fn default() -> Self {
+        DctTables::scaled(1.0)
+    }
+}
+
+impl DctTables {
+    /// The JPEG example tables with every step multiplied by `scale`; larger scales
+    /// give smaller files at lower quality
+    pub fn scaled(scale: f32) -> Self {
+        let luma = JPEG_LUMA.map(|s| s as f32 / 255.0 * scale);
+        let chroma = JPEG_CHROMA.map(|s| s as f32 / 255.0 * scale);
+        DctTables {
+            luma: QuantTable::from_steps(luma, 4.0, 4.0),
+            chroma: QuantTable::from_steps(chroma, 4.0, 2.0),
+        }
+    }
+
+    /// Number of bits one 8x8 block occupies: the luminance, plus both chroma planes
+    /// when `planes` is 3
+    pub fn bits_per_block(&self, planes: usize) -> u32 {
+        self.luma.bits() + (planes as u32 - 1) * self.chroma.bits()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        self.luma.validate()?;
+        self.chroma.validate()
+    }
+}
+
+/// cos((2x + 1) u pi / 16), scaled so the transform is orthonormal
+fn basis(u: usize, x: usize) -> f32 {
+    let scale = if u == 0 { (1.0 / N as f32).sqrt() } else { (2.0 / N as f32).sqrt() };
+    scale * ((2 * x + 1) as f32 * u as f32 * std::f32::consts::PI / (2 * N) as f32).cos()
+}
+
+/// Computes the two-dimensional DCT-II of an 8x8 block
+/// # Returns:
+/// * `[f32; 64]`: the coefficients, row-major, with the DC coefficient first
+///
+/// # Arguments:
+/// * `block`: the samples, row-major
+pub fn forward_dct(block: &[f32; 64]) -> [f32; 64] {
+    let mut coeffs = [0.0; 64];
+    for v in 0..N {
+        for u in 0..N {
+            let mut sum = 0.0;
+            for y in 0..N {
+                for x in 0..N {
+                    sum += block[y * N + x] * basis(u, x) * basis(v, y);
+                }
+            }
+            coeffs[v * N + u] = sum;
+        }
+    }
+    coeffs
+}
+
+/// Computes the inverse of `forward_dct`
+/// # Returns:
+/// * `[f32; 64]`: the samples, row-major
+///
+/// # Arguments:
+/// * `coeffs`: the coefficients, row-major
+pub fn inverse_dct(coeffs: &[f32; 64]) -> [f32; 64] {
+    let mut block = [0.0; 64];
+    for y in 0..N {
+        for x in 0..N {
+            let mut sum = 0.0;
+            for v in 0..N {
+                for u in 0..N {
+                    sum += coeffs[v * N + u] * basis(u, x) * basis(v, y);
+                }
+            }
+            block[y * N + x] = sum;
+        }
+    }
+    block
+}
+
+/// Number of bytes the DCT blocks of a `width` x `height` image with `planes` coded planes
+/// occupy, or `None` if that many bits cannot be counted in a usize
+pub fn payload_len(width: usize, height: usize, tables: &DctTables, planes: usize) -> Option<usize> {
+    let blocks = width.div_ceil(N).checked_mul(height.div_ceil(N))?;
+    Some(blocks.checked_mul(tables.bits_per_block(planes) as usize)?.div_ceil(8))
+}
+
+/// Transforms, quantizes, and bitpacks every 8x8 block of a component video Array2
+/// # Returns:
+/// * `Vec<u8>`: the packed blocks, in row-major block order
+///
+/// # Arguments:
+/// * `arr`: an Array2 of component video pixels whose dimensions are multiples of 8
+/// * `tables`: the quantization tables
+/// * `planes`: 3 to code y, pb, and pr, or 1 to code only y for grayscale images
+pub fn encode(arr: &Array2<YPbPr>, tables: &DctTables, planes: usize) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+
+    for by in (0..arr.height()).step_by(N) {
+        for bx in (0..arr.width()).step_by(N) {
+            let mut samples = [[0.0; 64]; 3];
+            for y in 0..N {
+                for x in 0..N {
+                    let e = arr.get(bx + x, by + y);
+                    // centre luminance on zero like the chroma
+                    samples[0][y * N + x] = e.y() - 0.5;
+                    samples[1][y * N + x] = e.pb();
+                    samples[2][y * N + x] = e.pr();
+                }
+            }
+
+            for (i, plane) in samples.iter().enumerate().take(planes) {
+                let table = if i == 0 { &tables.luma } else { &tables.chroma };
+                let q = table.quantize(&forward_dct(plane));
+                for (&value, &width) in q.iter().zip(table.widths.iter()) {
+                    if width > 0 {
+                        // quantize already clamped the value to its width
+                        writer.put_signed(value as i64, width).unwrap();
+                    }
+                }
+            }
+        }
+    }
+
+    writer.finish()
+}
+
+/// Reverses `encode`
+/// # Returns:
+/// * `Result<Array2<YPbPr>, RpegError>`: the component video pixels, with dimensions
+///   rounded up to multiples of 8
+///
+/// # Arguments:
+/// * `bytes`: the packed blocks
+/// * `width`: the width of the image
+/// * `height`: the height of the image
+/// * `tables`: the quantization tables the blocks were packed with
+/// * `planes`: the number of planes the blocks were packed with; pb and pr are 0 when it is 1
+pub fn decode(
+    bytes: &[u8],
+    width: usize,
+    height: usize,
+    tables: &DctTables,
+    planes: usize,
+) -> Result<Array2<YPbPr>, RpegError> {
+    let (width_px, height_px) = (width, height);
+    let width = width.div_ceil(N) * N;
+    let height = height.div_ceil(N) * N;
+    let mut arr = Array2::blank_state(width, height, YPbPr::default());
+    let mut reader = BitReader::new(bytes);
+
+    for by in (0..height).step_by(N) {
+        for bx in (0..width).step_by(N) {
+            let mut samples = [[0.0; 64]; 3];
+            for (i, plane) in samples.iter_mut().enumerate().take(planes) {
+                let table = if i == 0 { &tables.luma } else { &tables.chroma };
+                let mut q = [0; 64];
+                for (value, &width) in q.iter_mut().zip(table.widths.iter()) {
+                    *value = reader.get_signed(width).ok_or(RpegError::Truncated {
+                        expected: payload_len(width_px, height_px, tables, planes).unwrap_or(usize::MAX),
+                        actual: bytes.len(),
+                    })? as i32;
+                }
+                *plane = inverse_dct(&table.dequantize(&q));
+            }
+
+            for y in 0..N {
+                for x in 0..N {
+                    let e = arr.get_mut(bx + x, by + y);
+                    e.y = samples[0][y * N + x] + 0.5;
+                    e.pb = samples[1][y * N + x];
+                    e.pr = samples[2][y * N + x];
+                }
+            }
+        }
+    }
+
+    Ok(arr)
+}
+
+impl fmt::Display for QuantTable {
+    /// Writes each coefficient as `step:width`, separated by spaces
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for k in 0..64 {
+            if k > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}:{}", self.steps[k], self.widths[k])?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for QuantTable {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = || format!("invalid quantization table `{}`", s);
+        let entries: Vec<&str> = s.split_whitespace().collect();
+        if entries.len() != 64 {
+            return Err(bad());
+        }
+
+        let mut table = QuantTable { steps: [0.0; 64], widths: [0; 64] };
+        for (k, entry) in entries.iter().enumerate() {
+            let (step, width) = entry.split_once(':').ok_or_else(bad)?;
+            table.steps[k] = step.parse().map_err(|_| bad())?;
+            table.widths[k] = width.parse().map_err(|_| bad())?;
+        }
+        table.validate()?;
+        Ok(table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dct_round_trip() {
+        let block: [f32; 64] = std::array::from_fn(|i| (i % 8) as f32 / 8.0 - (i / 8) as f32 / 16.0);
+        let back = inverse_dct(&forward_dct(&block));
+        for (a, b) in block.iter().zip(back.iter()) {
+            assert!((a - b).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn flat_block_has_only_dc() {
+        let coeffs = forward_dct(&[0.25; 64]);
+        assert!((coeffs[0] - 2.0).abs() < 1e-4);
+        assert!(coeffs[1..].iter().all(|c| c.abs() < 1e-4));
+    }
+
+    #[test]
+    fn default_tables_beat_the_2x2_word() {
+        // 32 bits per 2x2 block is 512 bits per 8x8 block
+        assert!(DctTables::default().bits_per_block(3) < 512);
+        assert!(DctTables::scaled(2.0).bits_per_block(3) < DctTables::default().bits_per_block(3));
+        assert!(DctTables::default().bits_per_block(1) < DctTables::default().bits_per_block(3));
+    }
+
+    #[test]
+    fn table_round_trip() {
+        let table = DctTables::default().luma;
+        assert_eq!(table.to_string().parse::<QuantTable>(), Ok(table));
+    }
+
+    #[test]
+    fn encode_and_decode_blocks() {
+        let mut arr = Array2::blank_state(16, 8, YPbPr::default());
+        for (i, e) in arr.data.iter_mut().enumerate() {
+            *e = YPbPr::new((i % 16) as f32 / 16.0, 0.1, -0.05);
+        }
+        let tables = DctTables::default();
+        let bytes = encode(&arr, &tables, 3);
+        assert_eq!(bytes.len(), payload_len(16, 8, &tables, 3).unwrap());
+
+        let out = decode(&bytes, 16, 8, &tables, 3).unwrap();
+        for (a, b) in arr.data.iter().zip(out.data.iter()) {
+            assert!((a.y - b.y).abs() < 0.05, "{} {}", a.y, b.y);
+            assert!((a.pb - b.pb).abs() < 0.05, "{} {}", a.pb, b.pb);
+        }
+        assert!(decode(&bytes[..bytes.len() - 1], 16, 8, &tables, 3).is_err());
+
+        let luma_only = encode(&arr, &tables, 1);
+        assert_eq!(luma_only.len(), payload_len(16, 8, &tables, 1).unwrap());
+        let out = decode(&luma_only, 16, 8, &tables, 1).unwrap();
+        assert!(arr.data.iter().zip(out.data.iter()).all(|(a, b)| (a.y - b.y).abs() < 0.05 && b.pb == 0.0));
+    }
+}
@@ -0,0 +1,205 @@
+use std::fmt;
+use std::str::FromStr;
+
+use array2::Array2;
+
+/// How quantization error is spread out so smooth gradients do not band
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Dither {
+    /// Round every value to the nearest level
+    #[default]
+    None,
+    /// Round up or down by comparing against a 4x4 Bayer matrix
+    Ordered,
+    /// Floyd–Steinberg error diffusion, left to right and top to bottom
+    Diffusion,
+}
+
+impl fmt::Display for Dither {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Dither::None => write!(f, "none"),
+            Dither::Ordered => write!(f, "ordered"),
+            Dither::Diffusion => write!(f, "diffusion"),
+        }
+    }
+}
+
+impl FromStr for Dither {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Dither::None),
+            "ordered" | "bayer" => Ok(Dither::Ordered),
+            "diffusion" | "floyd-steinberg" => Ok(Dither::Diffusion),
+            _ => Err(format!("unknown dither `{}`, expected none, ordered, or diffusion", s)),
+        }
+    }
+}
+
+/// Thresholds of the 4x4 Bayer matrix, in sixteenths
+const BAYER: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// The SplitMix64 generator, so the same seed always dithers the same way
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A value in [0, 1)
+    fn unit(&mut self) -> f32 {
+        (self.next() >> 40) as f32 / (1 << 24) as f32
+    }
+}
+
+/// The values a quantizer can reproduce, given as the value of each of its codes rather
+/// than as a list, so that the levels of a wide field are never all in memory at once
+pub struct Levels<F> {
+    /// The lowest and the highest code
+    pub codes: (u64, u64),
+    /// The value a code stands for, never decreasing from one code to the next
+    pub level: F,
+}
+
+impl<F: Fn(u64) -> f32> Levels<F> {
+    /// Finds the levels on either side of `x` by a binary search over the codes
+    /// # Returns:
+    /// * `(f32, f32, f32)`: the level below `x`, the level above it, and how far `x` is from
+    ///   the first toward the second, in [0, 1]
+    fn bracket(&self, x: f32) -> (f32, f32, f32) {
+        let (first, last) = ((self.level)(self.codes.0), (self.level)(self.codes.1));
+        if first > x {
+            return (first, first, 0.0);
+        }
+        if last <= x {
+            return (last, last, 0.0);
+        }
+        // the level of `lo` is at most `x` and the level of `hi` is above it
+        let (mut lo, mut hi) = self.codes;
+        while hi - lo > 1 {
+            let mid = lo + (hi - lo) / 2;
+            if (self.level)(mid) <= x {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        let (lo, hi) = ((self.level)(lo), (self.level)(hi));
+        (lo, hi, (x - lo) / (hi - lo))
+    }
+}
+
+/// Replaces every value of a plane with one of `levels`, dithering so that the levels
+/// average out to the original values over small areas. Since every output is exactly a
+/// level, quantizing it afterwards loses nothing more.
+///
+/// # Arguments:
+/// * `plane`: the values to dither, in place
+/// * `levels`: the values the quantizer can reproduce
+/// * `dither`: the method; `Dither::None` rounds to the nearest level
+/// * `seed`: shifts the Bayer matrix for ordered dithering, and jitters the rounding
+///   threshold of error diffusion to break up its regular patterns
+pub fn dither_plane(plane: &mut Array2<f32>, levels: &Levels<impl Fn(u64) -> f32>, dither: Dither, seed: u64) {
+    let (width, height) = (plane.width(), plane.height());
+    let mut rng = SplitMix64(seed);
+    match dither {
+        Dither::None => {
+            for y in 0..height {
+                for x in 0..width {
+                    let (lo, hi, t) = levels.bracket(*plane.get(x, y));
+                    *plane.get_mut(x, y) = if t < 0.5 { lo } else { hi };
+                }
+            }
+        }
+        Dither::Ordered => {
+            let offset = rng.next() as usize;
+            let (ox, oy) = (offset % 4, offset / 4 % 4);
+            for y in 0..height {
+                for x in 0..width {
+                    let threshold = (BAYER[(y + oy) % 4][(x + ox) % 4] as f32 + 0.5) / 16.0;
+                    let (lo, hi, t) = levels.bracket(*plane.get(x, y));
+                    *plane.get_mut(x, y) = if t < threshold { lo } else { hi };
+                }
+            }
+        }
+        Dither::Diffusion => {
+            for y in 0..height {
+                for x in 0..width {
+                    let value = *plane.get(x, y);
+                    let threshold = 0.5 + (rng.unit() - 0.5) / 4.0;
+                    let (lo, hi, t) = levels.bracket(value);
+                    let level = if t < threshold { lo } else { hi };
+                    *plane.get_mut(x, y) = level;
+
+                    let error = value - level;
+                    let mut spread = |dx: isize, dy: usize, weight: f32| {
+                        let nx = x as isize + dx;
+                        if nx >= 0 && (nx as usize) < width && y + dy < height {
+                            *plane.get_mut(nx as usize, y + dy) += error * weight / 16.0;
+                        }
+                    };
+                    spread(1, 0, 7.0);
+                    spread(-1, 1, 3.0);
+                    spread(0, 1, 5.0);
+                    spread(1, 1, 1.0);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEVELS: [f32; 3] = [0.0, 0.5, 1.0];
+
+    fn levels() -> Levels<impl Fn(u64) -> f32> {
+        Levels { codes: (0, 2), level: |q| LEVELS[q as usize] }
+    }
+
+    fn mean(plane: &Array2<f32>) -> f32 {
+        plane.data().iter().sum::<f32>() / plane.data().len() as f32
+    }
+
+    #[test]
+    fn dithering_keeps_the_average() {
+        for dither in [Dither::Ordered, Dither::Diffusion] {
+            let mut plane = Array2::blank_state(16, 16, 0.3);
+            dither_plane(&mut plane, &levels(), dither, 7);
+            assert!(plane.data().iter().all(|v| LEVELS.contains(v)));
+            assert!((mean(&plane) - 0.3).abs() < 0.02, "{}: {}", dither, mean(&plane));
+        }
+
+        let mut plane = Array2::blank_state(16, 16, 0.3);
+        dither_plane(&mut plane, &levels(), Dither::None, 7);
+        assert!(plane.data().iter().all(|&v| v == 0.5));
+    }
+
+    #[test]
+    fn the_seed_decides_the_pattern() {
+        let dithered = |dither: Dither, seed: u64| {
+            let mut plane = Array2::blank_state(8, 8, 0.6);
+            dither_plane(&mut plane, &levels(), dither, seed);
+            plane.data().clone()
+        };
+        for dither in [Dither::Ordered, Dither::Diffusion] {
+            assert_eq!(dithered(dither, 1), dithered(dither, 1));
+            assert_ne!(dithered(dither, 1), dithered(dither, 2));
+        }
+    }
+
+    #[test]
+    fn values_outside_the_levels_are_clamped() {
+        let mut plane = Array2::from_row_major(2, 1, vec![-1.0, 2.0]).unwrap();
+        dither_plane(&mut plane, &levels(), Dither::Diffusion, 0);
+        assert_eq!(*plane.data(), vec![0.0, 1.0]);
+    }
+}
@@ -0,0 +1,497 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::fmt;
+use std::str::FromStr;
+
+use bitpack::bitpack::gets;
+
+use crate::bitio::{BitReader, BitWriter};
+use crate::error::RpegError;
+use crate::layout::WordLayout;
+
+/// Longest Huffman code we allow, so that code lengths fit in `LENGTH_BITS` bits
+const MAX_CODE_LEN: u32 = 24;
+const LENGTH_BITS: u32 = 5;
+/// Bits used to store the number of distinct symbols of a field
+const COUNT_BITS: u32 = 32;
+/// Bits used to store the symbol width of a stream of residuals
+const WIDTH_BITS: u32 = 6;
+
+/// How the quantized values of each 2x2 block are written to the file
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Coding {
+    /// One fixed-size word per block, laid out by the `WordLayout`
+    #[default]
+    Fixed,
+    /// A canonical Huffman code per field, with the code lengths stored ahead of the blocks
+    Huffman,
+}
+
+impl fmt::Display for Coding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Coding::Fixed => write!(f, "fixed"),
+            Coding::Huffman => write!(f, "huffman"),
+        }
+    }
+}
+
+impl FromStr for Coding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fixed" => Ok(Coding::Fixed),
+            "huffman" => Ok(Coding::Huffman),
+            _ => Err(format!("unknown coding `{}`, expected fixed or huffman", s)),
+        }
+    }
+}
+
+type Block = (u32, i32, i32, i32, usize, usize);
+
+/// Turns a block into one symbol per field. Each symbol is the field's bits as they would
+/// appear in a packed word, except that `a` is coded as the difference from the previous
+/// block's `a` (modulo the field size), which is what makes smooth images cheap.
+fn to_symbols(layout: &WordLayout, block: &Block, prev_a: u32) -> [u64; 6] {
+    let mask = |width: u32| (1_u64 << width) - 1;
+    [
+        (block.0 as u64).wrapping_sub(prev_a as u64) & mask(layout.a.width),
+        block.1 as u64 & mask(layout.b.width),
+        block.2 as u64 & mask(layout.c.width),
+        block.3 as u64 & mask(layout.d.width),
+        block.4 as u64,
+        block.5 as u64,
+    ]
+}
+
+/// Reverses `to_symbols`
+fn from_symbols(layout: &WordLayout, symbols: &[u64; 6], prev_a: u32) -> Block {
+    let mask = (1_u64 << layout.a.width) - 1;
+    (
+        ((prev_a as u64 + symbols[0]) & mask) as u32,
+        gets(symbols[1], layout.b.width as u64, 0) as i32,
+        gets(symbols[2], layout.c.width as u64, 0) as i32,
+        gets(symbols[3], layout.d.width as u64, 0) as i32,
+        symbols[4] as usize,
+        symbols[5] as usize,
+    )
+}
+
+/// Computes a Huffman code length for every symbol, no longer than `MAX_CODE_LEN`.
+/// Ties are broken by symbol value so the encoder always builds the same code.
+fn code_lengths(counts: &BTreeMap<u64, u64>) -> BTreeMap<u64, u32> {
+    if counts.len() == 1 {
+        return counts.keys().map(|&s| (s, 1)).collect();
+    }
+
+    let mut weights: Vec<u64> = counts.values().copied().collect();
+    loop {
+        // parent[i] is the internal node that node i was merged into
+        let mut parent = vec![usize::MAX; 2 * weights.len() - 1];
+        let mut heap: BinaryHeap<Reverse<(u64, usize)>> =
+            weights.iter().enumerate().map(|(i, &w)| Reverse((w, i))).collect();
+        let mut next = weights.len();
+        while heap.len() > 1 {
+            let Reverse((w1, n1)) = heap.pop().unwrap();
+            let Reverse((w2, n2)) = heap.pop().unwrap();
+            parent[n1] = next;
+            parent[n2] = next;
+            heap.push(Reverse((w1 + w2, next)));
+            next += 1;
+        }
+
+        let lengths: Vec<u32> = (0..weights.len())
+            .map(|mut node| {
+                let mut depth = 0;
+                while parent[node] != usize::MAX {
+                    node = parent[node];
+                    depth += 1;
+                }
+                depth
+            })
+            .collect();
+
+        if lengths.iter().all(|&l| l <= MAX_CODE_LEN) {
+            return counts.keys().copied().zip(lengths).collect();
+        }
+        // flatten the distribution until the tree is shallow enough
+        for w in weights.iter_mut() {
+            *w = (*w >> 1) | 1;
+        }
+    }
+}
+
+/// Assigns canonical codes: shorter codes first, and in symbol order within a length
+fn canonical_codes(lengths: &BTreeMap<u64, u32>) -> BTreeMap<u64, (u64, u32)> {
+    let mut by_length: Vec<(u32, u64)> = lengths.iter().map(|(&s, &l)| (l, s)).collect();
+    by_length.sort();
+
+    let mut codes = BTreeMap::new();
+    let mut code = 0_u64;
+    let mut prev_len = 0;
+    for (len, symbol) in by_length {
+        code <<= len - prev_len;
+        codes.insert(symbol, (code, len));
+        code += 1;
+        prev_len = len;
+    }
+    codes
+}
+
+/// Decodes symbols of one field using the canonical code rebuilt from the code lengths
+struct Decoder {
+    /// Symbols sorted by (code length, symbol)
+    symbols: Vec<u64>,
+    /// Number of codes of each length
+    counts: [u32; MAX_CODE_LEN as usize + 1],
+}
+
+impl Decoder {
+    fn new(lengths: &BTreeMap<u64, u32>) -> Self {
+        let mut by_length: Vec<(u32, u64)> = lengths.iter().map(|(&s, &l)| (l, s)).collect();
+        by_length.sort();
+        let mut counts = [0; MAX_CODE_LEN as usize + 1];
+        for &(len, _) in &by_length {
+            counts[len as usize] += 1;
+        }
+        Decoder { symbols: by_length.into_iter().map(|(_, s)| s).collect(), counts }
+    }
+
+    fn read(&self, reader: &mut BitReader) -> Option<u64> {
+        // `first` is the first code of the current length, `index` the position of its symbol
+        let (mut code, mut first, mut index) = (0_u64, 0_u64, 0_usize);
+        for len in 1..=MAX_CODE_LEN as usize {
+            code = code << 1 | reader.get(1)?;
+            let count = self.counts[len] as u64;
+            if code < first + count {
+                return self.symbols.get(index + (code - first) as usize).copied();
+            }
+            index += count as usize;
+            first = (first + count) << 1;
+        }
+        None
+    }
+}
+
+/// Builds a Huffman code for `symbols` and writes its table: the number of distinct
+/// symbols, then each symbol in `width` bits followed by its code length
+/// # Returns:
+/// * `BTreeMap<u64, (u64, u32)>`: the code and code length of each symbol
+fn write_table(writer: &mut BitWriter, symbols: impl Iterator<Item = u64>, width: u32) -> BTreeMap<u64, (u64, u32)> {
+    let mut counts = BTreeMap::new();
+    for symbol in symbols {
+        *counts.entry(symbol).or_insert(0_u64) += 1;
+    }
+    let lengths = if counts.is_empty() { BTreeMap::new() } else { code_lengths(&counts) };
+
+    writer.put(lengths.len() as u64, COUNT_BITS);
+    for (&symbol, &len) in &lengths {
+        writer.put(symbol, width);
+        writer.put(len as u64, LENGTH_BITS);
+    }
+    canonical_codes(&lengths)
+}
+
+/// Reads a table written by `write_table`
+fn read_table(reader: &mut BitReader, width: u32) -> Result<Decoder, RpegError> {
+    let bad = |msg: &str| RpegError::BadPayload(msg.to_string());
+    let n = reader.get(COUNT_BITS).ok_or_else(|| bad("code table ended unexpectedly"))?;
+    let mut lengths = BTreeMap::new();
+    for _ in 0..n {
+        let symbol = reader.get(width).ok_or_else(|| bad("code table ended unexpectedly"))?;
+        let len = reader.get(LENGTH_BITS).ok_or_else(|| bad("code table ended unexpectedly"))? as u32;
+        if len == 0 || len > MAX_CODE_LEN {
+            return Err(bad("invalid code length"));
+        }
+        lengths.insert(symbol, len);
+    }
+    Ok(Decoder::new(&lengths))
+}
+
+/// Huffman codes the quantized blocks of an image. The stream starts with, for each of
+/// the six fields, the number of distinct symbols followed by each symbol and its code
+/// length; the coded blocks follow in order. Fields of width 0, like the chroma of a
+/// grayscale layout, are left out entirely.
+/// # Returns:
+/// * `Vec<u8>`: the code tables and the coded blocks
+///
+/// # Arguments:
+/// * `layout`: the layout the blocks were quantized with
+/// * `blocks`: the quantized a, b, c, d, pb, and pr of every block, in row-major order
+pub fn encode_blocks(layout: &WordLayout, blocks: &[Block]) -> Vec<u8> {
+    let mut prev_a = 0;
+    let symbols: Vec<[u64; 6]> = blocks
+        .iter()
+        .map(|block| {
+            let s = to_symbols(layout, block, prev_a);
+            prev_a = block.0;
+            s
+        })
+        .collect();
+
+    let mut writer = BitWriter::new();
+    let mut codes = vec![];
+    for (field, f) in layout.fields().iter().enumerate() {
+        if f.width == 0 {
+            codes.push(BTreeMap::new());
+            continue;
+        }
+        codes.push(write_table(&mut writer, symbols.iter().map(|s| s[field]), f.width));
+    }
+
+    for s in &symbols {
+        for (field, symbol) in s.iter().enumerate() {
+            if let Some(&(code, len)) = codes[field].get(symbol) {
+                writer.put(code, len);
+            }
+        }
+    }
+    writer.finish()
+}
+
+/// Reverses `encode_blocks`
+/// # Returns:
+/// * `Result<Vec<(u32, i32, i32, i32, usize, usize)>, RpegError>`: the quantized blocks
+///
+/// # Arguments:
+/// * `layout`: the layout the blocks were quantized with
+/// * `bytes`: the output of `encode_blocks`
+/// * `count`: the number of blocks in the image
+pub fn decode_blocks(layout: &WordLayout, bytes: &[u8], count: usize) -> Result<Vec<Block>, RpegError> {
+    let bad = |msg: &str| RpegError::BadPayload(msg.to_string());
+    let mut reader = BitReader::new(bytes);
+
+    let mut decoders = vec![];
+    for f in layout.fields() {
+        if f.width == 0 {
+            decoders.push(None);
+            continue;
+        }
+        decoders.push(Some(read_table(&mut reader, f.width)?));
+    }
+
+    // every coded field takes at least one bit, so a count the stream cannot hold is refused
+    // before anything is allocated for it
+    let fields = decoders.iter().flatten().count();
+    if count.checked_mul(fields).is_none_or(|bits| bits > bytes.len().saturating_mul(8)) {
+        return Err(bad("more blocks than the coded stream can hold"));
+    }
+
+    let mut blocks = Vec::with_capacity(count);
+    let mut prev_a = 0;
+    for _ in 0..count {
+        let mut symbols = [0; 6];
+        for (symbol, decoder) in symbols.iter_mut().zip(&decoders) {
+            if let Some(decoder) = decoder {
+                *symbol = decoder.read(&mut reader).ok_or_else(|| bad("coded blocks ended unexpectedly"))?;
+            }
+        }
+        let block = from_symbols(layout, &symbols, prev_a);
+        prev_a = block.0;
+        blocks.push(block);
+    }
+
+    let used = reader.bytes_read();
+    if bytes.len() > used {
+        return Err(RpegError::TrailingData(bytes.len() - used));
+    }
+    Ok(blocks)
+}
+
+/// Losslessly codes a plane of samples, such as an alpha channel. Each sample is predicted
+/// by the one before it in row-major order, and the differences, taken modulo 2^`bits`,
+/// are Huffman coded, so flat and smoothly varying planes take little space.
+/// # Returns:
+/// * `Vec<u8>`: the code table and the coded samples
+///
+/// # Arguments:
+/// * `samples`: the samples, each less than 2^`bits`
+/// * `bits`: the number of bits a sample needs
+pub fn encode_samples(samples: &[u16], bits: u32) -> Vec<u8> {
+    let mask = (1_u64 << bits) - 1;
+    let mut prev = 0;
+    let deltas: Vec<u64> = samples
+        .iter()
+        .map(|&s| {
+            let delta = (s as u64).wrapping_sub(prev) & mask;
+            prev = s as u64;
+            delta
+        })
+        .collect();
+
+    let mut writer = BitWriter::new();
+    let codes = write_table(&mut writer, deltas.iter().copied(), bits);
+    for delta in &deltas {
+        let (code, len) = codes[delta];
+        writer.put(code, len);
+    }
+    writer.finish()
+}
+
+/// Reverses `encode_samples`
+/// # Returns:
+/// * `Result<Vec<u16>, RpegError>`: the samples
+///
+/// # Arguments:
+/// * `bytes`: the output of `encode_samples`
+/// * `count`: the number of samples
+/// * `bits`: the number of bits a sample needs
+pub fn decode_samples(bytes: &[u8], count: usize, bits: u32) -> Result<Vec<u16>, RpegError> {
+    let mask = (1_u64 << bits) - 1;
+    let mut reader = BitReader::new(bytes);
+    let decoder = read_table(&mut reader, bits)?;
+    // as in `decode_blocks`, every coded sample takes at least one bit
+    if count > bytes.len().saturating_mul(8) {
+        return Err(RpegError::BadPayload("more samples than the coded stream can hold".to_string()));
+    }
+
+    let mut samples = Vec::with_capacity(count);
+    let mut prev = 0_u64;
+    for _ in 0..count {
+        let delta = decoder
+            .read(&mut reader)
+            .ok_or_else(|| RpegError::BadPayload("coded samples ended unexpectedly".to_string()))?;
+        prev = (prev + delta) & mask;
+        samples.push(prev as u16);
+    }
+
+    let used = reader.bytes_read();
+    if bytes.len() > used {
+        return Err(RpegError::TrailingData(bytes.len() - used));
+    }
+    Ok(samples)
+}
+
+/// Maps a signed residual to an unsigned symbol, interleaving negative and positive values
+/// so that small magnitudes get small symbols
+fn zigzag(value: i32) -> u64 {
+    ((value << 1) ^ (value >> 31)) as u32 as u64
+}
+
+/// Reverses `zigzag`
+fn unzigzag(symbol: u64) -> i32 {
+    let symbol = symbol as u32;
+    (symbol >> 1) as i32 ^ -((symbol & 1) as i32)
+}
+
+/// Huffman codes streams of signed residuals, each with its own code. Every stream is
+/// written as the width of its symbols, its code table, and then its coded residuals.
+/// # Returns:
+/// * `Vec<u8>`: the coded streams
+///
+/// # Arguments:
+/// * `streams`: the residuals, one stream per kind of coefficient
+pub fn encode_residuals(streams: &[Vec<i32>]) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+    for stream in streams {
+        let symbols: Vec<u64> = stream.iter().map(|&r| zigzag(r)).collect();
+        let width = u64::BITS - symbols.iter().max().unwrap_or(&0).leading_zeros();
+        writer.put(width as u64, WIDTH_BITS);
+        let codes = write_table(&mut writer, symbols.iter().copied(), width);
+        for symbol in &symbols {
+            let (code, len) = codes[symbol];
+            writer.put(code, len);
+        }
+    }
+    writer.finish()
+}
+
+/// Reverses `encode_residuals`
+/// # Returns:
+/// * `Result<Vec<Vec<i32>>, RpegError>`: the streams of residuals
+///
+/// # Arguments:
+/// * `bytes`: the output of `encode_residuals`
+/// * `lens`: the number of residuals in each stream
+pub fn decode_residuals(bytes: &[u8], lens: &[usize]) -> Result<Vec<Vec<i32>>, RpegError> {
+    let bad = |msg: &str| RpegError::BadPayload(msg.to_string());
+    let mut reader = BitReader::new(bytes);
+
+    let mut streams = Vec::with_capacity(lens.len());
+    for &len in lens {
+        let width = reader.get(WIDTH_BITS).ok_or_else(|| bad("residuals ended unexpectedly"))? as u32;
+        if width > u32::BITS {
+            return Err(bad("invalid residual width"));
+        }
+        let decoder = read_table(&mut reader, width)?;
+        let stream = (0..len)
+            .map(|_| decoder.read(&mut reader).map(unzigzag))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| bad("residuals ended unexpectedly"))?;
+        streams.push(stream);
+    }
+
+    let used = reader.bytes_read();
+    if bytes.len() > used {
+        return Err(RpegError::TrailingData(bytes.len() - used));
+    }
+    Ok(streams)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_are_prefix_free() {
+        let counts: BTreeMap<u64, u64> = [(0, 50), (1, 20), (2, 20), (7, 5), (9, 1)].into_iter().collect();
+        let codes: Vec<(u64, u32)> = canonical_codes(&code_lengths(&counts)).into_values().collect();
+        assert_eq!(codes[0], (0, 1));
+        for (i, &(c1, l1)) in codes.iter().enumerate() {
+            for &(c2, l2) in &codes[i + 1..] {
+                let (short, long, ls, ll) = if l1 <= l2 { (c1, c2, l1, l2) } else { (c2, c1, l2, l1) };
+                assert_ne!(long >> (ll - ls), short);
+            }
+        }
+    }
+
+    #[test]
+    fn lengths_are_limited() {
+        // fibonacci weights give the deepest possible tree
+        let mut fib = vec![1_u64, 1];
+        while fib.len() < 40 {
+            fib.push(fib[fib.len() - 1] + fib[fib.len() - 2]);
+        }
+        let counts: BTreeMap<u64, u64> = fib.into_iter().enumerate().map(|(i, w)| (i as u64, w)).collect();
+        assert!(code_lengths(&counts).values().all(|&l| l <= MAX_CODE_LEN));
+    }
+
+    #[test]
+    fn blocks_round_trip() {
+        let layout = WordLayout::default();
+        let blocks: Vec<Block> = (0..100)
+            .map(|i| (200 + i % 7, (i % 3) as i32 - 1, 0, -15, (i % 16) as usize, 3))
+            .collect();
+        let bytes = encode_blocks(&layout, &blocks);
+        assert!(bytes.len() < blocks.len() * layout.bytes_per_word());
+        assert_eq!(decode_blocks(&layout, &bytes, blocks.len()).unwrap(), blocks);
+
+        assert!(matches!(decode_blocks(&layout, &bytes[..bytes.len() - 4], 100), Err(RpegError::BadPayload(_))));
+        let mut extra = bytes.clone();
+        extra.push(0);
+        assert!(matches!(decode_blocks(&layout, &extra, 100), Err(RpegError::TrailingData(1))));
+    }
+
+    #[test]
+    fn samples_round_trip() {
+        let samples: Vec<u16> = (0..500).map(|i| if i % 100 < 60 { 255 } else { (i % 7) as u16 }).collect();
+        let bytes = encode_samples(&samples, 8);
+        assert!(bytes.len() < samples.len() / 2);
+        assert_eq!(decode_samples(&bytes, samples.len(), 8).unwrap(), samples);
+        assert!(decode_samples(&bytes, samples.len() + 50, 8).is_err());
+        assert!(matches!(decode_samples(&bytes, usize::MAX, 8), Err(RpegError::BadPayload(_))));
+
+        let wide = vec![0, 65535, 1, 40000];
+        assert_eq!(decode_samples(&encode_samples(&wide, 16), 4, 16).unwrap(), wide);
+    }
+
+    #[test]
+    fn residuals_round_trip() {
+        let streams = vec![vec![0, -1, 1, i32::MIN, i32::MAX, 5, 5, 5], vec![], vec![0; 10]];
+        let bytes = encode_residuals(&streams);
+        let lens: Vec<usize> = streams.iter().map(Vec::len).collect();
+        assert_eq!(decode_residuals(&bytes, &lens).unwrap(), streams);
+        assert!(decode_residuals(&bytes[..bytes.len() - 1], &lens).is_err());
+    }
+}
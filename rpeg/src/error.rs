@@ -0,0 +1,101 @@
+use std::fmt;
+use std::io;
+
+use array2::Array2Error;
+use bitpack::bitpack::BitpackError;
+
+/// Everything that can go wrong while compressing or decompressing an image
+#[derive(Debug)]
+pub enum RpegError {
+    /// Reading or writing the underlying stream failed
+    Io(io::Error),
+    /// The pixels or blocks did not match the image dimensions
+    Array2(Array2Error),
+    /// A quantized value did not fit in its field of the word layout
+    Bitpack(BitpackError),
+    /// The word layout cannot be used to pack a 2x2 block
+    Layout(String),
+    /// The input image is not a PPM file we can read
+    BadImage(String),
+    /// The rpeg header is missing or malformed
+    BadHeader(String),
+    /// The rpeg data ended before all of the packed words were read
+    Truncated { expected: usize, actual: usize },
+    /// There are bytes left over after the last packed word
+    TrailingData(usize),
+    /// The checksum stored in the file does not match its contents
+    Corrupt { expected: u32, actual: u32 },
+    /// The entropy-coded blocks are malformed or end early
+    BadPayload(String),
+    /// The requested combination of options is not supported
+    Unsupported(String),
+    /// The image uses a denominator the codec cannot handle
+    UnsupportedDenominator(u16),
+    /// A quality outside 1..=100 was requested
+    InvalidQuality(u8),
+    /// Even the lowest quality did not fit in the requested number of bytes
+    TargetTooSmall { target: usize, smallest: usize },
+    /// The region to decode does not lie within the image
+    BadRegion(String),
+}
+
+impl fmt::Display for RpegError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RpegError::Io(e) => write!(f, "i/o error: {}", e),
+            RpegError::Array2(e) => write!(f, "{}", e),
+            RpegError::Bitpack(e) => write!(f, "cannot pack block: {}", e),
+            RpegError::Layout(msg) => write!(f, "invalid word layout: {}", msg),
+            RpegError::BadImage(msg) => write!(f, "bad image: {}", msg),
+            RpegError::BadHeader(msg) => write!(f, "bad rpeg header: {}", msg),
+            RpegError::Truncated { expected, actual } => write!(
+                f,
+                "truncated rpeg data: expected {} bytes of packed words, found {}",
+                expected, actual
+            ),
+            RpegError::TrailingData(n) => write!(f, "{} unexpected bytes after the packed words", n),
+            RpegError::Corrupt { expected, actual } => write!(
+                f,
+                "corrupt rpeg data: checksum is {:08x} but the contents hash to {:08x}",
+                expected, actual
+            ),
+            RpegError::BadPayload(msg) => write!(f, "bad entropy-coded data: {}", msg),
+            RpegError::Unsupported(msg) => write!(f, "unsupported: {}", msg),
+            RpegError::UnsupportedDenominator(d) => write!(f, "unsupported denominator {}", d),
+            RpegError::InvalidQuality(q) => write!(f, "quality {} is outside 1..=100", q),
+            RpegError::TargetTooSmall { target, smallest } => {
+                write!(f, "cannot fit the image in {} bytes; the smallest encoding is {} bytes", target, smallest)
+            }
+            RpegError::BadRegion(msg) => write!(f, "bad region: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for RpegError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RpegError::Io(e) => Some(e),
+            RpegError::Array2(e) => Some(e),
+            RpegError::Bitpack(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RpegError {
+    fn from(e: io::Error) -> Self {
+        RpegError::Io(e)
+    }
+}
+
+impl From<Array2Error> for RpegError {
+    fn from(e: Array2Error) -> Self {
+        RpegError::Array2(e)
+    }
+}
+
+impl From<BitpackError> for RpegError {
+    fn from(e: BitpackError) -> Self {
+        RpegError::Bitpack(e)
+    }
+}
@@ -0,0 +1,176 @@
+use std::fmt;
+use std::str::FromStr;
+
+use array2::Array2;
+
+use crate::subsampling::Subsampling;
+use crate::to_component_video::YPbPr;
+
+/// Steps across a block edge smaller than this are taken to be quantization error and
+/// smoothed; larger ones are left alone as real edges in the image
+pub const EDGE_THRESHOLD: f32 = 0.1;
+
+/// How the decoder fills in the chroma of pixels between chroma samples
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChromaUpsampling {
+    /// Every pixel takes the chroma of the sample it was averaged into
+    #[default]
+    Replicate,
+    /// Every pixel interpolates between the four nearest samples
+    Bilinear,
+}
+
+impl fmt::Display for ChromaUpsampling {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChromaUpsampling::Replicate => write!(f, "replicate"),
+            ChromaUpsampling::Bilinear => write!(f, "bilinear"),
+        }
+    }
+}
+
+impl FromStr for ChromaUpsampling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "replicate" => Ok(ChromaUpsampling::Replicate),
+            "bilinear" => Ok(ChromaUpsampling::Bilinear),
+            _ => Err(format!("unknown chroma upsampling `{}`, expected replicate or bilinear", s)),
+        }
+    }
+}
+
+/// Smooths the edges between the blocks along one row or column of a channel
+/// # Arguments:
+/// * `line`: the values of the channel along the row or column
+/// * `block`: the block size
+fn smooth_line(line: &mut [f32], block: usize) {
+    for edge in (block..line.len()).step_by(block) {
+        let step = line[edge] - line[edge - 1];
+        if step.abs() >= EDGE_THRESHOLD {
+            continue;
+        }
+        // turn the step into a ramp, reaching further into blocks that are wide enough
+        line[edge - 1] += step / 4.0;
+        line[edge] -= step / 4.0;
+        if block >= 4 {
+            line[edge - 2] += step / 8.0;
+            line[edge + 1] -= step / 8.0;
+        }
+    }
+}
+
+/// Smooths the seams between the blocks of a decoded image in Y, Pb, and Pr, first
+/// across the vertical edges and then across the horizontal ones. Blocks of 4 pixels or
+/// more have two pixels adjusted on each side of an edge; smaller blocks only one.
+///
+/// # Arguments:
+/// * `arr`: the decoded image, whose dimensions are multiples of `block`
+/// * `block`: the block size of the transform that coded it
+pub fn deblock(arr: &mut Array2<YPbPr>, block: usize) {
+    let (width, height) = (arr.width(), arr.height());
+    let channels: [fn(&mut YPbPr) -> &mut f32; 3] = [|p| &mut p.y, |p| &mut p.pb, |p| &mut p.pr];
+
+    for channel in channels {
+        for y in 0..height {
+            let mut line: Vec<f32> = (0..width).map(|x| *channel(arr.get_mut(x, y))).collect();
+            smooth_line(&mut line, block);
+            for (x, value) in line.into_iter().enumerate() {
+                *channel(arr.get_mut(x, y)) = value;
+            }
+        }
+        for x in 0..width {
+            let mut line: Vec<f32> = (0..height).map(|y| *channel(arr.get_mut(x, y))).collect();
+            smooth_line(&mut line, block);
+            for (y, value) in line.into_iter().enumerate() {
+                *channel(arr.get_mut(x, y)) = value;
+            }
+        }
+    }
+}
+
+/// Maps a pixel coordinate to a coordinate on a grid of samples `scale` pixels apart,
+/// each centred on the pixels it covers
+/// # Returns:
+/// * `(usize, usize, f32)`: the samples on either side and how far the pixel is from the first
+fn sample_position(x: usize, scale: usize, samples: usize) -> (usize, usize, f32) {
+    let u = (x as f32 - (scale as f32 - 1.0) / 2.0) / scale as f32;
+    let u = u.clamp(0.0, (samples - 1) as f32);
+    let lo = u.floor() as usize;
+    (lo, (lo + 1).min(samples - 1), u - lo as f32)
+}
+
+/// Replaces the replicated chroma of a 2x2-transform image with chroma interpolated
+/// bilinearly between the samples `subsampling` kept, leaving luminance alone.
+///
+/// # Arguments:
+/// * `arr`: the decoded image, with even dimensions and each sample's chroma copied to
+///   every pixel it covers
+/// * `subsampling`: how the chroma was sampled
+pub fn upsample_chroma(arr: &mut Array2<YPbPr>, subsampling: Subsampling) {
+    let (columns, rows) = subsampling.grid();
+    let (scale_x, scale_y) = (2 / columns, 2 / rows);
+    if scale_x == 1 && scale_y == 1 {
+        return;
+    }
+    let (width, height) = (arr.width() / scale_x, arr.height() / scale_y);
+    let mut samples = Array2::blank_state(width, height, (0.0, 0.0));
+    for sy in 0..height {
+        for sx in 0..width {
+            let pixel = arr.get(sx * scale_x, sy * scale_y);
+            *samples.get_mut(sx, sy) = (pixel.pb, pixel.pr);
+        }
+    }
+
+    for y in 0..arr.height() {
+        let (y0, y1, fy) = sample_position(y, scale_y, height);
+        for x in 0..arr.width() {
+            let (x0, x1, fx) = sample_position(x, scale_x, width);
+            let lerp = |a: (f32, f32), b: (f32, f32), t: f32| (a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t);
+            let top = lerp(*samples.get(x0, y0), *samples.get(x1, y0), fx);
+            let bottom = lerp(*samples.get(x0, y1), *samples.get(x1, y1), fx);
+            let (pb, pr) = lerp(top, bottom, fy);
+            let pixel = arr.get_mut(x, y);
+            pixel.pb = pb;
+            pixel.pr = pr;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deblocking_smooths_small_steps_only() {
+        // two 4-pixel blocks with a small step in Y, and a large step in Pb
+        let data = (0..8)
+            .map(|x| if x < 4 { YPbPr::new(0.50, -0.3, 0.0) } else { YPbPr::new(0.54, 0.3, 0.0) })
+            .collect();
+        let mut arr = Array2::from_row_major(8, 1, data).unwrap();
+        deblock(&mut arr, 4);
+
+        let y: Vec<f32> = arr.data().iter().map(|p| p.y).collect();
+        assert!(y.windows(2).all(|w| w[1] >= w[0]));
+        assert!((y[4] - y[3]) < 0.03 && y[0] == 0.50 && y[7] == 0.54);
+        assert!(arr.data().iter().all(|p| p.pb.abs() == 0.3));
+    }
+
+    #[test]
+    fn bilinear_chroma_interpolates_between_samples() {
+        // 4:2:0 samples of -0.2 and 0.2 in two blocks side by side
+        let data = (0..4 * 2).map(|i| YPbPr::new(0.5, if i % 4 < 2 { -0.2 } else { 0.2 }, 0.1)).collect();
+        let mut arr = Array2::from_row_major(4, 2, data).unwrap();
+        upsample_chroma(&mut arr, Subsampling::S420);
+
+        let pb: Vec<f32> = (0..4).map(|x| arr.get(x, 0).pb).collect();
+        assert_eq!((pb[0], pb[3]), (-0.2, 0.2));
+        assert!((pb[1] + 0.1).abs() < 1e-6 && (pb[2] - 0.1).abs() < 1e-6);
+        assert!(arr.data().iter().all(|p| p.y == 0.5 && (p.pr - 0.1).abs() < 1e-6));
+
+        let before: Vec<f32> = arr.data().iter().map(|p| p.pb).collect();
+        upsample_chroma(&mut arr, Subsampling::S444);
+        assert!(arr.data().iter().map(|p| p.pb).eq(before));
+    }
+}
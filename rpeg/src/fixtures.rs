@@ -0,0 +1,30 @@
+use csc411_image::{Rgb, RgbImage};
+
+/// A smooth colour gradient, which the lossy transforms reproduce closely
+pub fn gradient(width: u32, height: u32) -> RgbImage {
+    let pixels = (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .map(|(x, y)| Rgb {
+            red: (x * 255 / width) as u16,
+            green: (y * 255 / height) as u16,
+            blue: ((x + y) * 255 / (width + height)) as u16,
+        })
+        .collect();
+    RgbImage { width, height, denominator: 255, pixels }
+}
+
+/// Smooth shading with a disc whose hard edge the lossy transforms have to work at
+pub fn scene(width: u32, height: u32) -> RgbImage {
+    let pixels = (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x as f32 / width as f32, y as f32 / height as f32)))
+        .map(|(fx, fy)| {
+            let inside = (fx - 0.5).powi(2) + (fy - 0.5).powi(2) < 0.1;
+            Rgb {
+                red: (255.0 * fx) as u16,
+                green: if inside { 220 } else { (128.0 * fy) as u16 },
+                blue: (255.0 * (1.0 - fx * fy)) as u16,
+            }
+        })
+        .collect();
+    RgbImage { width, height, denominator: 255, pixels }
+}
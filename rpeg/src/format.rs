@@ -0,0 +1,942 @@
+use std::io::{self, Read, Write};
+
+use crate::checksum::{crc32, Crc32};
+use crate::dct::{self, DctTables};
+use crate::entropy::Coding;
+use crate::error::RpegError;
+use crate::layout::{Field, WordLayout};
+use crate::quantize::ChromaCurve;
+use crate::subsampling::Subsampling;
+use crate::tile::{tile_count, tiles_over, Region};
+use crate::to_component_video::ColorSpace;
+use crate::transform::Transform;
+
+const LEGACY_MAGIC: &str = "Compressed image format 2";
+const MAGIC: &[u8; 4] = b"RPEG";
+/// Version of the container written by `write_rpeg`
+pub const VERSION: u8 = 2;
+
+/// Set in the flags when the 2x2 blocks are Huffman coded
+pub const FLAG_HUFFMAN: u16 = 1;
+/// Set in the flags when a CRC-32 of the header and payload follows the payload
+pub const FLAG_CHECKSUM: u16 = 2;
+/// Set in the flags when the image is split into independently coded tiles
+pub const FLAG_TILED: u16 = 4;
+/// Set in the flags when the 2x2 blocks are written in progressive order
+pub const FLAG_PROGRESSIVE: u16 = 8;
+/// Flags this version of the decoder understands
+const KNOWN_FLAGS: u16 = FLAG_HUFFMAN | FLAG_CHECKSUM | FLAG_TILED | FLAG_PROGRESSIVE;
+
+/// Everything the decoder needs to know before it reads the compressed blocks
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Header {
+    pub width: u32,
+    pub height: u32,
+    pub layout: WordLayout,
+    pub transform: Transform,
+    /// Only written to the file for the DCT
+    pub tables: DctTables,
+    pub coding: Coding,
+    /// The colour transform applied before the block transform
+    pub color: ColorSpace,
+    /// How many chroma samples each block keeps; anything but 4:4:4 needs the 2x2 transform
+    pub subsampling: Subsampling,
+    /// The denominator (maxval) of the original image
+    pub denominator: u16,
+    /// 1 for a grayscale image, whose blocks hold only luminance, 3 for a colour image, or
+    /// 4 for a colour image with an alpha plane
+    pub channels: u8,
+    /// The width and height of the tiles the image is split into, or `None` if the whole
+    /// image is coded at once
+    pub tile_size: Option<u32>,
+    /// Whether the a and chroma of every 2x2 block come before any block's b, c, and d, so
+    /// a half-scale preview can be decoded from the start of the payload; see `progressive`
+    pub progressive: bool,
+}
+
+impl Header {
+    /// Number of 2x2 blocks in the image, counting the partial blocks at odd edges. It
+    /// saturates at `usize::MAX`, which `read_header` never lets through.
+    pub fn blocks_2x2(&self) -> usize {
+        self.checked_blocks_2x2().unwrap_or(usize::MAX)
+    }
+
+    /// Number of 2x2 blocks in the image, or `None` if it cannot be counted in a usize
+    fn checked_blocks_2x2(&self) -> Option<usize> {
+        (self.width as usize).div_ceil(2).checked_mul((self.height as usize).div_ceil(2))
+    }
+
+    /// Number of colour planes the blocks hold: 1 for luminance only, 3 with chroma
+    pub fn color_planes(&self) -> usize {
+        if self.channels == 1 {
+            1
+        } else {
+            3
+        }
+    }
+
+    /// Whether an alpha section precedes the compressed blocks
+    pub fn has_alpha(&self) -> bool {
+        self.channels == 4
+    }
+
+    /// Number of bytes of compressed blocks in the payload, not counting any alpha section,
+    /// or `None` when the blocks are entropy coded and their size is only known once they
+    /// are decoded. The tile index gives the size of each tile of a tiled image instead.
+    /// A size too large to count saturates at `usize::MAX`, which no payload matches.
+    pub fn payload_len(&self) -> Option<usize> {
+        self.checked_payload_len().unwrap_or(Some(usize::MAX))
+    }
+
+    /// Like `payload_len`, but reports a size that cannot be counted in a usize as
+    /// `RpegError::BadHeader`
+    fn checked_payload_len(&self) -> Result<Option<usize>, RpegError> {
+        let (width, height) = (self.width as usize, self.height as usize);
+        if self.tile_size.is_some() {
+            return Ok(None);
+        }
+        let len = match (&self.transform, &self.coding) {
+            (_, Coding::Huffman) | (Transform::Lossless, _) => return Ok(None),
+            (Transform::Haar2x2, Coding::Fixed) if self.progressive => self
+                .checked_progressive_lens()
+                .and_then(|(coarse, detail)| coarse.checked_add(self.checked_extra_chroma_len()?)?.checked_add(detail)),
+            (Transform::Haar2x2, Coding::Fixed) => self
+                .checked_blocks_2x2()
+                .and_then(|blocks| blocks.checked_mul(self.layout.bytes_per_word()))
+                .and_then(|words| words.checked_add(self.checked_extra_chroma_len()?)),
+            (Transform::Dct8x8, Coding::Fixed) => dct::payload_len(width, height, &self.tables, self.color_planes()),
+        };
+        let too_large = || RpegError::BadHeader(format!("a {}x{} image is too large", self.width, self.height));
+        len.map(Some).ok_or_else(too_large)
+    }
+
+    /// Number of bytes of chroma samples that follow the 2x2 blocks because they did not
+    /// fit in the blocks' words, saturating like `blocks_2x2`
+    pub fn extra_chroma_len(&self) -> usize {
+        self.checked_extra_chroma_len().unwrap_or(usize::MAX)
+    }
+
+    /// Number of bytes of extra chroma samples, or `None` if it cannot be counted in a usize
+    fn checked_extra_chroma_len(&self) -> Option<usize> {
+        if self.transform != Transform::Haar2x2 || self.channels == 1 || !self.layout.has_chroma() {
+            return Some(0);
+        }
+        self.subsampling.extra_chroma_len(&self.layout, self.checked_blocks_2x2()?)
+    }
+
+    /// Number of bytes of the coarse section, which holds the a and first chroma sample of
+    /// each block, and of the detail section, which holds b, c, and d, of a progressive
+    /// payload, each saturating like `blocks_2x2`
+    pub fn progressive_lens(&self) -> (usize, usize) {
+        self.checked_progressive_lens().unwrap_or((usize::MAX, usize::MAX))
+    }
+
+    /// Like `progressive_lens`, or `None` if either length cannot be counted in a usize
+    fn checked_progressive_lens(&self) -> Option<(usize, usize)> {
+        let bits = |fields: [Field; 3]| fields.iter().map(|f| f.width as usize).sum::<usize>();
+        let blocks = self.checked_blocks_2x2()?;
+        Some((
+            blocks.checked_mul(bits(self.layout.coarse_fields()))?.div_ceil(8),
+            blocks.checked_mul(bits(self.layout.detail_fields()))?.div_ceil(8),
+        ))
+    }
+
+    /// Number of tiles of the image, saturating like `blocks_2x2`; an untiled image is a
+    /// single tile
+    pub fn tile_count(&self) -> usize {
+        match self.tile_size {
+            Some(size) => tile_count(self.width, self.height, size).unwrap_or(usize::MAX),
+            None => 1,
+        }
+    }
+
+    /// The tiles of the image, left to right and then top to bottom; an untiled image is
+    /// a single tile
+    pub fn tiles(&self) -> impl Iterator<Item = Region> {
+        self.tiles_over(Region { x: 0, y: 0, width: self.width, height: self.height }).map(|(_, tile)| tile)
+    }
+
+    /// The tiles of the image that overlap a region, which must lie within the image, with
+    /// their positions in the order of `tiles`. Of an untiled image, the single tile is
+    /// always given.
+    pub fn tiles_over(&self, region: Region) -> impl Iterator<Item = (usize, Region)> {
+        let whole = Region { x: 0, y: 0, width: self.width, height: self.height };
+        let tiled = self.tile_size.map(|size| tiles_over(self.width, self.height, size, region));
+        tiled.into_iter().flatten().chain(self.tile_size.is_none().then_some((0, whole)))
+    }
+
+    /// The header a tile is coded with: that of an untiled image of the tile's size
+    pub fn tile_header(&self, tile: Region) -> Header {
+        Header { width: tile.width, height: tile.height, tile_size: None, ..*self }
+    }
+
+    /// The flags field describing this header; `write_rpeg` always appends a checksum
+    fn flags(&self) -> u16 {
+        let tiled = if self.tile_size.is_some() { FLAG_TILED } else { 0 };
+        let progressive = if self.progressive { FLAG_PROGRESSIVE } else { 0 };
+        match self.coding {
+            Coding::Fixed => FLAG_CHECKSUM | tiled | progressive,
+            Coding::Huffman => FLAG_CHECKSUM | FLAG_HUFFMAN | tiled | progressive,
+        }
+    }
+}
+
+/// Writes a compressed image: a binary header followed by the compressed blocks.
+/// All multi-byte values are big-endian. The header holds, in order:
+/// * the magic `RPEG` and the container version (1 byte)
+/// * the flags (2 bytes)
+/// * the width and height of the original, unpadded image (4 bytes each)
+/// * the denominator of the original image (2 bytes) and its number of channels (1 byte)
+/// * the block size, the transform id (0 for the 2x2 transform, 1 for the DCT, 2 for
+///   lossless), and the colour transform id (1 byte each); the
+///   colour ids are 0 for BT.601, 1 for BT.709, and 2 for YCoCg-R
+/// * the chroma subsampling id: 0 for 4:4:4, 1 for 4:2:2, 2 for 4:2:0 (1 byte)
+/// * the word layout: the word size (1 byte), the cosine force (an f32), then the width
+///   and lsb of a, b, c, d, pb, and pr (1 byte each), and the chroma curve id: 0 for the
+///   chroma table, 1 for uniform, 2 for companded (1 byte)
+/// * for the DCT only, the luma then the chroma table: each step (an f32) followed by
+///   each coefficient width (1 byte)
+/// * for tiled images only, the tile size (4 bytes); the payload then starts with the
+///   tile index, see `join_tiles`
+///
+/// When the image has an alpha plane, the payload starts with the length of the coded
+/// alpha samples (4 bytes) and the samples themselves; see `join_alpha`. The payload is
+/// followed by a CRC-32 of the header and the payload (4 bytes). With the 2x2 transform,
+/// chroma samples beyond the one in each block's word come after the blocks; see
+/// `subsampling::encode_extra_chroma`. In progressive order the blocks are split in two
+/// around the extra chroma samples; see `progressive::to_progressive`.
+///
+/// # Arguments:
+/// * `out`: where to write the compressed image
+/// * `header`: the dimensions, word layout, and transform of the image
+/// * `payload`: the compressed blocks
+pub fn write_rpeg<W: Write>(out: &mut W, header: &Header, payload: &[u8]) -> io::Result<()> {
+    let header = header_bytes(header);
+    let mut crc = Crc32::new();
+    crc.update(&header);
+    crc.update(payload);
+
+    out.write_all(&header)?;
+    out.write_all(payload)?;
+    out.write_all(&crc.finish().to_be_bytes())?;
+    out.flush()
+}
+
+/// Serializes a header in the layout described by `write_rpeg`
+pub fn header_bytes(header: &Header) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(VERSION);
+    bytes.extend(header.flags().to_be_bytes());
+    bytes.extend(header.width.to_be_bytes());
+    bytes.extend(header.height.to_be_bytes());
+    bytes.extend(header.denominator.to_be_bytes());
+    bytes.push(header.channels);
+    bytes.push(header.transform.block_size() as u8);
+    bytes.push(transform_id(header.transform));
+    bytes.push(header.color.id());
+    bytes.push(header.subsampling.id());
+
+    let layout = &header.layout;
+    bytes.push(layout.word_bits as u8);
+    bytes.extend(layout.cosine_force.to_be_bytes());
+    for field in layout.fields() {
+        bytes.push(field.width as u8);
+        bytes.push(field.lsb as u8);
+    }
+    bytes.push(layout.chroma.id());
+
+    if header.transform == Transform::Dct8x8 {
+        for table in [&header.tables.luma, &header.tables.chroma] {
+            for step in table.steps {
+                bytes.extend(step.to_be_bytes());
+            }
+            bytes.extend(table.widths.map(|w| w as u8));
+        }
+    }
+    if let Some(size) = header.tile_size {
+        bytes.extend(size.to_be_bytes());
+    }
+    bytes
+}
+
+/// Parses a compressed image written by `write_rpeg`, or a legacy
+/// `Compressed image format 2` file. The checksum, when there is one, is verified before
+/// anything else is read, so a damaged file is reported as `RpegError::Corrupt`.
+/// # Returns:
+/// * `Result<(Header, &[u8]), RpegError>`: the header and the compressed blocks
+///
+/// # Arguments:
+/// * `bytes`: the contents of a compressed image file
+pub fn read_rpeg(bytes: &[u8]) -> Result<(Header, &[u8]), RpegError> {
+    let (header, rest) = if bytes.starts_with(MAGIC) {
+        read_header(&verify_checksum(bytes)?[MAGIC.len()..])?
+    } else {
+        read_legacy_header(bytes)?
+    };
+
+    // the tiles are checked as they are split off
+    if header.tile_size.is_none() {
+        check_payload_len(&header, rest)?;
+    }
+    Ok((header, rest))
+}
+
+/// Checks that the payload of an untiled image has as many bytes as its header calls for.
+/// Entropy-coded payloads are checked by the decoder instead.
+fn check_payload_len(header: &Header, payload: &[u8]) -> Result<(), RpegError> {
+    let (_, blocks) = split_alpha(header, payload)?;
+    if let Some(expected) = header.payload_len() {
+        if blocks.len() < expected {
+            return Err(RpegError::Truncated { expected, actual: blocks.len() });
+        }
+        if blocks.len() > expected {
+            return Err(RpegError::TrailingData(blocks.len() - expected));
+        }
+    }
+    Ok(())
+}
+
+/// Joins the payloads of the tiles of an image behind the tile index: the length of each
+/// tile's payload (4 bytes each), in the order of `Header::tiles`
+/// # Returns:
+/// * `Vec<u8>`: the payload of a tiled image
+///
+/// # Arguments:
+/// * `tiles`: the payload of each tile, as an untiled image of its size would have
+pub fn join_tiles(tiles: &[Vec<u8>]) -> Vec<u8> {
+    let mut payload: Vec<u8> = tiles.iter().flat_map(|tile| (tile.len() as u32).to_be_bytes()).collect();
+    for tile in tiles {
+        payload.extend(tile);
+    }
+    payload
+}
+
+/// Reverses `join_tiles`, checking each tile's payload against its header. An untiled
+/// image is one tile.
+/// # Returns:
+/// * `Result<Vec<&[u8]>, RpegError>`: the payload of each tile, in the order of `Header::tiles`
+///
+/// # Arguments:
+/// * `header`: the header of the image
+/// * `payload`: everything between the header and the checksum
+pub fn split_tiles<'a>(header: &Header, payload: &'a [u8]) -> Result<Vec<&'a [u8]>, RpegError> {
+    if header.tile_size.is_none() {
+        return Ok(vec![payload]);
+    }
+
+    // the tile count comes from the header, so it is checked against the payload before
+    // anything is allocated for the tiles
+    let count = header.tile_count();
+    let index_len = count.saturating_mul(4);
+    if payload.len() < index_len {
+        return Err(RpegError::Truncated { expected: index_len, actual: payload.len() });
+    }
+    let (index, mut rest) = payload.split_at(index_len);
+    let mut payloads = Vec::with_capacity(count);
+    for (tile, len) in header.tiles().zip(index.chunks_exact(4)) {
+        let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+        if rest.len() < len {
+            return Err(RpegError::Truncated { expected: len, actual: rest.len() });
+        }
+        let (tile_payload, tail) = rest.split_at(len);
+        check_payload_len(&header.tile_header(tile), tile_payload)?;
+        payloads.push(tile_payload);
+        rest = tail;
+    }
+    if !rest.is_empty() {
+        return Err(RpegError::TrailingData(rest.len()));
+    }
+    Ok(payloads)
+}
+
+/// Prefixes the compressed blocks of an image with its coded alpha samples and their length
+/// # Returns:
+/// * `Vec<u8>`: the payload of an image with an alpha plane
+///
+/// # Arguments:
+/// * `alpha`: the coded alpha samples
+/// * `blocks`: the compressed colour blocks
+pub fn join_alpha(alpha: &[u8], blocks: &[u8]) -> Vec<u8> {
+    let mut payload = (alpha.len() as u32).to_be_bytes().to_vec();
+    payload.extend(alpha);
+    payload.extend(blocks);
+    payload
+}
+
+/// Reverses `join_alpha`. An image without alpha has an empty alpha section.
+/// # Returns:
+/// * `Result<(&[u8], &[u8]), RpegError>`: the coded alpha samples and the compressed blocks
+///
+/// # Arguments:
+/// * `header`: the header of the image
+/// * `payload`: everything between the header and the checksum
+pub fn split_alpha<'a>(header: &Header, payload: &'a [u8]) -> Result<(&'a [u8], &'a [u8]), RpegError> {
+    if !header.has_alpha() {
+        return Ok((&[], payload));
+    }
+
+    let Some((len, rest)) = payload.split_first_chunk::<4>() else {
+        return Err(RpegError::Truncated { expected: 4, actual: payload.len() });
+    };
+    let len = u32::from_be_bytes(*len) as usize;
+    if rest.len() < len {
+        return Err(RpegError::Truncated { expected: len, actual: rest.len() });
+    }
+    Ok(rest.split_at(len))
+}
+
+/// Splits the chroma samples that follow the 2x2 blocks off the end of a payload; there
+/// are none unless the image keeps more than one chroma sample per block
+/// # Returns:
+/// * `Result<(&[u8], &[u8]), RpegError>`: the blocks and the extra chroma samples
+///
+/// # Arguments:
+/// * `header`: the header of the image
+/// * `blocks`: the compressed blocks, without any alpha section
+pub fn split_chroma<'a>(header: &Header, blocks: &'a [u8]) -> Result<(&'a [u8], &'a [u8]), RpegError> {
+    let len = header.extra_chroma_len();
+    if blocks.len() < len {
+        return Err(RpegError::Truncated { expected: len, actual: blocks.len() });
+    }
+    Ok(blocks.split_at(blocks.len() - len))
+}
+
+/// Checks the CRC-32 at the end of a file whose flags say it has one
+/// # Returns:
+/// * `Result<&[u8], RpegError>`: the file without the checksum
+///
+/// # Arguments:
+/// * `bytes`: the contents of a compressed image file, starting with the magic
+fn verify_checksum(bytes: &[u8]) -> Result<&[u8], RpegError> {
+    // a header too short to hold the flags is reported by `read_header`
+    let Some(flags) = bytes.get(5..7) else {
+        return Ok(bytes);
+    };
+    if u16::from_be_bytes([flags[0], flags[1]]) & FLAG_CHECKSUM == 0 {
+        return Ok(bytes);
+    }
+
+    let Some(split) = bytes.len().checked_sub(4).filter(|&n| n >= 7) else {
+        return Err(RpegError::BadHeader("header ended unexpectedly".to_string()));
+    };
+    let (body, trailer) = bytes.split_at(split);
+    let expected = u32::from_be_bytes(trailer.try_into().unwrap());
+    let actual = crc32(body);
+    if expected != actual {
+        return Err(RpegError::Corrupt { expected, actual });
+    }
+    Ok(body)
+}
+
+/// Reads the binary header of an rpeg file from a stream, leaving the stream at the start
+/// of the payload. Legacy text headers are not recognized.
+/// # Returns:
+/// * `Result<(Header, Vec<u8>), RpegError>`: the header and its bytes, which the
+///   checksum covers along with the payload
+///
+/// # Arguments:
+/// * `input`: the stream
+pub fn read_header_from<R: Read>(input: &mut R) -> Result<(Header, Vec<u8>), RpegError> {
+    let mut read = |bytes: &mut Vec<u8>, len: usize| {
+        let start = bytes.len();
+        bytes.resize(start + len, 0);
+        input.read_exact(&mut bytes[start..]).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => RpegError::BadHeader("header ended unexpectedly".to_string()),
+            _ => RpegError::Io(e),
+        })
+    };
+
+    let mut bytes = vec![];
+    read(&mut bytes, MAGIC.len() + HEADER_PREFIX_LEN)?;
+    if !bytes.starts_with(MAGIC) {
+        return Err(RpegError::BadHeader("unrecognized format".to_string()));
+    }
+    let len = header_len(&bytes[MAGIC.len()..]).unwrap();
+    read(&mut bytes, len - HEADER_PREFIX_LEN)?;
+
+    let (header, _) = read_header(&bytes[MAGIC.len()..])?;
+    Ok((header, bytes))
+}
+
+/// Bytes of a binary header, after the magic, up to and including the transform id
+const HEADER_PREFIX_LEN: usize = 1 + 2 + 8 + 3 + 2;
+
+/// Finds the length of the binary header that follows the magic from the flags and the
+/// transform id, which decide which parts of it are present
+/// # Returns:
+/// * `Option<usize>`: the length, or `None` if `bytes` is shorter than `HEADER_PREFIX_LEN`
+fn header_len(bytes: &[u8]) -> Option<usize> {
+    let prefix = bytes.get(..HEADER_PREFIX_LEN)?;
+    let flags = u16::from_be_bytes([prefix[1], prefix[2]]);
+    // the colour id, the subsampling id, the word size, the cosine force, the fields, and the chroma curve id
+    let mut len = HEADER_PREFIX_LEN + 1 + 1 + 1 + 4 + 2 * 6 + 1;
+    if prefix[HEADER_PREFIX_LEN - 1] == transform_id(Transform::Dct8x8) {
+        len += 2 * (64 * 4 + 64);
+    }
+    if flags & FLAG_TILED != 0 {
+        len += 4;
+    }
+    Some(len)
+}
+
+/// Parses the binary header that follows the magic
+fn read_header(bytes: &[u8]) -> Result<(Header, &[u8]), RpegError> {
+    let bad = |msg: String| RpegError::BadHeader(msg);
+    // a short header is reported by the reader as it runs out
+    let len = header_len(bytes).map_or(bytes.len(), |len| len.min(bytes.len()));
+    let (bytes, payload) = bytes.split_at(len);
+    let mut r = ByteReader { bytes };
+
+    let version = r.u8()?;
+    if version != VERSION {
+        return Err(bad(format!("unsupported version {}", version)));
+    }
+    let flags = r.u16()?;
+    if flags & !KNOWN_FLAGS != 0 {
+        return Err(bad(format!("unknown flags {:#06x}", flags & !KNOWN_FLAGS)));
+    }
+    let coding = if flags & FLAG_HUFFMAN != 0 { Coding::Huffman } else { Coding::Fixed };
+
+    let width = r.u32()?;
+    let height = r.u32()?;
+    let denominator = r.u16()?;
+    if denominator == 0 {
+        return Err(bad("denominator must not be 0".to_string()));
+    }
+    let channels = r.u8()?;
+    if ![1, 3, 4].contains(&channels) {
+        return Err(bad(format!("unsupported number of channels {}", channels)));
+    }
+    let block_size = r.u8()?;
+    let transform = match r.u8()? {
+        0 => Transform::Haar2x2,
+        1 => Transform::Dct8x8,
+        2 => Transform::Lossless,
+        id => return Err(bad(format!("unknown transform id {}", id))),
+    };
+    if block_size as usize != transform.block_size() {
+        return Err(bad(format!("block size {} does not match the {} transform", block_size, transform)));
+    }
+    let color_id = r.u8()?;
+    let Some(color) = ColorSpace::from_id(color_id) else {
+        return Err(bad(format!("unknown colour transform id {}", color_id)));
+    };
+    let subsampling_id = r.u8()?;
+    let Some(subsampling) = Subsampling::from_id(subsampling_id) else {
+        return Err(bad(format!("unknown subsampling id {}", subsampling_id)));
+    };
+    if transform != Transform::Haar2x2 && subsampling != Subsampling::S444 {
+        return Err(bad(format!("{} subsampling needs the 2x2 transform", subsampling)));
+    }
+
+    let word_bits = r.u8()? as u32;
+    let cosine_force = r.f32()?;
+    let mut fields = [Field::new(0, 0, false); 6];
+    for (i, field) in fields.iter_mut().enumerate() {
+        // b, c, and d are the only signed values
+        *field = Field::new(r.u8()? as u32, r.u8()? as u32, (1..=3).contains(&i));
+    }
+    let chroma_id = r.u8()?;
+    let Some(chroma) = ChromaCurve::from_id(chroma_id) else {
+        return Err(bad(format!("unknown chroma curve id {}", chroma_id)));
+    };
+    let layout = WordLayout {
+        word_bits,
+        a: fields[0],
+        b: fields[1],
+        c: fields[2],
+        d: fields[3],
+        pb: fields[4],
+        pr: fields[5],
+        cosine_force,
+        chroma,
+    };
+    layout.validate().map_err(bad)?;
+
+    let mut tables = DctTables::default();
+    if transform == Transform::Dct8x8 {
+        for table in [&mut tables.luma, &mut tables.chroma] {
+            for step in table.steps.iter_mut() {
+                *step = r.f32()?;
+            }
+            for width in table.widths.iter_mut() {
+                *width = r.u8()? as u32;
+            }
+        }
+        tables.validate().map_err(bad)?;
+    }
+    let tile_size = if flags & FLAG_TILED != 0 {
+        let size = r.u32()?;
+        if size == 0 || size % block_size as u32 != 0 {
+            return Err(bad(format!("tile size {} is not a multiple of the block size {}", size, block_size)));
+        }
+        Some(size)
+    } else {
+        None
+    };
+    if channels != 1 && transform == Transform::Haar2x2 && !layout.has_chroma() {
+        return Err(bad("a colour image needs a layout with chroma fields".to_string()));
+    }
+    if coding == Coding::Huffman && transform == Transform::Dct8x8 {
+        return Err(bad("entropy coding is only available for the 2x2 transform".to_string()));
+    }
+    if transform == Transform::Lossless && coding != Coding::Huffman {
+        return Err(bad("lossless images are always entropy coded".to_string()));
+    }
+    if transform == Transform::Lossless && channels != 1 && color != ColorSpace::YCoCgR {
+        return Err(bad(format!("lossless images must use ycocg-r, not {}", color)));
+    }
+    let progressive = flags & FLAG_PROGRESSIVE != 0;
+    if progressive && (transform != Transform::Haar2x2 || coding != Coding::Fixed) {
+        return Err(bad("progressive order needs the 2x2 transform with fixed-size words".to_string()));
+    }
+    if progressive && (tile_size.is_some() || channels == 4) {
+        return Err(bad("progressive images cannot be tiled or have alpha".to_string()));
+    }
+
+    let header = Header {
+        width,
+        height,
+        layout,
+        transform,
+        tables,
+        coding,
+        color,
+        subsampling,
+        denominator,
+        channels,
+        tile_size,
+        progressive,
+    };
+    // the decoder counts pixels, blocks, and payload bytes in a usize
+    if (width as usize).checked_mul(height as usize).is_none() {
+        return Err(bad(format!("a {}x{} image is too large", width, height)));
+    }
+    header.checked_payload_len()?;
+    Ok((header, payload))
+}
+
+/// Parses the text header of a `Compressed image format 2` file, which always uses the
+/// default layout and the 2x2 transform
+fn read_legacy_header(bytes: &[u8]) -> Result<(Header, &[u8]), RpegError> {
+    let mut rest = bytes;
+    let magic = next_line(&mut rest)?;
+    if magic != LEGACY_MAGIC {
+        return Err(RpegError::BadHeader("unrecognized format".to_string()));
+    }
+
+    let dimensions = next_line(&mut rest)?;
+    let (width, height) = dimensions
+        .split_once(' ')
+        .and_then(|(w, h)| Some((w.parse::<u32>().ok()?, h.parse::<u32>().ok()?)))
+        .ok_or_else(|| RpegError::BadHeader(format!("invalid dimensions `{}`", dimensions)))?;
+
+    let header = Header {
+        width,
+        height,
+        layout: WordLayout::default(),
+        transform: Transform::Haar2x2,
+        tables: DctTables::default(),
+        coding: Coding::Fixed,
+        color: ColorSpace::Bt601,
+        subsampling: Subsampling::S420,
+        denominator: 255,
+        channels: 3,
+        tile_size: None,
+        progressive: false,
+    };
+    Ok((header, rest))
+}
+
+fn transform_id(transform: Transform) -> u8 {
+    match transform {
+        Transform::Haar2x2 => 0,
+        Transform::Dct8x8 => 1,
+        Transform::Lossless => 2,
+    }
+}
+
+/// Reads big-endian values off the front of a byte slice
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl ByteReader<'_> {
+    fn take<const LEN: usize>(&mut self) -> Result<[u8; LEN], RpegError> {
+        if self.bytes.len() < LEN {
+            return Err(RpegError::BadHeader("header ended unexpectedly".to_string()));
+        }
+        let (value, rest) = self.bytes.split_at(LEN);
+        self.bytes = rest;
+        Ok(value.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, RpegError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, RpegError> {
+        Ok(u16::from_be_bytes(self.take()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, RpegError> {
+        Ok(u32::from_be_bytes(self.take()?))
+    }
+
+    fn f32(&mut self) -> Result<f32, RpegError> {
+        Ok(f32::from_be_bytes(self.take()?))
+    }
+}
+
+/// Serializes packed words in big-endian order, using as many bytes per word as `layout` needs
+pub fn words_to_bytes(layout: &WordLayout, words: &[u64]) -> Vec<u8> {
+    let bytes_per_word = layout.bytes_per_word();
+    words
+        .iter()
+        .flat_map(|word| word.to_be_bytes()[8 - bytes_per_word..].to_vec())
+        .collect()
+}
+
+/// Reverses `words_to_bytes`; any incomplete word at the end is ignored
+pub fn bytes_to_words(layout: &WordLayout, bytes: &[u8]) -> Vec<u64> {
+    bytes
+        .chunks_exact(layout.bytes_per_word())
+        .map(|chunk| chunk.iter().fold(0_u64, |word, &byte| word << 8 | byte as u64))
+        .collect()
+}
+
+/// Removes the next newline-terminated line from `bytes` and returns it without the newline
+fn next_line(bytes: &mut &[u8]) -> Result<String, RpegError> {
+    let end = bytes
+        .iter()
+        .position(|&b| b == b'\n')
+        .ok_or_else(|| RpegError::BadHeader("header ended unexpectedly".to_string()))?;
+    let line = String::from_utf8_lossy(&bytes[..end]).trim_end_matches('\r').to_string();
+    *bytes = &bytes[end + 1..];
+    Ok(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(width: u32, height: u32, layout: WordLayout, transform: Transform) -> Header {
+        Header {
+            width,
+            height,
+            layout,
+            transform,
+            tables: DctTables::default(),
+            coding: Coding::Fixed,
+            color: ColorSpace::Bt601,
+            subsampling: Subsampling::native(transform),
+            denominator: 255,
+            channels: 3,
+            tile_size: None,
+            progressive: false,
+        }
+    }
+
+    #[test]
+    fn reads_legacy_files() {
+        let bytes = b"Compressed image format 2\n4 2\n\x01\x02\x03\x04\xff\xee\xdd\xcc";
+        let (read_header, read_payload) = read_rpeg(bytes).unwrap();
+        assert_eq!(read_header, header(4, 2, WordLayout::default(), Transform::Haar2x2));
+        assert_eq!(bytes_to_words(&read_header.layout, read_payload), vec![0x01020304, 0xffeeddcc]);
+    }
+
+    #[test]
+    fn header_layout() {
+        let header = header(3, 1, WordLayout::default(), Transform::Haar2x2);
+        let bytes = header_bytes(&header);
+
+        assert!(bytes.starts_with(b"RPEG\x02\x00\x02\x00\x00\x00\x03\x00\x00\x00\x01\x00\xff\x03\x02\x00\x00\x02\x20"));
+        assert_eq!(bytes.len(), 4 + 1 + 2 + 8 + 3 + 3 + 1 + 1 + 4 + 12 + 1);
+        assert_eq!(bytes[bytes.len() - 1], ChromaCurve::Table.id());
+    }
+
+    #[test]
+    fn dct_header_round_trip() {
+        let header = Header {
+            tables: DctTables::scaled(2.0),
+            ..header(9, 8, WordLayout::default(), Transform::Dct8x8)
+        };
+        let payload = vec![0; header.payload_len().unwrap()];
+        let mut bytes = vec![];
+        write_rpeg(&mut bytes, &header, &payload).unwrap();
+
+        assert_eq!(read_rpeg(&bytes).unwrap(), (header, &payload[..]));
+    }
+
+    #[test]
+    fn alpha_section_round_trip() {
+        let header = Header { channels: 4, ..header(9, 8, WordLayout::default(), Transform::Dct8x8) };
+        let blocks = vec![7; header.payload_len().unwrap()];
+        let payload = join_alpha(&[1, 2, 3], &blocks);
+        let mut bytes = vec![];
+        write_rpeg(&mut bytes, &header, &payload).unwrap();
+
+        let (read_header, read_payload) = read_rpeg(&bytes).unwrap();
+        assert_eq!(read_header, header);
+        assert_eq!(split_alpha(&read_header, read_payload).unwrap(), (&[1, 2, 3][..], &blocks[..]));
+
+        let short = join_alpha(&[1, 2, 3], &blocks[1..]);
+        bytes.clear();
+        write_rpeg(&mut bytes, &header, &short).unwrap();
+        assert!(matches!(read_rpeg(&bytes), Err(RpegError::Truncated { .. })));
+    }
+
+    #[test]
+    fn tile_index_round_trip() {
+        let header = Header { tile_size: Some(4), ..header(6, 5, WordLayout::default(), Transform::Haar2x2) };
+        assert_eq!(header.payload_len(), None);
+        let tiles: Vec<Vec<u8>> = header
+            .tiles()
+            .map(|tile| vec![tile.x as u8; header.tile_header(tile).payload_len().unwrap()])
+            .collect();
+        let payload = join_tiles(&tiles);
+        let mut bytes = vec![];
+        write_rpeg(&mut bytes, &header, &payload).unwrap();
+
+        let (read_header, read_payload) = read_rpeg(&bytes).unwrap();
+        assert_eq!(read_header, header);
+        assert_eq!(split_tiles(&read_header, read_payload).unwrap(), tiles.iter().map(|t| &t[..]).collect::<Vec<_>>());
+        assert!(matches!(split_tiles(&header, &payload[..payload.len() - 1]), Err(RpegError::Truncated { .. })));
+        let mut long = payload.clone();
+        long.push(0);
+        assert!(matches!(split_tiles(&header, &long), Err(RpegError::TrailingData(1))));
+
+        // the tile size must be a whole number of blocks
+        let mut odd = header_bytes(&Header { tile_size: Some(3), ..header });
+        odd.extend(crc32(&odd).to_be_bytes());
+        assert!(matches!(read_rpeg(&odd), Err(RpegError::BadHeader(_))));
+    }
+
+    #[test]
+    fn layout_header_round_trip() {
+        let header = header(2, 2, WordLayout::wide(), Transform::Haar2x2);
+        let mut bytes = vec![];
+        write_rpeg(&mut bytes, &header, &words_to_bytes(&header.layout, &[0x0102030405060708])).unwrap();
+
+        let (read_header, payload) = read_rpeg(&bytes).unwrap();
+        assert_eq!(read_header, header);
+        assert_eq!(bytes_to_words(&header.layout, payload), vec![0x0102030405060708]);
+        assert!(matches!(read_rpeg(&bytes[..bytes.len() - 1]), Err(RpegError::Corrupt { .. })));
+        bytes.push(0);
+        assert!(matches!(read_rpeg(&bytes), Err(RpegError::Corrupt { .. })));
+    }
+
+    #[test]
+    fn huffman_header_round_trip() {
+        let header = Header { coding: Coding::Huffman, ..header(4, 2, WordLayout::default(), Transform::Haar2x2) };
+        let mut bytes = vec![];
+        write_rpeg(&mut bytes, &header, &[1, 2, 3]).unwrap();
+
+        assert_eq!(bytes[5..7], (FLAG_CHECKSUM | FLAG_HUFFMAN).to_be_bytes());
+        assert_eq!(read_rpeg(&bytes).unwrap(), (header, &[1_u8, 2, 3][..]));
+    }
+
+    #[test]
+    fn progressive_header_round_trip() {
+        let dct = header_bytes(&Header { progressive: true, ..header(8, 8, WordLayout::default(), Transform::Dct8x8) });
+        let header = Header { progressive: true, ..header(5, 3, WordLayout::default(), Transform::Haar2x2) };
+        // 6 blocks of 9 + 4 + 4 coarse bits and 3 * 5 detail bits
+        assert_eq!(header.progressive_lens(), (13, 12));
+        let payload = vec![0; header.payload_len().unwrap()];
+        assert_eq!(payload.len(), 13 + 12);
+        let mut bytes = vec![];
+        write_rpeg(&mut bytes, &header, &payload).unwrap();
+
+        assert_eq!(bytes[5..7], (FLAG_CHECKSUM | FLAG_PROGRESSIVE).to_be_bytes());
+        assert_eq!(read_rpeg(&bytes).unwrap(), (header, &payload[..]));
+
+        let tiled = header_bytes(&Header { tile_size: Some(4), ..header });
+        for mut bytes in [dct, tiled] {
+            bytes[6] &= !(FLAG_CHECKSUM as u8);
+            assert!(matches!(read_rpeg(&bytes), Err(RpegError::BadHeader(_))));
+        }
+    }
+
+    #[test]
+    fn sizes_too_large_to_count_are_bad_headers() {
+        for transform in [Transform::Haar2x2, Transform::Dct8x8] {
+            let header = header(u32::MAX, u32::MAX, WordLayout::wide(), transform);
+            assert_eq!(header.payload_len(), Some(usize::MAX));
+            let mut bytes = header_bytes(&header);
+            bytes[6] &= !(FLAG_CHECKSUM as u8);
+            assert!(matches!(read_rpeg(&bytes), Err(RpegError::BadHeader(_))), "{}", transform);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let good = header_bytes(&header(2, 2, WordLayout::default(), Transform::Haar2x2));
+        // headers without a checksum, so that each field is checked on its own
+        let corrupt = |offset: usize, value: u8| {
+            let mut bytes = good.clone();
+            bytes[6] = 0;
+            bytes[offset] = value;
+            bytes.extend([0; 4]);
+            read_rpeg(&bytes).map(|(header, _)| header)
+        };
+
+        assert!(corrupt(4, VERSION).is_ok());
+        assert!(matches!(corrupt(4, 3), Err(RpegError::BadHeader(_)))); // version
+        assert!(matches!(corrupt(6, 0x80), Err(RpegError::BadHeader(_)))); // flags
+        assert!(matches!(corrupt(16, 0), Err(RpegError::BadHeader(_)))); // denominator
+        assert!(matches!(corrupt(17, 2), Err(RpegError::BadHeader(_)))); // channels
+        assert!(matches!(corrupt(18, 8), Err(RpegError::BadHeader(_)))); // block size
+        assert!(matches!(corrupt(20, 9), Err(RpegError::BadHeader(_)))); // colour transform
+        assert!(matches!(corrupt(21, 7), Err(RpegError::BadHeader(_)))); // subsampling
+        assert!(matches!(corrupt(39, 3), Err(RpegError::BadHeader(_)))); // chroma curve
+        // a native curve needs at least 4 bits of chroma, and the default layout has 4
+        assert!(corrupt(39, ChromaCurve::Uniform.id()).is_ok());
+        assert!(matches!(read_rpeg(&good[..10]), Err(RpegError::BadHeader(_))));
+    }
+
+    #[test]
+    fn headers_can_be_read_from_a_stream() {
+        let configurations = [(Transform::Haar2x2, None), (Transform::Dct8x8, None), (Transform::Dct8x8, Some(8))];
+        for (transform, tile_size) in configurations {
+            let header = Header { tile_size, ..header(9, 8, WordLayout::wide(), transform) };
+            let mut bytes = header_bytes(&header);
+            let len = bytes.len();
+            bytes.extend([1, 2, 3]);
+
+            let mut input = &bytes[..];
+            assert_eq!(read_header_from(&mut input).unwrap(), (header, bytes[..len].to_vec()));
+            assert_eq!(input, [1, 2, 3]);
+            assert!(read_header_from(&mut &bytes[..len - 1]).is_err());
+        }
+    }
+
+    #[test]
+    fn extra_chroma_follows_the_blocks() {
+        let header = header(4, 2, WordLayout::default(), Transform::Haar2x2);
+        let header = Header { subsampling: Subsampling::S422, ..header };
+        // two words, then one extra 8-bit chroma sample per block
+        assert_eq!(header.payload_len(), Some(2 * 4 + 2));
+        let payload = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+        assert_eq!(split_chroma(&header, &payload).unwrap(), (&payload[..8], &payload[8..]));
+        assert!(matches!(split_chroma(&header, &payload[..1]), Err(RpegError::Truncated { .. })));
+    }
+
+    #[test]
+    fn checksum_catches_damage() {
+        let header = header(2, 2, WordLayout::default(), Transform::Haar2x2);
+        let mut bytes = vec![];
+        write_rpeg(&mut bytes, &header, &[1, 2, 3, 4]).unwrap();
+        assert!(read_rpeg(&bytes).is_ok());
+
+        for i in 0..bytes.len() {
+            for bit in 0..8 {
+                let mut damaged = bytes.clone();
+                damaged[i] ^= 1 << bit;
+                assert!(read_rpeg(&damaged).is_err(), "bit {} of byte {}", bit, i);
+            }
+        }
+        for len in 0..bytes.len() {
+            assert!(read_rpeg(&bytes[..len]).is_err());
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use bitpack::bitpack::BitpackError;
+use csc411_image::{GrayImage, Rgb, RgbImage};
+
+use crate::codec::Options;
+use crate::entropy::{self, Coding};
+use crate::error::RpegError;
+use crate::format::{words_to_bytes, Header};
+use crate::padding::{round_up, source_index};
+use crate::parallel::map_bands;
+use crate::progressive::to_progressive;
+use crate::subsampling::encode_extra_chroma;
+use crate::to_component_video::YPbPr;
+use crate::to_rgb_float::RgbF32;
+use crate::{bitpack, get_luminosity_coeffs};
+
+/// What one band of rows of blocks codes to
+struct Band {
+    /// The packed words, or the first value that did not fit, with fixed-size coding
+    words: Vec<Result<u64, BitpackError>>,
+    /// The quantized blocks, with Huffman coding
+    blocks: Vec<(u32, i32, i32, i32, usize, usize)>,
+    /// Every chroma sample but the first of each block
+    extra: Vec<(f32, f32)>,
+}
+
+/// Codes the 2x2 blocks of an image in a single pass. Each block's four pixels are looked
+/// up through the padding, converted to component video, transformed, quantized, and
+/// packed before the next block is read, so none of the padded, floating-point, component
+/// video, or packed Array2s of the staged pipeline are built. The bytes are the same as
+/// `codec::encode_2x2` gives for the padded component video of the image, reordered by
+/// `progressive::to_progressive` if the header asks for progressive order.
+/// # Returns:
+/// * `Result<Vec<u8>, RpegError>`: the packed blocks and any extra chroma samples
+///
+/// # Arguments:
+/// * `width`, `height`: the dimensions of the image before padding
+/// * `header`: the header of the compressed image, with the 2x2 transform
+/// * `options`: the padding, coding, and number of threads; dithering is not supported
+/// * `pixel`: the component video of the pixel at an index into the image's pixels, or of
+///   zero padding for `None`
+fn encode_blocks<F>(
+    width: usize,
+    height: usize,
+    header: &Header,
+    options: &Options,
+    pixel: F,
+) -> Result<Vec<u8>, RpegError>
+where
+    F: Fn(Option<usize>) -> YPbPr + Sync,
+{
+    let (layout, subsampling) = (&header.layout, header.subsampling);
+    let fixed = options.coding == Coding::Fixed;
+    let columns: Vec<_> = (0..round_up(width, 2)).map(|x| source_index(x, width, options.padding)).collect();
+
+    let bands = map_bands(round_up(height, 2) / 2, options.threads, |rows| {
+        let mut band = Band { words: vec![], blocks: vec![], extra: vec![] };
+        let mut samples = Vec::with_capacity(subsampling.samples_per_block());
+        for by in rows {
+            let [top, bottom] = [2 * by, 2 * by + 1].map(|y| source_index(y, height, options.padding));
+            let at = |row: Option<usize>, column: Option<usize>| pixel(row.zip(column).map(|(y, x)| y * width + x));
+            for pair in columns.chunks_exact(2) {
+                let group = [at(top, pair[0]), at(top, pair[1]), at(bottom, pair[0]), at(bottom, pair[1])];
+                let group = [&group[0], &group[1], &group[2], &group[3]];
+                let (a, b, c, d) = get_luminosity_coeffs(group);
+                samples.clear();
+                subsampling.sample_into(group, &mut samples);
+
+                let block = layout.quantize((a, b, c, d, samples[0].0, samples[0].1));
+                if fixed {
+                    let (qa, qb, qc, qd, pb, pr) = block;
+                    band.words.push(bitpack(layout, qa, qb, qc, qd, pb as u32, pr as u32));
+                } else {
+                    band.blocks.push(block);
+                }
+                band.extra.extend_from_slice(&samples[1..]);
+            }
+        }
+        vec![band]
+    });
+
+    let mut words = vec![];
+    let mut blocks = vec![];
+    let mut extra = vec![];
+    for band in bands {
+        words.extend(band.words);
+        blocks.extend(band.blocks);
+        extra.extend(band.extra);
+    }
+    let mut payload = if fixed {
+        words_to_bytes(layout, &words.into_iter().collect::<Result<Vec<_>, _>>()?)
+    } else {
+        entropy::encode_blocks(layout, &blocks)
+    };
+    if layout.has_chroma() {
+        payload.extend(encode_extra_chroma(layout, &extra));
+    }
+    if header.progressive {
+        return to_progressive(header, &payload);
+    }
+    Ok(payload)
+}
+
+/// Codes a colour image with the 2x2 transform in a single pass; see `encode_blocks`
+pub(crate) fn encode_rgb_2x2(img: &RgbImage, header: &Header, options: &Options) -> Result<Vec<u8>, RpegError> {
+    let transform = header.color.transform();
+    let scale = img.denominator as f32;
+    let black = Rgb { red: 0, green: 0, blue: 0 };
+    encode_blocks(img.width as usize, img.height as usize, header, options, |i| {
+        let p = i.map_or(&black, |i| &img.pixels[i]);
+        transform.forward(&RgbF32::new(p.red as f32 / scale, p.green as f32 / scale, p.blue as f32 / scale))
+    })
+}
+
+/// Codes the luminance of a grayscale image with the 2x2 transform in a single pass; see
+/// `encode_blocks`
+pub(crate) fn encode_gray_2x2(img: &GrayImage, header: &Header, options: &Options) -> Result<Vec<u8>, RpegError> {
+    let scale = img.denominator as f32;
+    encode_blocks(img.width as usize, img.height as usize, header, options, |i| {
+        YPbPr::new(i.map_or(0.0, |i| img.pixels[i].value as f32 / scale), 0.0, 0.0)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use array2::Array2;
+    use csc411_image::Gray;
+
+    use super::*;
+    use crate::codec::{encode_2x2, lossy_header};
+    use crate::fixtures::scene;
+    use crate::padding::{pad_to_multiple, Padding};
+    use crate::parallel::map_array;
+    use crate::subsampling::Subsampling;
+    use crate::to_component_video::ColorSpace;
+    use crate::to_rgb_float::to_rgbf32_with_denominator;
+
+    /// Pads, converts, and transforms the whole image before coding it, as the codec did
+    /// before the fused pipeline
+    fn staged(img: &RgbImage, header: &Header, options: &Options) -> Vec<u8> {
+        let arr = Array2::from_row_major(img.width as usize, img.height as usize, img.pixels.clone()).unwrap();
+        let arr_padded = pad_to_multiple(&arr, 2, options.padding, Rgb { red: 0, green: 0, blue: 0 });
+        let arr_f = to_rgbf32_with_denominator(&arr_padded, img.denominator);
+        let transform = header.color.transform();
+        let arr_cv = map_array(&arr_f, options.threads, |p| transform.forward(p));
+        encode_2x2(arr_cv, &header.layout, options, header.subsampling).unwrap()
+    }
+
+    #[test]
+    fn fused_output_matches_the_staged_pipeline() {
+        for (width, height) in [(1, 1), (2, 2), (5, 3), (16, 9), (33, 20)] {
+            let mut img = scene(width, height);
+            for p in img.pixels.iter_mut() {
+                for sample in [&mut p.red, &mut p.green, &mut p.blue] {
+                    *sample = (*sample as u32 * 1000 / 255) as u16;
+                }
+            }
+            img.denominator = 1000;
+            for padding in [Padding::Edge, Padding::Mirror, Padding::Zero] {
+                for subsampling in [Subsampling::S444, Subsampling::S422, Subsampling::S420] {
+                    for coding in [Coding::Fixed, Coding::Huffman] {
+                        let options = Options {
+                            padding,
+                            coding,
+                            color: ColorSpace::Bt709,
+                            subsampling: Some(subsampling),
+                            quality: Some(80),
+                            threads: 3,
+                            ..Default::default()
+                        };
+                        let header = lossy_header(width, height, img.denominator, 3, &options).unwrap();
+                        assert!(
+                            encode_rgb_2x2(&img, &header, &options).unwrap() == staged(&img, &header, &options),
+                            "{}x{} {:?} {} {}",
+                            width,
+                            height,
+                            padding,
+                            subsampling,
+                            coding
+                        );
+                    }
+                }
+            }
+
+            let pixels = img.pixels.iter().map(|p| Gray { value: p.green }).collect();
+            let gray = GrayImage { width, height, denominator: img.denominator, pixels };
+            let arr = Array2::from_row_major(width as usize, height as usize, gray.pixels.clone()).unwrap();
+            for padding in [Padding::Edge, Padding::Mirror, Padding::Zero] {
+                let options = Options { padding, ..Default::default() };
+                let header = lossy_header(width, height, gray.denominator, 1, &options).unwrap();
+                let arr_padded = pad_to_multiple(&arr, 2, padding, Gray { value: 0 });
+                let arr_cv = map_array(&arr_padded, 1, |p| YPbPr::new(p.value as f32 / 1000.0, 0.0, 0.0));
+                let expected = encode_2x2(arr_cv, &header.layout, &options, header.subsampling).unwrap();
+                let fused = encode_gray_2x2(&gray, &header, &options).unwrap();
+                assert!(fused == expected, "{}x{} gray {:?}", width, height, padding);
+            }
+        }
+    }
+
+    #[test]
+    #[ignore = "a benchmark; run it with --release -- --ignored --nocapture"]
+    fn fused_pipeline_speedup() {
+        let img = scene(3000, 2000);
+        for coding in [Coding::Fixed, Coding::Huffman] {
+            let options = Options { coding, ..Default::default() };
+            let header = lossy_header(img.width, img.height, img.denominator, 3, &options).unwrap();
+            // the fastest of a few runs, so a busy machine does not decide the comparison
+            let time = |encode: &dyn Fn() -> Vec<u8>| {
+                let mut best = Duration::MAX;
+                let mut bytes = vec![];
+                for _ in 0..3 {
+                    let start = Instant::now();
+                    bytes = encode();
+                    best = best.min(start.elapsed());
+                }
+                (best, bytes)
+            };
+            let (fused, fused_bytes) = time(&|| encode_rgb_2x2(&img, &header, &options).unwrap());
+            let (staged, staged_bytes) = time(&|| staged(&img, &header, &options));
+            assert!(fused_bytes == staged_bytes, "{}", coding);
+            eprintln!(
+                "{}x{} {}: fused {:?}, staged {:?}, {:.2}x",
+                img.width,
+                img.height,
+                coding,
+                fused,
+                staged,
+                staged.as_secs_f64() / fused.as_secs_f64()
+            );
+        }
+    }
+}
@@ -0,0 +1,533 @@
+use std::fmt;
+use std::str::FromStr;
+
+use bitpack::bitpack::{gets, getu, news, newu, BitpackError};
+
+use csc411_arith::{chroma_of_index, index_of_chroma};
+
+use crate::dither::Levels;
+use crate::quantize::{
+    decode_chroma, decodes, decodeu, encode_chroma, encodes, encodeu, umax, ChromaCurve, MAX_CHROMA_BITS,
+    MIN_CHROMA_BITS,
+};
+
+/// A single bit field inside a packed word
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Field {
+    pub width: u32,
+    pub lsb: u32,
+    pub signed: bool,
+}
+
+impl Field {
+    pub fn new(width: u32, lsb: u32, signed: bool) -> Self {
+        Field { width, lsb, signed }
+    }
+
+    /// Returns the bits of the word covered by this field
+    fn mask(&self) -> u64 {
+        (((1_u128 << self.width) - 1) as u64) << self.lsb
+    }
+
+    /// Returns the raw bits of the word covered by this field, shifted down to bit 0
+    pub fn extract(&self, word: u64) -> u64 {
+        (word & self.mask()) >> self.lsb
+    }
+
+    /// Returns the low bits of `value` moved up to where this field sits in a word
+    pub fn insert(&self, value: u64) -> u64 {
+        value << self.lsb & self.mask()
+    }
+}
+
+/// Describes how the six values of a 2x2 block (a, b, c, d, pb, pr) are laid out in a word,
+/// along with the clamp applied to the cosine coefficients b, c, and d before quantizing.
+/// Both the encoder and the decoder work from the same `WordLayout`, and the layout used
+/// to compress an image is written into the compressed file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WordLayout {
+    pub word_bits: u32,
+    pub a: Field,
+    pub b: Field,
+    pub c: Field,
+    pub d: Field,
+    pub pb: Field,
+    pub pr: Field,
+    pub cosine_force: f32,
+    /// How pb and pr are quantized; the native curves use the full width of their fields
+    pub chroma: ChromaCurve,
+}
+
+impl Default for WordLayout {
+    /// The classic 32-bit layout: a 9 bits, b/c/d 5 bits each, pb/pr 4 bits each
+    fn default() -> Self {
+        WordLayout::from_widths(32, [9, 5, 5, 5, 4, 4], 0.3).unwrap()
+    }
+}
+
+impl WordLayout {
+    /// A 64-bit layout with 12 bits for a and 8 bits for every other value
+    pub fn wide() -> Self {
+        WordLayout::from_widths(64, [12, 8, 8, 8, 8, 8], 0.3).unwrap()
+    }
+
+    /// A 32-bit layout for grayscale images, which spends the chroma bits on luminance:
+    /// 11 bits for a and 7 bits each for b, c, and d
+    pub fn gray() -> Self {
+        WordLayout::from_widths(32, [11, 7, 7, 7, 0, 0], 0.3).unwrap()
+    }
+
+    /// Builds a layout by placing the fields a, b, c, d, pb, pr one after another,
+    /// starting at the most-significant end of the word.
+    /// # Returns:
+    /// * `Result<WordLayout, String>`: the layout, or a description of why it is invalid
+    ///
+    /// # Arguments:
+    /// * `word_bits`: the size of a packed word, either 32 or 64
+    /// * `widths`: the widths of a, b, c, d, pb, and pr, in that order
+    /// * `cosine_force`: the magnitude at which b, c, and d are clamped
+    pub fn from_widths(word_bits: u32, widths: [u32; 6], cosine_force: f32) -> Result<Self, String> {
+        let total: u32 = widths.iter().sum();
+        if total > word_bits {
+            return Err(format!("fields need {} bits but the word only has {}", total, word_bits));
+        }
+
+        let mut lsb = word_bits;
+        let mut fields = [Field::new(0, 0, false); 6];
+        for (i, &width) in widths.iter().enumerate() {
+            lsb -= width;
+            // b, c, and d are the only signed values
+            fields[i] = Field::new(width, lsb, (1..=3).contains(&i));
+        }
+
+        let layout = WordLayout {
+            word_bits,
+            a: fields[0],
+            b: fields[1],
+            c: fields[2],
+            d: fields[3],
+            pb: fields[4],
+            pr: fields[5],
+            cosine_force,
+            chroma: ChromaCurve::Table,
+        };
+        layout.validate()?;
+        Ok(layout)
+    }
+
+    /// Returns the fields in a, b, c, d, pb, pr order
+    pub fn fields(&self) -> [Field; 6] {
+        [self.a, self.b, self.c, self.d, self.pb, self.pr]
+    }
+
+    /// Returns the fields a progressive payload puts first, in a, pb, pr order
+    pub fn coarse_fields(&self) -> [Field; 3] {
+        [self.a, self.pb, self.pr]
+    }
+
+    /// Returns the fields a progressive payload puts last, in b, c, d order
+    pub fn detail_fields(&self) -> [Field; 3] {
+        [self.b, self.c, self.d]
+    }
+
+    /// Whether the layout has room for pb and pr; grayscale layouts leave both out
+    pub fn has_chroma(&self) -> bool {
+        self.pb.width > 0 || self.pr.width > 0
+    }
+
+    /// Number of bytes a packed word occupies in the compressed file
+    pub fn bytes_per_word(&self) -> usize {
+        (self.word_bits / 8) as usize
+    }
+
+    /// Checks that the layout can actually be used to pack and unpack words
+    pub fn validate(&self) -> Result<(), String> {
+        if self.word_bits != 32 && self.word_bits != 64 {
+            return Err(format!("word size must be 32 or 64 bits, not {}", self.word_bits));
+        }
+        if !(self.cosine_force > 0.0 && self.cosine_force <= 1.0) {
+            return Err(format!("cosine force must be in (0, 1], not {}", self.cosine_force));
+        }
+
+        let names = ["a", "b", "c", "d", "pb", "pr"];
+        let mut used = 0_u64;
+        for (name, field) in names.iter().zip(self.fields()) {
+            if field.width > 31 || (field.width == 0 && !matches!(*name, "pb" | "pr")) {
+                return Err(format!("field {} must be 1 to 31 bits wide", name));
+            }
+            if field.lsb + field.width > self.word_bits {
+                return Err(format!("field {} does not fit in a {}-bit word", name, self.word_bits));
+            }
+            if field.signed != matches!(*name, "b" | "c" | "d") {
+                let expected = if field.signed { "unsigned" } else { "signed" };
+                return Err(format!("field {} must be {}", name, expected));
+            }
+            if used & field.mask() != 0 {
+                return Err(format!("field {} overlaps another field", name));
+            }
+            used |= field.mask();
+        }
+
+        // grayscale layouts have neither chroma field; otherwise fewer than 2 bits cannot tell
+        // positive chroma from negative
+        if self.has_chroma() && (self.pb.width < 2 || self.pr.width < 2) {
+            return Err("fields pb and pr must be at least 2 bits wide, or both 0".to_string());
+        }
+        let native = MIN_CHROMA_BITS..=MAX_CHROMA_BITS;
+        if self.has_chroma()
+            && self.chroma != ChromaCurve::Table
+            && !(native.contains(&self.pb.width) && native.contains(&self.pr.width))
+        {
+            return Err(format!(
+                "the {} chroma curve needs pb and pr to be {} to {} bits wide",
+                self.chroma, MIN_CHROMA_BITS, MAX_CHROMA_BITS
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Quantizes the coefficients of a 2x2 block
+    /// # Returns:
+    /// * `(u32, i32, i32, i32, usize, usize)`: the quantized a, b, c, d, pb, and pr
+    ///
+    /// # Arguments:
+    /// * `block`: a, b, c, d, pb, and pr
+    pub fn quantize(&self, block: (f32, f32, f32, f32, f32, f32)) -> (u32, i32, i32, i32, usize, usize) {
+        let (pb, pr) = self.quantize_chroma(block.4, block.5);
+        (
+            encodeu(block.0, self.a.width, 1.0),
+            encodes(block.1, self.b.width, self.cosine_force),
+            encodes(block.2, self.c.width, self.cosine_force),
+            encodes(block.3, self.d.width, self.cosine_force),
+            pb,
+            pr,
+        )
+    }
+
+    /// Quantizes one chroma sample to the widths of pb and pr with the layout's curve, as
+    /// `quantize` does for the chroma stored in a block's word
+    pub fn quantize_chroma(&self, pb: f32, pr: f32) -> (usize, usize) {
+        let quantize = |x: f32, field: &Field| match self.chroma {
+            ChromaCurve::Table => index_of_chroma(x) >> chroma_shift(field),
+            curve => encode_chroma(x, field.width, curve),
+        };
+        (quantize(pb, &self.pb), quantize(pr, &self.pr))
+    }
+
+    /// Reverses `quantize_chroma`
+    pub fn dequantize_chroma(&self, pb: usize, pr: usize) -> (f32, f32) {
+        let dequantize = |q: usize, field: &Field| match self.chroma {
+            ChromaCurve::Table => chroma_of_index(chroma_index(q, field)),
+            curve => decode_chroma(q, field.width, curve),
+        };
+        (dequantize(pb, &self.pb), dequantize(pr, &self.pr))
+    }
+
+    /// The values `dequantize` can give a
+    pub fn luma_levels(&self) -> Levels<impl Fn(u64) -> f32 + '_> {
+        Levels { codes: (0, umax(self.a.width) as u64), level: |q| decodeu(q as u32, self.a.width, 1.0) }
+    }
+
+    /// The values `dequantize_chroma` can give pb and pr. Every curve, the table included,
+    /// dequantizes larger codes to larger chroma, as `Levels` needs.
+    pub fn chroma_levels(&self) -> [Levels<impl Fn(u64) -> f32 + '_>; 2] {
+        [0, 1].map(|n| Levels {
+            codes: (0, umax([self.pb, self.pr][n].width) as u64),
+            level: move |q| {
+                let (pb, pr) = self.dequantize_chroma(q as usize, q as usize);
+                [pb, pr][n]
+            },
+        })
+    }
+
+    /// Reverses `quantize`
+    /// # Returns:
+    /// * `(f32, f32, f32, f32, f32, f32)`: a, b, c, d, pb, and pr
+    ///
+    /// # Arguments:
+    /// * `block`: the quantized a, b, c, d, pb, and pr
+    pub fn dequantize(&self, block: (u32, i32, i32, i32, usize, usize)) -> (f32, f32, f32, f32, f32, f32) {
+        let (pb, pr) = self.dequantize_chroma(block.4, block.5);
+        (
+            decodeu(block.0, self.a.width, 1.0),
+            decodes(block.1, self.b.width, self.cosine_force),
+            decodes(block.2, self.c.width, self.cosine_force),
+            decodes(block.3, self.d.width, self.cosine_force),
+            pb,
+            pr,
+        )
+    }
+
+    /// Packs quantized a, b, c, d, pb, and pr into a single word
+    /// # Returns:
+    /// * `Result<u64, BitpackError>`: the packed word, or which value did not fit in its field
+    ///
+    /// # Arguments:
+    /// * `block`: the quantized a, b, c, d, pb, and pr
+    pub fn pack(&self, block: (u32, i32, i32, i32, usize, usize)) -> Result<u64, BitpackError> {
+        let mut word = 0_u64;
+        word = newu(word, self.a.width as u64, self.a.lsb as u64, block.0 as u64)?;
+        word = news(word, self.b.width as u64, self.b.lsb as u64, block.1 as i64)?;
+        word = news(word, self.c.width as u64, self.c.lsb as u64, block.2 as i64)?;
+        word = news(word, self.d.width as u64, self.d.lsb as u64, block.3 as i64)?;
+        word = newu(word, self.pb.width as u64, self.pb.lsb as u64, block.4 as u64)?;
+        word = newu(word, self.pr.width as u64, self.pr.lsb as u64, block.5 as u64)?;
+        Ok(word)
+    }
+
+    /// Unpacks a word into quantized a, b, c, d, pb, and pr
+    /// # Returns:
+    /// * `(u32, i32, i32, i32, usize, usize)`: the quantized values
+    ///
+    /// # Arguments:
+    /// * `word`: a word packed with this layout
+    pub fn unpack(&self, word: u64) -> (u32, i32, i32, i32, usize, usize) {
+        (
+            getu(word, self.a.width as u64, self.a.lsb as u64) as u32,
+            gets(word, self.b.width as u64, self.b.lsb as u64) as i32,
+            gets(word, self.c.width as u64, self.c.lsb as u64) as i32,
+            gets(word, self.d.width as u64, self.d.lsb as u64) as i32,
+            getu(word, self.pb.width as u64, self.pb.lsb as u64) as usize,
+            getu(word, self.pr.width as u64, self.pr.lsb as u64) as usize,
+        )
+    }
+}
+
+/// Number of low bits dropped from a 4-bit chroma index to fit it in `field`
+fn chroma_shift(field: &Field) -> u32 {
+    4 - field.width.min(4)
+}
+
+/// Maps a chroma index stored in `field` back to an index into the 16-entry chroma table.
+/// When low bits were dropped, each stored value stands for a group of table entries and
+/// decodes to the middle of its group, rounded toward zero chroma so that positive and
+/// negative chroma stay balanced.
+fn chroma_index(q: usize, field: &Field) -> usize {
+    let shift = chroma_shift(field);
+    let groups = 16 >> shift;
+    let half = (1 << shift) / 2;
+    let index = if q < groups / 2 {
+        (q << shift) + half
+    } else {
+        ((q << shift) + half).saturating_sub(if shift > 0 { 1 } else { 0 })
+    };
+    index.min(15)
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.signed { 's' } else { 'u' };
+        write!(f, "{}@{}{}", self.width, self.lsb, sign)
+    }
+}
+
+impl FromStr for Field {
+    type Err = String;
+
+    /// Parses a field written as `width@lsb` followed by `u` or `s`, e.g. `9@23u`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = || format!("invalid field `{}`", s);
+        let signed = match s.chars().last() {
+            Some('s') => true,
+            Some('u') => false,
+            _ => return Err(bad()),
+        };
+        let (width, lsb) = s[..s.len() - 1].split_once('@').ok_or_else(bad)?;
+
+        Ok(Field {
+            width: width.parse().map_err(|_| bad())?,
+            lsb: lsb.parse().map_err(|_| bad())?,
+            signed,
+        })
+    }
+}
+
+impl fmt::Display for WordLayout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.word_bits, self.cosine_force)?;
+        for field in self.fields() {
+            write!(f, " {}", field)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for WordLayout {
+    type Err = String;
+
+    /// Parses a layout from one of:
+    /// * a preset name, `default`, `wide`, or `gray`
+    /// * word size and widths, e.g. `64:12,8,8,8,8,8` or `64:12,8,8,8,8,8:0.25`
+    /// * the full description written to compressed files, e.g. `32 0.3 9@23u 5@18s ...`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s {
+            "default" => return Ok(WordLayout::default()),
+            "wide" => return Ok(WordLayout::wide()),
+            "gray" => return Ok(WordLayout::gray()),
+            _ => {}
+        }
+
+        let bad = || format!("invalid word layout `{}`", s);
+
+        if s.contains(':') {
+            let mut parts = s.split(':');
+            let word_bits = parts.next().ok_or_else(bad)?.parse().map_err(|_| bad())?;
+            let widths: Vec<u32> = parts
+                .next()
+                .ok_or_else(bad)?
+                .split(',')
+                .map(|w| w.trim().parse().map_err(|_| bad()))
+                .collect::<Result<_, _>>()?;
+            let cosine_force = match parts.next() {
+                Some(force) => force.parse().map_err(|_| bad())?,
+                None => 0.3,
+            };
+            let widths: [u32; 6] = widths.try_into().map_err(|_| bad())?;
+            return WordLayout::from_widths(word_bits, widths, cosine_force);
+        }
+
+        let tokens: Vec<&str> = s.split_whitespace().collect();
+        if tokens.len() != 8 {
+            return Err(bad());
+        }
+        let fields: Vec<Field> = tokens[2..]
+            .iter()
+            .map(|t| t.parse())
+            .collect::<Result<_, _>>()?;
+
+        let layout = WordLayout {
+            word_bits: tokens[0].parse().map_err(|_| bad())?,
+            cosine_force: tokens[1].parse().map_err(|_| bad())?,
+            a: fields[0],
+            b: fields[1],
+            c: fields[2],
+            d: fields[3],
+            pb: fields[4],
+            pr: fields[5],
+            chroma: ChromaCurve::Table,
+        };
+        layout.validate()?;
+        Ok(layout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dither::{dither_plane, Dither};
+    use array2::Array2;
+
+    #[test]
+    fn default_matches_classic_layout() {
+        let layout = WordLayout::default();
+        assert_eq!(layout.a, Field::new(9, 23, false));
+        assert_eq!(layout.b, Field::new(5, 18, true));
+        assert_eq!(layout.c, Field::new(5, 13, true));
+        assert_eq!(layout.d, Field::new(5, 8, true));
+        assert_eq!(layout.pb, Field::new(4, 4, false));
+        assert_eq!(layout.pr, Field::new(4, 0, false));
+    }
+
+    #[test]
+    fn pack_and_unpack() {
+        for layout in [WordLayout::default(), WordLayout::wide()] {
+            let block = (300, -7, 3, 0, 15, 2);
+            let word = layout.pack(block).unwrap();
+            assert_eq!(layout.unpack(word), block);
+        }
+        assert_eq!(
+            WordLayout::default().pack((512, 0, 0, 0, 0, 0)),
+            Err(BitpackError::UnsignedOverflow { width: 9, value: 512 })
+        );
+    }
+
+    #[test]
+    fn parse_layouts() {
+        let wide = WordLayout::wide();
+        assert_eq!(wide.to_string().parse::<WordLayout>(), Ok(wide));
+        assert_eq!("64:12,8,8,8,8,8".parse::<WordLayout>(), Ok(wide));
+        assert_eq!("default".parse::<WordLayout>(), Ok(WordLayout::default()));
+        assert!("32:9,9,9,9,4,4".parse::<WordLayout>().is_err());
+        assert!("32 0.3 9@23u 5@18s 5@13s 5@8s 4@4u 4@2u".parse::<WordLayout>().is_err());
+
+        let gray = WordLayout::gray();
+        assert!(!gray.has_chroma());
+        assert_eq!("32:11,7,7,7,0,0".parse::<WordLayout>(), Ok(gray));
+        assert!("32:11,7,7,7,0,4".parse::<WordLayout>().is_err());
+        assert!("32:0,7,7,7,4,4".parse::<WordLayout>().is_err());
+    }
+
+    #[test]
+    fn narrow_chroma_stays_symmetric() {
+        let layout = WordLayout::from_widths(32, [9, 5, 5, 5, 2, 3], 0.3).unwrap();
+        let decoded: Vec<usize> = (0..4).map(|q| chroma_index(q, &layout.pb)).collect();
+        assert_eq!(decoded, vec![2, 6, 9, 13]);
+        let decoded: Vec<usize> = (0..8).map(|q| chroma_index(q, &layout.pr)).collect();
+        assert_eq!(decoded, vec![1, 3, 5, 7, 8, 10, 12, 14]);
+
+        let q = layout.quantize((0.5, 0.0, 0.0, 0.0, chroma_of_index(15), chroma_of_index(0)));
+        assert_eq!((q.4, q.5), (3, 0));
+    }
+
+    #[test]
+    fn quantize_round_trip() {
+        let layout = WordLayout::default();
+        let q = layout.quantize((0.5, 0.1, -0.2, 0.0, chroma_of_index(3), chroma_of_index(12)));
+        let (a, b, c, d, pb, pr) = layout.dequantize(q);
+        assert!((a - 0.5).abs() < 0.01);
+        assert!((b - 0.1).abs() < 0.02);
+        assert!((c + 0.2).abs() < 0.02);
+        assert_eq!(d, 0.0);
+        assert_eq!((pb, pr), (chroma_of_index(3), chroma_of_index(12)));
+    }
+
+    #[test]
+    fn the_widest_fields_round_trip() {
+        // the widest a and b the validator allows, at the ends of their ranges
+        let layout = WordLayout::from_widths(64, [31, 27, 1, 1, 2, 2], 0.3).unwrap();
+        for (a, b) in [(0.0, -0.3), (1.0, 0.3)] {
+            let word = layout.pack(layout.quantize((a, b, 0.0, 0.0, 0.0, 0.0))).unwrap();
+            let (qa, qb, ..) = layout.dequantize(layout.unpack(word));
+            assert_eq!((qa, qb), (a, b));
+        }
+    }
+
+    #[test]
+    fn native_chroma_needs_4_to_8_bits() {
+        let mut layout = WordLayout::wide();
+        layout.chroma = ChromaCurve::Companded;
+        assert!(layout.validate().is_ok());
+        let (qb, qr) = layout.quantize_chroma(0.01, -0.2);
+        let (pb, pr) = layout.dequantize_chroma(qb, qr);
+        assert!((pb - 0.01).abs() < 0.001 && (pr + 0.2).abs() < 0.01);
+
+        let mut narrow = WordLayout::from_widths(32, [9, 5, 5, 5, 3, 3], 0.3).unwrap();
+        narrow.chroma = ChromaCurve::Uniform;
+        assert!(narrow.validate().is_err());
+    }
+
+    #[test]
+    fn levels_are_found_without_listing_them() {
+        // a 31-bit a has 2^31 levels, far too many to list
+        let mut layout = WordLayout::from_widths(64, [31, 6, 6, 5, 8, 8], 0.3).unwrap();
+        let mut plane = Array2::from_row_major(2, 1, vec![0.3, 1.5]).unwrap();
+        dither_plane(&mut plane, &layout.luma_levels(), Dither::None, 0);
+        assert!((plane.data()[0] - 0.3).abs() < 1e-6);
+        assert_eq!(plane.data()[1], 1.0);
+
+        for curve in [ChromaCurve::Table, ChromaCurve::Uniform, ChromaCurve::Companded] {
+            layout.chroma = curve;
+            for (n, levels) in layout.chroma_levels().iter().enumerate() {
+                for q in 0..256 {
+                    let (pb, pr) = layout.dequantize_chroma(q, q);
+                    let level = [pb, pr][n];
+                    let mut plane = Array2::blank_state(1, 1, level);
+                    dither_plane(&mut plane, levels, Dither::None, 0);
+                    assert_eq!(plane.data()[0], level, "{} {}", curve, q);
+                }
+            }
+        }
+    }
+}
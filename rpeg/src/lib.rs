@@ -1,16 +1,36 @@
+pub mod bitio;
+pub mod checksum;
 pub mod codec;
+pub mod dct;
+pub mod dither;
+pub mod entropy;
+pub mod error;
+#[cfg(test)]
+mod fixtures;
+pub mod filter;
+pub mod format;
+mod fused;
+pub mod layout;
+pub mod lossless;
+pub mod padding;
+pub mod parallel;
+pub mod pnm;
+pub mod progressive;
+pub mod quality;
 pub mod quantize;
+pub mod rate;
+pub mod stream;
+pub mod subsampling;
+pub mod tile;
 pub mod to_component_video;
 pub mod to_rgb_float;
-
-use core::f32;
-use std::{usize, vec};
+pub mod transform;
 
 use array2::Array2;
-use bitpack::bitpack::{fitss, fitsu, gets, getu, news, newu};
+use bitpack::bitpack::BitpackError;
 use csc411_arith::{chroma_of_index, index_of_chroma};
 use csc411_image::Rgb;
-use quantize::{encodes, encodeu};
+use layout::WordLayout;
 use to_component_video::YPbPr;
 
 /// Trims an Array2<Rgb> to have an even width and height
@@ -20,13 +40,13 @@ use to_component_video::YPbPr;
 /// # Arguments:
 /// * `arr`: a ref Array2<Rgb>
 pub fn trim_to_even_dimensions(arr: &Array2<Rgb>) -> Array2<Rgb> {
-    let new_width = if arr.width() % 2 == 0 {
+    let new_width = if arr.width().is_multiple_of(2) {
         arr.width()
     } else {
         arr.width() - 1
     };
 
-    let new_height = if arr.height() % 2 == 0 {
+    let new_height = if arr.height().is_multiple_of(2) {
         arr.height()
     } else {
         arr.height() - 1
@@ -41,45 +61,51 @@ pub fn trim_to_even_dimensions(arr: &Array2<Rgb>) -> Array2<Rgb> {
         }
     }
 
-    return Array2::from_row_major(new_width, new_height, new_data).unwrap();
+    Array2::from_row_major(new_width, new_height, new_data).unwrap()
 }
 
-/// Packs pixels of a coponent video Array2 into 2x2 groups and computes the luminosity and chroma index values, and packaging them into a 6-tuple.
+/// Packs pixels of a coponent video Array2 into 2x2 groups and computes the luminosity and average chroma values, and packaging them into a 6-tuple.
 /// # Returns:
-/// * `Array2<(f32, f32, f32, f32, usize, usize)>` : a 6-tuple containing a, b, c, d, pb ave, and pr ave
+/// * `Array2<(f32, f32, f32, f32, f32, f32)>` : a 6-tuple containing a, b, c, d, pb ave, and pr ave
 ///
 /// # Arguments:
 /// * `arr`: a Array2<YPbPr>, a Array2 of component video pixels
-fn pack_2x2_elements(arr: Array2<YPbPr>) -> Array2<(f32, f32, f32, f32, usize, usize)> {
-    let mut packed_elements =
-        Array2::blank_state(arr.width / 2, arr.height / 2, (0.0, 0.0, 0.0, 0.0, 0, 0));
-
-    // Iterate through 2x2 groups
-    for x in (0..arr.width).step_by(2) {
-        for y in (0..arr.height).step_by(2) {
-            // Extract the 2x2 group
-            let e1 = arr.get(x, y);
-            let e2 = arr.get(x + 1, y);
-            let e3 = arr.get(x, y + 1);
-            let e4 = arr.get(x + 1, y + 1);
-
-            let group = [e1, e2, e3, e4];
-            let luminosity_coeffs = get_luminosity_coeffs(group);
-            let chroma_vals = average_pbpr(group);
-
-            let val = packed_elements.get_mut(x / 2, y / 2);
-            *val = (
-                luminosity_coeffs.0,
-                luminosity_coeffs.1,
-                luminosity_coeffs.2,
-                luminosity_coeffs.3,
-                chroma_vals.0,
-                chroma_vals.1,
-            );
+/// * `threads`: the most threads to split the rows of groups across
+fn pack_2x2_elements(arr: &Array2<YPbPr>, threads: usize) -> Array2<(f32, f32, f32, f32, f32, f32)> {
+    let (width, height) = (arr.width / 2, arr.height / 2);
+
+    // Each band of rows of 2x2 groups is packed on its own thread
+    let packed = parallel::map_bands(height, threads, |rows| {
+        let mut packed_elements = Vec::with_capacity(rows.len() * width);
+        for y in rows.map(|y| y * 2) {
+            for x in (0..arr.width).step_by(2) {
+                // Extract the 2x2 group
+                let e1 = arr.get(x, y);
+                let e2 = arr.get(x + 1, y);
+                let e3 = arr.get(x, y + 1);
+                let e4 = arr.get(x + 1, y + 1);
+
+                let group = [e1, e2, e3, e4];
+                let luminosity_coeffs = get_luminosity_coeffs(group);
+                let chroma_vals = (
+                    group.iter().map(|e| e.pb()).sum::<f32>() / 4.0,
+                    group.iter().map(|e| e.pr()).sum::<f32>() / 4.0,
+                );
+
+                packed_elements.push((
+                    luminosity_coeffs.0,
+                    luminosity_coeffs.1,
+                    luminosity_coeffs.2,
+                    luminosity_coeffs.3,
+                    chroma_vals.0,
+                    chroma_vals.1,
+                ));
+            }
         }
-    }
+        packed_elements
+    });
 
-    packed_elements
+    Array2::from_row_major(width, height, packed).unwrap()
 }
 
 /// Unpacks a pixels of a Array2 that contains a tuple of a, b, c, d, pb ave, and pr ave into pixels
@@ -143,57 +169,51 @@ pub fn unpack_2x2_pixels(arr: Array2<(u32, i32, i32, i32, usize, usize)>) -> Arr
 /// * `Array2<YPbPr>` : a Array2 of component video pixels
 ///
 /// # Arguments:
-/// * `arr`: a Array2<(u32, i32, i32, i32, usize, usize)>, a 6-tuple containing a, b, c, d, pb ave, and pr ave
+/// * `arr`: a Array2<(f32, f32, f32, f32, f32, f32)>, a 6-tuple containing a, b, c, d, pb ave, and pr ave
 pub fn unpack_2x2_pixels_from_float(
-    arr: Array2<(f32, f32, f32, f32, usize, usize)>,
+    arr: Array2<(f32, f32, f32, f32, f32, f32)>,
 ) -> Array2<YPbPr> {
-    let mut unpacked_elements = Array2::blank_state(
-        arr.width * 2,
-        arr.height * 2,
-        YPbPr {
-            y: 0.0,
-            pb: 0.0,
-            pr: 0.0,
-        },
-    );
-
-    // Iterate through packed elements
-    for x in 0..arr.width {
-        for y in 0..arr.height {
-            let y_coeffs = reverse_luminosity_coeffs_from_float(
-                arr.get(x, y).0,
-                arr.get(x, y).1,
-                arr.get(x, y).2,
-                arr.get(x, y).3,
-            );
-
-            let pb_chroma = chroma_of_index(arr.get(x, y).4);
-            let pr_chroma = chroma_of_index(arr.get(x, y).5);
-
-            // Unpack the values into the corresponding 2x2 group
-            let e1 = unpacked_elements.get_mut(x * 2, y * 2);
-            e1.y = y_coeffs.0 as f32;
-            e1.pb = pb_chroma;
-            e1.pr = pr_chroma;
-
-            let e2 = unpacked_elements.get_mut(x * 2 + 1, y * 2);
-            e2.y = y_coeffs.1 as f32;
-            e2.pb = pb_chroma;
-            e2.pr = pr_chroma;
-
-            let e3 = unpacked_elements.get_mut(x * 2, y * 2 + 1);
-            e3.y = y_coeffs.2 as f32;
-            e3.pb = pb_chroma;
-            e3.pr = pr_chroma;
+    unpack_2x2_pixels_from_float_with_threads(arr, 1)
+}
 
-            let e4 = unpacked_elements.get_mut(x * 2 + 1, y * 2 + 1);
-            e4.y = y_coeffs.3 as f32;
-            e4.pb = pb_chroma;
-            e4.pr = pr_chroma;
+/// Does what `unpack_2x2_pixels_from_float` does, splitting the rows of packed elements
+/// across threads
+/// # Returns:
+/// * `Array2<YPbPr>` : a Array2 of component video pixels
+///
+/// # Arguments:
+/// * `arr`: a Array2<(f32, f32, f32, f32, f32, f32)>, a 6-tuple containing a, b, c, d, pb ave, and pr ave
+/// * `threads`: the most threads to split the rows of packed elements across
+pub fn unpack_2x2_pixels_from_float_with_threads(
+    arr: Array2<(f32, f32, f32, f32, f32, f32)>,
+    threads: usize,
+) -> Array2<YPbPr> {
+    // Each band of rows of packed elements becomes twice as many rows of pixels
+    let unpacked = parallel::map_bands(arr.height, threads, |rows| {
+        let mut unpacked_elements = Vec::with_capacity(rows.len() * arr.width * 4);
+        for y in rows {
+            let groups: Vec<_> = (0..arr.width)
+                .map(|x| {
+                    let &(a, b, c, d, pb_chroma, pr_chroma) = arr.get(x, y);
+                    let y_coeffs = reverse_luminosity_coeffs_from_float(a, b, c, d);
+                    (y_coeffs, pb_chroma, pr_chroma)
+                })
+                .collect();
+
+            // Unpack the values into the top and then the bottom row of each 2x2 group
+            for (y_coeffs, pb_chroma, pr_chroma) in &groups {
+                unpacked_elements.push(YPbPr { y: y_coeffs.0, pb: *pb_chroma, pr: *pr_chroma });
+                unpacked_elements.push(YPbPr { y: y_coeffs.1, pb: *pb_chroma, pr: *pr_chroma });
+            }
+            for (y_coeffs, pb_chroma, pr_chroma) in &groups {
+                unpacked_elements.push(YPbPr { y: y_coeffs.2, pb: *pb_chroma, pr: *pr_chroma });
+                unpacked_elements.push(YPbPr { y: y_coeffs.3, pb: *pb_chroma, pr: *pr_chroma });
+            }
         }
-    }
+        unpacked_elements
+    });
 
-    unpacked_elements
+    Array2::from_row_major(arr.width * 2, arr.height * 2, unpacked).unwrap()
 }
 
 /// Computes the average chroma index for pb and pr
@@ -203,8 +223,8 @@ pub fn unpack_2x2_pixels_from_float(
 /// # Arguments:
 /// * `group` `: [YPbPr; 4], a slice of 4 component video pixels
 pub fn average_pbpr(group: [&YPbPr; 4]) -> (usize, usize) {
-    let pb_ave = (group[0].pb() + group[1].pb() + group[2].pb() + group[3].pb()) / 4 as f32;
-    let pr_ave = (group[0].pr() + group[1].pr() + group[2].pr() + group[3].pr()) / 4 as f32;
+    let pb_ave = (group[0].pb() + group[1].pb() + group[2].pb() + group[3].pb()) / 4_f32;
+    let pr_ave = (group[0].pr() + group[1].pr() + group[2].pr() + group[3].pr()) / 4_f32;
 
     (index_of_chroma(pb_ave), index_of_chroma(pr_ave))
 }
@@ -258,105 +278,45 @@ pub fn reverse_luminosity_coeffs(a: u32, b: i32, c: i32, d: i32) -> (u32, i32, i
     let y3 = a as i32 + b - c - d;
     let y4 = a as i32 + b + c + d;
 
-    (y1 as u32, y2 as i32, y3 as i32, y4 as i32)
+    (y1 as u32, y2, y3, y4)
 }
 
-/// Takes a, b, c, d, pb, and pr and triggers bitpacking functions with the given width and lsb
+/// Takes a, b, c, d, pb, and pr and bitpacks them into a word according to `layout`
 /// # Returns:
-/// * `Option<u32>`, a u32 bit-packed word
+/// * `Result<u64, BitpackError>`, the bit-packed word, or which value did not fit in its field
 /// # Arguments:
+/// * `layout`: the widths and positions of the fields in the word
 /// * `(a: u32, b: i32, c: i32, d: i32, pb: u32, pr: u32)`, a tuple of values to bitpack
-pub fn bitpack(a: u32, b: i32, c: i32, d: i32, pb: u32, pr: u32) -> Option<u32> {
-    let mut val: u64 = 0;
-
-    if fitsu(a as u64, 9) {
-        val = match newu(val, 9, 23, a as u64) {
-            Some(val) => val,
-            None => return None,
-        };
-    } else {
-        return None;
-    }
-
-    if fitss(b as i64, 5) {
-        val = match news(val, 5, 18, b as i64) {
-            Some(val) => val,
-            None => return None,
-        };
-    } else {
-        return None;
-    }
-
-    if fitss(c as i64, 5) {
-        val = match news(val, 5, 13, c as i64) {
-            Some(val) => val,
-            None => return None,
-        };
-    } else {
-        return None;
-    }
-
-    if fitss(d as i64, 5) {
-        val = match news(val, 5, 8, d as i64) {
-            Some(val) => val,
-            None => return None,
-        };
-    } else {
-        return None;
-    }
-
-    if fitsu(pb as u64, 4) {
-        val = match newu(val, 4, 4, pb as u64) {
-            Some(val) => val,
-            None => return None,
-        };
-    } else {
-        return None;
-    }
-
-    if fitsu(pr as u64, 4) {
-        val = match newu(val, 4, 0, pr as u64) {
-            Some(val) => val,
-            None => return None,
-        };
-    } else {
-        return None;
-    }
-
-    Some(val as u32)
+pub fn bitpack(layout: &WordLayout, a: u32, b: i32, c: i32, d: i32, pb: u32, pr: u32) -> Result<u64, BitpackError> {
+    layout.pack((a, b, c, d, pb as usize, pr as usize))
 }
 
-/// Takes a, b, c, d, pb, and pr and unpacks the bits for the corresponding value, given a width and lsb
+/// Takes a word and unpacks the bits for a, b, c, d, pb, and pr according to `layout`
 /// # Returns:
 /// * `(u32, i32, i32, i32, usize, usize)`, a tuple of values
 /// # Arguments:
-/// * `packed value: u32`, a u32 word
-pub fn unpack_bits(packed_value: u32) -> (u32, i32, i32, i32, usize, usize) {
-    let a = getu(packed_value as u64, 9, 23) as u32;
-    let b = gets(packed_value as u64, 5, 18) as i32;
-    let c = gets(packed_value as u64, 5, 13) as i32;
-    let d = gets(packed_value as u64, 5, 8) as i32;
-    let pb = getu(packed_value as u64, 4, 4) as usize;
-    let pr = getu(packed_value as u64, 4, 0) as usize;
-
-    (a, b, c, d, pb, pr)
+/// * `layout`: the widths and positions of the fields in the word
+/// * `packed value: u64`, a packed word
+pub fn unpack_bits(layout: &WordLayout, packed_value: u64) -> (u32, i32, i32, i32, usize, usize) {
+    layout.unpack(packed_value)
 }
 
 #[cfg(test)]
 mod tests {
-    use std::io::{self, Write};
-
     use crate::{
-        bitpack, pack_2x2_elements, quantize::encodes, quantize::encodeu,
+        bitpack, fixtures::gradient, layout::WordLayout, pack_2x2_elements, quantize::encodes, quantize::encodeu,
         to_component_video::from_ypbpr, to_component_video::to_ypbpr, to_rgb_float::from_rgb32,
-        to_rgb_float::to_rgbf32, trim_to_even_dimensions, unpack_2x2_pixels,
-        unpack_2x2_pixels_from_float, unpack_bits,
+        to_rgb_float::to_rgbf32, trim_to_even_dimensions, unpack_2x2_pixels, unpack_2x2_pixels_from_float,
+        unpack_2x2_pixels_from_float_with_threads,
+        unpack_bits,
     };
     use array2::Array2;
+    use csc411_arith::index_of_chroma;
     use csc411_image::{Read, Rgb, RgbImage, Write as RgbWrite};
-    use csc411_rpegio::{output_rpeg_data, output_rpeg_data_debug};
+    use csc411_rpegio::output_rpeg_data;
 
     #[test]
+    #[ignore = "reads frost.ppm, which is not in the repository"]
     fn start_to_ypbpr_and_back() {
         // Construct an Array2
         let img = RgbImage::read(Some("frost.ppm")).unwrap();
@@ -397,6 +357,7 @@ mod tests {
     }
 
     #[test]
+    #[ignore = "reads frost.ppm, which is not in the repository"]
     fn start_pack2x2_and_back() {
         // Construct an Array2
         let img = RgbImage::read(Some("frost.ppm")).unwrap();
@@ -423,7 +384,7 @@ mod tests {
         }*/
 
         let cv_arr = to_ypbpr(&float_arr);
-        let packed_arr = pack_2x2_elements(cv_arr);
+        let packed_arr = pack_2x2_elements(&cv_arr, 1);
 
         /*for (x, y, &ref element) in packed_arr.iter_row_major() {
             let modified_element = element.clone();
@@ -447,6 +408,9 @@ mod tests {
     }
 
     #[test]
+    #[ignore = "reads frost.ppm, which is not in the repository"]
+    // kept as written before the word layout could change, apart from the calls it changed and
+    // a check on the image it decodes
     fn start_encode_and_back() {
         // Construct an Array2
         let img = RgbImage::read(Some("frost.ppm")).unwrap();
@@ -462,10 +426,11 @@ mod tests {
 
         let cv_arr = to_ypbpr(&float_arr);
 
-        let packed_arr = pack_2x2_elements(cv_arr);
+        let packed_arr = pack_2x2_elements(&cv_arr, 1);
 
+        let layout = WordLayout::default();
         let mut empty_vec = vec![];
-        for (_x, _y, &ref element) in packed_arr.iter_row_major() {
+        for (_x, _y, element) in packed_arr.iter_row_major() {
             let qa = encodeu(element.0, 9, 0.3);
             let qb = encodes(element.1, 5, 0.3);
             let qc = encodes(element.2, 5, 0.3);
@@ -476,22 +441,23 @@ mod tests {
                 element.0, qa, element.1, qb, element.2, qc, element.3, qd
             );*/
 
-            let test = bitpack(qa, qb, qc, qd, element.4 as u32, element.5 as u32).unwrap();
+            let (pb, pr) = (index_of_chroma(element.4), index_of_chroma(element.5));
+            let test = bitpack(&layout, qa, qb, qc, qd, pb as u32, pr as u32).unwrap();
             empty_vec.push(test);
         }
 
-        let compressed_data: Vec<[u8; 4]> = empty_vec.into_iter().map(u32::to_be_bytes).collect();
+        let compressed_data: Vec<[u8; 4]> = empty_vec.into_iter().map(|word| (word as u32).to_be_bytes()).collect();
 
-        output_rpeg_data(&compressed_data, width, height).unwrap();
+        output_rpeg_data(&compressed_data, width as u32, height as u32);
 
         // Read in the rpeg data
         let input = Some("output.rpeg");
-        let (compressed_data, width, height) = csc411_rpegio::input_rpeg_data(input).unwrap();
+        let (compressed_data, width, height) = csc411_rpegio::read_in_rpeg_data(input).unwrap();
 
         let mut decompressed_vec = vec![];
 
         for word in compressed_data {
-            let vals = unpack_bits(u32::from_be_bytes(word));
+            let vals = unpack_bits(&layout, u32::from_be_bytes(word) as u64);
             decompressed_vec.push(vals);
         }
 
@@ -508,12 +474,92 @@ mod tests {
         let returned_float_arr = from_rgb32(&returned_cv_arr);
 
         let out_image = RgbImage {
-            height: height as u32,
-            width: width as u32,
+            height,
+            width,
             denominator: img.denominator,
             pixels: returned_float_arr.data,
         };
 
         //out_image.write(Some("new_out.ppm")).unwrap();
+        assert_eq!(out_image.pixels.len(), arr_trimmed.width * arr_trimmed.height);
+    }
+
+    // The gradient fixture, with odd dimensions, as an Array2
+    fn gradient_array(width: u32, height: u32) -> Array2<Rgb> {
+        let img = gradient(width, height);
+        Array2::from_row_major(width as usize, height as usize, img.pixels).unwrap()
+    }
+
+    // Mean absolute difference per channel between two images of the same size
+    fn mean_error(a: &Array2<Rgb>, b: &Array2<Rgb>) -> f64 {
+        assert_eq!((a.width(), a.height()), (b.width(), b.height()));
+        let total: u32 = a
+            .data()
+            .iter()
+            .zip(b.data())
+            .map(|(p, q)| {
+                p.red.abs_diff(q.red) as u32
+                    + p.green.abs_diff(q.green) as u32
+                    + p.blue.abs_diff(q.blue) as u32
+            })
+            .sum();
+        total as f64 / (3 * a.data().len()) as f64
+    }
+
+    #[test]
+    fn ypbpr_round_trip_is_close() {
+        let arr_trimmed = trim_to_even_dimensions(&gradient_array(33, 21));
+        assert_eq!((arr_trimmed.width(), arr_trimmed.height()), (32, 20));
+
+        let float_arr = to_rgbf32(&arr_trimmed);
+        let cv_arr = to_ypbpr(&float_arr);
+        let returned_cv_arr = from_ypbpr(&cv_arr);
+        let returned_float_arr = from_rgb32(&returned_cv_arr);
+
+        assert!(mean_error(&arr_trimmed, &returned_float_arr) < 1.0);
+    }
+
+    #[test]
+    fn packed_2x2_round_trip_is_close() {
+        let arr_trimmed = trim_to_even_dimensions(&gradient_array(33, 21));
+
+        let float_arr = to_rgbf32(&arr_trimmed);
+        let cv_arr = to_ypbpr(&float_arr);
+        let packed_arr = pack_2x2_elements(&cv_arr, 1);
+        let unpacked_arr = unpack_2x2_pixels_from_float(packed_arr);
+
+        let returned_cv_arr = from_ypbpr(&unpacked_arr);
+        let returned_float_arr = from_rgb32(&returned_cv_arr);
+
+        assert!(mean_error(&arr_trimmed, &returned_float_arr) < 8.0);
+    }
+
+    #[test]
+    fn packed_words_round_trip_is_close() {
+        for layout in [WordLayout::default(), WordLayout::wide()] {
+            let arr_trimmed = trim_to_even_dimensions(&gradient_array(33, 21));
+
+            let float_arr = to_rgbf32(&arr_trimmed);
+            let cv_arr = to_ypbpr(&float_arr);
+            let packed_arr = pack_2x2_elements(&cv_arr, 1);
+
+            let mut words = vec![];
+            for (_x, _y, element) in packed_arr.iter_row_major() {
+                let (qa, qb, qc, qd, pb, pr) = layout.quantize(*element);
+                words.push(bitpack(&layout, qa, qb, qc, qd, pb as u32, pr as u32).unwrap());
+            }
+
+            let decompressed_vec = words
+                .into_iter()
+                .map(|word| layout.dequantize(unpack_bits(&layout, word)))
+                .collect();
+            let decompressed_arr =
+                Array2::from_row_major(packed_arr.width, packed_arr.height, decompressed_vec).unwrap();
+            let unpacked_arr = unpack_2x2_pixels_from_float_with_threads(decompressed_arr, 2);
+            let returned_cv_arr = from_ypbpr(&unpacked_arr);
+            let returned_float_arr = from_rgb32(&returned_cv_arr);
+
+            assert!(mean_error(&arr_trimmed, &returned_float_arr) < 8.0);
+        }
     }
 }
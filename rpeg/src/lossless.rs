@@ -0,0 +1,130 @@
+use array2::Array2;
+
+use crate::entropy;
+use crate::error::RpegError;
+
+/// One step of the S-transform, the integer Haar transform
+/// # Returns:
+/// * `(i32, i32)`: the floor of the mean of `a` and `b`, and their difference
+fn split(a: i32, b: i32) -> (i32, i32) {
+    let h = a - b;
+    (b + (h >> 1), h)
+}
+
+/// Reverses `split`. It wraps rather than overflowing, since a damaged file can hold any
+/// coefficients.
+fn merge(l: i32, h: i32) -> (i32, i32) {
+    let b = l.wrapping_sub(h >> 1);
+    (b.wrapping_add(h), b)
+}
+
+/// Applies the S-transform to the rows and then the columns of a 2x2 block. Unlike the
+/// float transform in `get_luminosity_coeffs`, every step rounds the same way in both
+/// directions, so `unlift` recovers the block exactly.
+/// # Returns:
+/// * `[i32; 4]`: the low-pass coefficient, then the horizontal, vertical, and diagonal detail
+///
+/// # Arguments:
+/// * `block`: the top-left, top-right, bottom-left, and bottom-right samples
+pub fn lift(block: [i32; 4]) -> [i32; 4] {
+    let (l0, h0) = split(block[0], block[1]);
+    let (l1, h1) = split(block[2], block[3]);
+    let (ll, lh) = split(l0, l1);
+    let (hl, hh) = split(h0, h1);
+    [ll, hl, lh, hh]
+}
+
+/// Reverses `lift`
+pub fn unlift(coeffs: [i32; 4]) -> [i32; 4] {
+    let [ll, hl, lh, hh] = coeffs;
+    let (l0, l1) = merge(ll, lh);
+    let (h0, h1) = merge(hl, hh);
+    let (p00, p01) = merge(l0, h0);
+    let (p10, p11) = merge(l1, h1);
+    [p00, p01, p10, p11]
+}
+
+/// Losslessly codes integer planes with even dimensions. Each 2x2 block of each plane is
+/// lifted; the low-pass coefficient is predicted by the previous block's and the detail
+/// coefficients are kept as they are. The four kinds of residual of every plane are
+/// then entropy coded as separate streams.
+/// # Returns:
+/// * `Vec<u8>`: the coded planes
+///
+/// # Arguments:
+/// * `planes`: the planes, all with the same even dimensions
+pub fn encode(planes: &[Array2<i32>]) -> Vec<u8> {
+    let mut streams = vec![];
+    for plane in planes {
+        let mut subbands: [Vec<i32>; 4] = Default::default();
+        let mut prev_ll = 0;
+        for y in (0..plane.height()).step_by(2) {
+            for x in (0..plane.width()).step_by(2) {
+                let block = [*plane.get(x, y), *plane.get(x + 1, y), *plane.get(x, y + 1), *plane.get(x + 1, y + 1)];
+                let coeffs = lift(block);
+                subbands[0].push(coeffs[0] - prev_ll);
+                prev_ll = coeffs[0];
+                for (subband, &c) in subbands[1..].iter_mut().zip(&coeffs[1..]) {
+                    subband.push(c);
+                }
+            }
+        }
+        streams.extend(subbands);
+    }
+    entropy::encode_residuals(&streams)
+}
+
+/// Reverses `encode`
+/// # Returns:
+/// * `Result<Vec<Array2<i32>>, RpegError>`: the planes
+///
+/// # Arguments:
+/// * `bytes`: the output of `encode`
+/// * `width`, `height`: the even dimensions of the planes
+/// * `planes`: the number of planes
+pub fn decode(bytes: &[u8], width: usize, height: usize, planes: usize) -> Result<Vec<Array2<i32>>, RpegError> {
+    let blocks = (width / 2) * (height / 2);
+    let streams = entropy::decode_residuals(bytes, &vec![blocks; 4 * planes])?;
+
+    let mut decoded = vec![];
+    for subbands in streams.chunks_exact(4) {
+        let mut plane = Array2::blank_state(width, height, 0);
+        let mut ll = 0_i32;
+        let [deltas, hl, lh, hh] = subbands else { unreachable!() };
+        for (i, delta) in deltas.iter().enumerate() {
+            ll = ll.wrapping_add(*delta);
+            let block = unlift([ll, hl[i], lh[i], hh[i]]);
+            let (x, y) = (i % (width / 2) * 2, i / (width / 2) * 2);
+            *plane.get_mut(x, y) = block[0];
+            *plane.get_mut(x + 1, y) = block[1];
+            *plane.get_mut(x, y + 1) = block[2];
+            *plane.get_mut(x + 1, y + 1) = block[3];
+        }
+        decoded.push(plane);
+    }
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lifting_is_reversible() {
+        for block in [[0, 0, 0, 0], [255, 0, 0, 255], [-65535, 65535, 3, -7], [1, 2, 3, 4]] {
+            assert_eq!(unlift(lift(block)), block);
+        }
+        assert_eq!(lift([10, 10, 10, 10]), [10, 0, 0, 0]);
+    }
+
+    #[test]
+    fn planes_round_trip() {
+        let data: Vec<i32> = (0..6 * 4).map(|i| (i * 37 % 101) - 50).collect();
+        let plane = || Array2::from_row_major(6, 4, data.clone()).unwrap();
+        let bytes = encode(&[plane(), plane()]);
+
+        let planes = decode(&bytes, 6, 4, 2).unwrap();
+        assert_eq!(planes.len(), 2);
+        assert!(planes.iter().all(|p| *p.data() == data));
+    }
+}
@@ -1,92 +1,270 @@
-use rpeg::codec::{compress, decompress};
+use rpeg::codec::{
+    compress_gray_to, compress_rgba_to, compress_to, decode_region_with, decompress_image_with, verify_bytes,
+    DecodeOptions, Options,
+};
+use rpeg::pnm::{read_pnm, write_pnm, Image};
+use rpeg::progressive::decompress_preview;
+use rpeg::rate::compress_to_target;
+use rpeg::stream::{compress_stream, decompress_stream};
+use rpeg::tile::Region;
+use std::io::{BufRead, BufReader, BufWriter, Read as _, Write as _};
 use std::env;
+use std::error::Error;
+use std::process::exit;
 
-fn main() {
+const USAGE: &str = "Usage: rpeg -d [--stream] [--stats] [--deblock] [--upsample replicate|bilinear] [--region X,Y,W,H] [--threads N] [filename]\nrpeg thumbnail [--stats] [filename]\nrpeg verify filename...\nrpeg -c [--stream] [--progressive] [--layout default|wide|gray|BITS:A,B,C,D,PB,PR] [--padding edge|mirror|zero] [--transform 2x2|dct|lossless] [--quality 1-100] [--coding fixed|huffman] [--color bt601|bt709|ycocg] [--subsampling 444|422|420] [--chroma table|uniform|companded] [--dither none|ordered|diffusion] [--seed N] [--target-bytes N] [--tile N] [--threads N] [filename]";
 
+fn main() {
     let args: Vec<String> = env::args().collect();
-    let argnum = args.len();
-    assert!(argnum == 2 || argnum == 3);
-    let filename = args.iter().nth(2).unwrap();
+    if args.len() < 2 {
+        eprintln!("{}", USAGE);
+        exit(1);
+    }
 
-    match args[1].as_str() {
-        "-c" => compress(Some(filename)),
-        "-d" => decompress(Some(filename)),
+    let result = match args[1].as_str() {
+        "-c" => compress(&args[2..]),
+        "-d" => decompress(&args[2..]),
+        "thumbnail" => thumbnail(&args[2..]),
+        "verify" => verify(&args[2..]),
         _ => {
-            eprintln!("Usage: rpeg -d [filename]\nrpeg -c [filename]")
+            eprintln!("{}", USAGE);
+            exit(1);
         }
-    }
-
-
-
-
+    };
 
+    if let Err(e) = result {
+        eprintln!("rpeg: {}", e);
+        exit(1);
+    }
+}
 
+/// Reads a ppm, pgm, or pam image from a file or stdin and writes the compressed image to stdout
+/// With `--target-bytes`, picks the highest quality that fits and reports it on stderr.
+/// With `--stream`, reads and compresses the image a pair of rows at a time.
+fn compress(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut options = Options::default();
+    let mut stream = false;
+    let mut target = None;
+    let mut filename = None;
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--stream" => stream = true,
+            "--progressive" => options.progressive = true,
+            "--layout" => {
+                let spec = rest.next().ok_or("--layout needs a value")?;
+                options.layout = spec.parse()?;
+            }
+            "--padding" => {
+                let padding = rest.next().ok_or("--padding needs a value")?;
+                options.padding = padding.parse()?;
+            }
+            "--transform" => {
+                let transform = rest.next().ok_or("--transform needs a value")?;
+                options.transform = transform.parse()?;
+            }
+            "--quality" => {
+                let quality = rest.next().ok_or("--quality needs a value")?;
+                options.quality = Some(quality.parse()?);
+            }
+            "--coding" => {
+                let coding = rest.next().ok_or("--coding needs a value")?;
+                options.coding = coding.parse()?;
+            }
+            "--color" => {
+                let color = rest.next().ok_or("--color needs a value")?;
+                options.color = color.parse()?;
+            }
+            "--subsampling" => {
+                let subsampling = rest.next().ok_or("--subsampling needs a value")?;
+                options.subsampling = Some(subsampling.parse()?);
+            }
+            "--chroma" => {
+                let chroma = rest.next().ok_or("--chroma needs a value")?;
+                options.chroma = Some(chroma.parse()?);
+            }
+            "--dither" => {
+                let dither = rest.next().ok_or("--dither needs a value")?;
+                options.dither = dither.parse()?;
+            }
+            "--seed" => {
+                let seed = rest.next().ok_or("--seed needs a value")?;
+                options.seed = seed.parse()?;
+            }
+            "--target-bytes" => {
+                let bytes = rest.next().ok_or("--target-bytes needs a value")?;
+                target = Some(bytes.parse()?);
+            }
+            "--tile" => {
+                let size = rest.next().ok_or("--tile needs a value")?;
+                options.tile_size = Some(size.parse()?);
+            }
+            "--threads" => {
+                let threads = rest.next().ok_or("--threads needs a value")?;
+                options.threads = parse_threads(threads)?;
+            }
+            _ => filename = Some(arg.as_str()),
+        }
+    }
 
+    if stream {
+        if target.is_some() {
+            return Err("--target-bytes needs the whole image and cannot be used with --stream".into());
+        }
+        compress_stream(open_input(filename)?, BufWriter::new(std::io::stdout().lock()), &options)?;
+        return Ok(());
+    }
 
+    // grayscale and alpha modes are picked by the input's magic number and tuple type
+    let img = read_pnm(&read_input(filename)?)?;
+    if let Some(target) = target {
+        let result = compress_to_target(&img, &options, target)?;
+        eprintln!(
+            "wrote {} of {} bytes at quality {} with {} coding, rmse {:.4}",
+            result.bytes.len(),
+            target,
+            result.quality,
+            result.coding,
+            result.rmse
+        );
+        std::io::stdout().lock().write_all(&result.bytes)?;
+        return Ok(());
+    }
+    match img {
+        Image::Rgb(img) => compress_to(&img, &options, std::io::stdout().lock())?,
+        Image::Gray(img) => compress_gray_to(&img, &options, std::io::stdout().lock())?,
+        Image::Rgba(img) => compress_rgba_to(&img, &options, std::io::stdout().lock())?,
+    }
+    Ok(())
+}
 
+/// Reads a compressed image from a file or stdin and writes the ppm, pgm, or pam image to stdout.
+/// With `--stats`, also reports on stderr how many samples had to be clamped.
+/// With `--stream`, decodes and writes the image a pair of rows at a time.
+/// With `--region`, decodes only a rectangle of the image.
+fn decompress(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut stream = false;
+    let mut region = None;
+    let mut stats = false;
+    let mut options = DecodeOptions::default();
+    let mut filename = None;
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--stream" => stream = true,
+            "--stats" => stats = true,
+            "--deblock" => options.deblock = true,
+            "--upsample" => {
+                let upsampling = rest.next().ok_or("--upsample needs a value")?;
+                options.chroma_upsampling = upsampling.parse()?;
+            }
+            "--region" => {
+                let spec = rest.next().ok_or("--region needs a value")?;
+                region = Some(parse_region(spec)?);
+            }
+            "--threads" => {
+                let threads = rest.next().ok_or("--threads needs a value")?;
+                options.threads = parse_threads(threads)?;
+            }
+            _ => filename = Some(arg.as_str()),
+        }
+    }
 
-    /* This struc is for our temp type of f32s
-    #[derive(Clone, Debug)]
-    pub struct RgbF32Temp {
-        red: f32,
-        green: f32,
-        blue: f32,
+    if stream && region.is_some() {
+        return Err("--region needs the whole file and cannot be used with --stream".into());
     }
-    impl fmt::Display for RgbF32Temp {
-        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-            write!(
-                f,
-                "RgbF32Temp {{ red: {}, green: {}, blue: {} }}",
-                self.red, self.green, self.blue
-            )
+    let decode_stats = if stream {
+        decompress_stream(open_input(filename)?, BufWriter::new(std::io::stdout().lock()), &options)?
+    } else {
+        let bytes = read_input(filename)?;
+        let (img, decode_stats) = match region {
+            Some(region) => decode_region_with(&bytes, region, &options)?,
+            None => decompress_image_with(&bytes, &options)?,
+        };
+        write_pnm(&mut std::io::stdout().lock(), &img)?;
+        decode_stats
+    };
+    if stats {
+        eprintln!("clipped {} of {} samples", decode_stats.clipped, decode_stats.samples);
+    }
+    Ok(())
+}
+
+/// Reads a compressed image from a file or stdin and writes a half-scale preview of it to
+/// stdout. Progressive files are only read as far as the preview needs.
+/// With `--stats`, also reports on stderr how many samples had to be clamped.
+fn thumbnail(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut stats = false;
+    let mut filename = None;
+    for arg in args {
+        match arg.as_str() {
+            "--stats" => stats = true,
+            _ => filename = Some(arg.as_str()),
         }
-    }*/
+    }
 
-    // read in
-    /*
-    let input = env::args().nth(1);
+    let (img, decode_stats) = decompress_preview(open_input(filename)?)?;
+    write_pnm(&mut std::io::stdout().lock(), &img)?;
+    if stats {
+        eprintln!("clipped {} of {} samples", decode_stats.clipped, decode_stats.samples);
+    }
+    Ok(())
+}
 
-    // set rgb image with th filename
-    let img = RgbImage::read(input.as_deref()).unwrap();
+/// Parses a rectangle written as `X,Y,W,H`
+fn parse_region(spec: &str) -> Result<Region, Box<dyn Error>> {
+    let values = spec.split(',').map(str::parse).collect::<Result<Vec<u32>, _>>()?;
+    let [x, y, width, height] = values[..] else {
+        return Err(format!("region `{}` should be X,Y,W,H", spec).into());
+    };
+    Ok(Region { x, y, width, height })
+}
 
-    // get our values from grayimage to the correct types for our structure
-    let height2 = img.height.try_into().unwrap();
-    let width2 = img.width.try_into().unwrap();
+/// Parses a number of threads, using no more than the machine can run at once
+fn parse_threads(value: &str) -> Result<usize, Box<dyn Error>> {
+    let available = std::thread::available_parallelism().map_or(1, |n| n.get());
+    Ok(value.parse::<usize>()?.min(available))
+}
 
-    // Got to figure out how we're representing RGB
-    let usize_vec: Vec<csc411_image::Rgb> = img.pixels.clone();
+/// Opens a file, or stdin when there is no filename, for reading a little at a time
+fn open_input(filename: Option<&str>) -> std::io::Result<Box<dyn BufRead>> {
+    match filename {
+        Some(filename) => Ok(Box::new(BufReader::new(std::fs::File::open(filename)?))),
+        None => Ok(Box::new(std::io::stdin().lock())),
+    }
+}
 
-    //iter().map(|rgb| (rgb.red, rgb.green, rgb.blue)).collect();
-    let try_2 = Array2::from_row_major(width2, height2, usize_vec).unwrap();
-    /*for (x, y, &ref element) in try_2.iter_row_major(){
-        println!("{}, {}, {:?}", x, y, element);
-    }*/
-    // match case for rotate
-    */
-    // trim to even with function call
+/// Reads the whole of a file, or of stdin when there is no filename
+fn read_input(filename: Option<&str>) -> std::io::Result<Vec<u8>> {
+    match filename {
+        Some(filename) => std::fs::read(filename),
+        None => {
+            let mut bytes = vec![];
+            std::io::stdin().lock().read_to_end(&mut bytes)?;
+            Ok(bytes)
+        }
+    }
+}
 
-    //let ben = try_2.trim_to_even_dimensions();
+/// Checks that each compressed file is intact, printing one line per file
+fn verify(args: &[String]) -> Result<(), Box<dyn Error>> {
+    if args.is_empty() {
+        return Err(USAGE.into());
+    }
 
-    // here is the process for making the values f32s.
-    /*let new_data: Vec<RgbF32Temp> = ben
-        .iter_row_major()
-        .map(|(_, _, element)| {
-            let r = element.red as f32 / img.denominator as f32;
-            let g = element.green as f32 / img.denominator as f32;
-            let b = element.blue as f32 / img.denominator as f32;
-            RgbF32Temp {
-                red: r,
-                green: g,
-                blue: b,
+    let mut failed = 0;
+    for filename in args {
+        match std::fs::read(filename).map_err(Into::into).and_then(|bytes| verify_bytes(&bytes)) {
+            Ok(header) => println!("{}: ok, {}x{} {}", filename, header.width, header.height, header.transform),
+            Err(e) => {
+                println!("{}: {}", filename, e);
+                failed += 1;
             }
-        })
-        .collect();
-
-    // creating new instnace for f 32s
-
-    let ben2 = Array2::from_row_major(ben.width(), ben.height(), new_data).unwrap();
-
-    // testing to make sure dimensions function is working*/
+        }
+    }
 
+    if failed > 0 {
+        return Err(format!("{} of {} files failed verification", failed, args.len()).into());
+    }
+    Ok(())
 }
-
@@ -0,0 +1,137 @@
+use std::str::FromStr;
+
+use array2::Array2;
+
+/// How the encoder fills the extra rows and columns needed to make an image
+/// a whole number of blocks wide and high
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Padding {
+    /// Repeat the last row/column
+    #[default]
+    Edge,
+    /// Reflect the image about its last row/column
+    Mirror,
+    /// Fill with a constant (black) value
+    Zero,
+}
+
+impl FromStr for Padding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "edge" => Ok(Padding::Edge),
+            "mirror" => Ok(Padding::Mirror),
+            "zero" => Ok(Padding::Zero),
+            _ => Err(format!("unknown padding `{}`, expected edge, mirror, or zero", s)),
+        }
+    }
+}
+
+/// Rounds `n` up to a multiple of `block`
+pub fn round_up(n: usize, block: usize) -> usize {
+    n.div_ceil(block) * block
+}
+
+/// Maps a coordinate past the end of a row/column of length `len` back inside it
+/// # Returns:
+/// * `Option<usize>`: the coordinate to copy from, or `None` for zero padding
+///
+/// # Arguments:
+/// * `i`: the coordinate, which may be `len` or more
+/// * `len`: the length of the row/column
+/// * `padding`: how to fill coordinates past the end
+pub(crate) fn source_index(i: usize, len: usize, padding: Padding) -> Option<usize> {
+    if i < len {
+        return Some(i);
+    }
+
+    match padding {
+        Padding::Edge => Some(len - 1),
+        Padding::Zero => None,
+        Padding::Mirror => {
+            if len == 1 {
+                return Some(0);
+            }
+            // reflect without repeating the edge, bouncing back and forth if the
+            // padding is wider than the image itself
+            let period = 2 * (len - 1);
+            let i = i % period;
+            Some(if i < len { i } else { period - i })
+        }
+    }
+}
+
+/// Pads an Array2 so its width and height are multiples of `block`
+/// # Returns:
+/// * `Array2<T>`: the padded Array2; it is a copy of `arr` if no padding is needed
+///
+/// # Arguments:
+/// * `arr`: the Array2 to pad
+/// * `block`: the block size the dimensions must be a multiple of
+/// * `padding`: how to fill the new rows and columns
+/// * `zero`: the value used for `Padding::Zero`
+pub fn pad_to_multiple<T: Clone>(arr: &Array2<T>, block: usize, padding: Padding, zero: T) -> Array2<T> {
+    let new_width = round_up(arr.width(), block);
+    let new_height = round_up(arr.height(), block);
+
+    let mut new_data = Vec::with_capacity(new_width * new_height);
+    for y in 0..new_height {
+        for x in 0..new_width {
+            let sx = source_index(x, arr.width(), padding);
+            let sy = source_index(y, arr.height(), padding);
+            let element = match (sx, sy) {
+                (Some(sx), Some(sy)) => arr.get(sx, sy).clone(),
+                _ => zero.clone(),
+            };
+            new_data.push(element);
+        }
+    }
+
+    Array2::from_row_major(new_width, new_height, new_data).unwrap()
+}
+
+/// Crops an Array2 to its top-left `width` x `height` elements
+/// # Returns:
+/// * `Array2<T>`: the cropped Array2
+///
+/// # Arguments:
+/// * `arr`: the Array2 to crop, at least `width` x `height`
+/// * `width`: the width to keep
+/// * `height`: the height to keep
+pub fn crop<T: Clone>(arr: &Array2<T>, width: usize, height: usize) -> Array2<T> {
+    let mut new_data = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            new_data.push(arr.get(x, y).clone());
+        }
+    }
+
+    Array2::from_row_major(width, height, new_data).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(values: Vec<u8>) -> Array2<u8> {
+        Array2::from_row_major(values.len(), 1, values).unwrap()
+    }
+
+    #[test]
+    fn pad_each_strategy() {
+        let arr = row(vec![1, 2, 3]);
+        let first_row = |padding| pad_to_multiple(&arr, 8, padding, 0).data[..8].to_vec();
+        assert_eq!(first_row(Padding::Edge), vec![1, 2, 3, 3, 3, 3, 3, 3]);
+        assert_eq!(first_row(Padding::Mirror), vec![1, 2, 3, 2, 1, 2, 3, 2]);
+        assert_eq!(first_row(Padding::Zero), vec![1, 2, 3, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn pad_then_crop() {
+        let arr = Array2::from_row_major(3, 3, (0..9).collect()).unwrap();
+        let padded = pad_to_multiple(&arr, 2, Padding::Mirror, 0);
+        assert_eq!((padded.width(), padded.height()), (4, 4));
+        assert_eq!(crop(&padded, 3, 3).data, arr.data);
+    }
+}
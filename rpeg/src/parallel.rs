@@ -0,0 +1,78 @@
+use std::ops::Range;
+use std::thread;
+
+use array2::Array2;
+
+/// Splits `0..rows` into up to `threads` contiguous bands of nearly equal height, runs `f`
+/// on each band on a thread of its own, and concatenates the results in band order. The
+/// output is the same for every number of threads as long as `f` only depends on the rows
+/// it is given.
+/// # Returns:
+/// * `Vec<T>`: the results of every band, top to bottom
+///
+/// # Arguments:
+/// * `rows`: the number of rows to split
+/// * `threads`: the most threads to use; 0 and 1 both run `f` once on the calling thread
+/// * `f`: computes the results of a band of rows
+pub fn map_bands<T, F>(rows: usize, threads: usize, f: F) -> Vec<T>
+where
+    T: Send,
+    F: Fn(Range<usize>) -> Vec<T> + Sync,
+{
+    let bands = threads.clamp(1, rows.max(1));
+    if bands == 1 {
+        return f(0..rows);
+    }
+
+    let f = &f;
+    thread::scope(|scope| {
+        let handles: Vec<_> = (0..bands)
+            .map(|band| scope.spawn(move || f(band * rows / bands..(band + 1) * rows / bands)))
+            .collect();
+        handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect()
+    })
+}
+
+/// Applies `f` to every element of an Array2, splitting the rows across threads
+/// # Returns:
+/// * `Array2<U>`: the results, in the same places as the elements they came from
+///
+/// # Arguments:
+/// * `arr`: the elements
+/// * `threads`: the most threads to use, as for `map_bands`
+/// * `f`: the function to apply
+pub fn map_array<T, U, F>(arr: &Array2<T>, threads: usize, f: F) -> Array2<U>
+where
+    T: Clone + Sync,
+    U: Clone + Send,
+    F: Fn(&T) -> U + Sync,
+{
+    let width = arr.width();
+    let data = map_bands(arr.height(), threads, |rows| {
+        rows.flat_map(|y| (0..width).map(move |x| (x, y))).map(|(x, y)| f(arr.get(x, y))).collect()
+    });
+    Array2::from_row_major(width, arr.height(), data).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bands_cover_every_row_in_order() {
+        for threads in [0, 1, 2, 3, 7, 50] {
+            let rows = map_bands(7, threads, |band| band.collect());
+            assert_eq!(rows, (0..7).collect::<Vec<_>>(), "{} threads", threads);
+        }
+        assert!(map_bands(0, 4, |band| band.collect::<Vec<_>>()).is_empty());
+
+        // each band runs once, on a thread other than the caller's
+        let caller = thread::current().id();
+        let bands = map_bands(7, 3, |band| vec![(band, thread::current().id())]);
+        assert_eq!(bands.iter().map(|(band, _)| band.clone()).collect::<Vec<_>>(), [0..2, 2..4, 4..7]);
+        assert!(bands.iter().all(|&(_, id)| id != caller));
+
+        let arr = Array2::from_row_major(3, 5, (0..15).collect()).unwrap();
+        assert_eq!(*map_array(&arr, 4, |&v| v * 2).data(), (0..15).map(|v| v * 2).collect::<Vec<_>>());
+    }
+}
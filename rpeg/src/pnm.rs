@@ -0,0 +1,496 @@
+use std::io::{self, BufRead, Read, Write};
+
+use csc411_image::{Gray, GrayImage, Rgb, RgbImage};
+
+use crate::error::RpegError;
+
+/// A colour image with an alpha channel. The alpha samples are in row-major order and
+/// share the colour image's denominator, which stands for fully opaque.
+#[derive(Debug)]
+pub struct RgbaImage {
+    pub color: RgbImage,
+    pub alpha: Vec<u16>,
+}
+
+/// A colour, grayscale, or transparent image read from a netpbm file
+#[derive(Debug)]
+pub enum Image {
+    Rgb(RgbImage),
+    Gray(GrayImage),
+    Rgba(RgbaImage),
+}
+
+impl Image {
+    /// Converts the image to colour; a grayscale pixel becomes three equal samples, and
+    /// alpha is dropped
+    pub fn to_rgb(&self) -> RgbImage {
+        match self {
+            Image::Rgb(img) | Image::Rgba(RgbaImage { color: img, .. }) => RgbImage {
+                width: img.width,
+                height: img.height,
+                denominator: img.denominator,
+                pixels: img.pixels.clone(),
+            },
+            Image::Gray(img) => RgbImage {
+                width: img.width,
+                height: img.height,
+                denominator: img.denominator,
+                pixels: img.pixels.iter().map(|p| Rgb { red: p.value, green: p.value, blue: p.value }).collect(),
+            },
+        }
+    }
+
+    /// Like `to_rgb`, but takes the image so that colour pixels are not copied
+    pub fn into_rgb(self) -> RgbImage {
+        match self {
+            Image::Rgb(img) => img,
+            Image::Rgba(img) => img.color,
+            Image::Gray(img) => RgbImage {
+                width: img.width,
+                height: img.height,
+                denominator: img.denominator,
+                pixels: img.pixels.iter().map(|p| Rgb { red: p.value, green: p.value, blue: p.value }).collect(),
+            },
+        }
+    }
+}
+
+/// Reads a PPM (P3 or P6), PGM (P2 or P5), or PAM (P7) image with any maxval from 1 to
+/// 65535, choosing between them by the magic number. PAM images must have a tuple type of
+/// GRAYSCALE, RGB, or RGB_ALPHA. Unlike `csc411_image`, samples of raw images with a
+/// maxval above 255 are read as the two-byte big-endian values the format specifies, so
+/// 16-bit images keep their precision.
+/// # Returns:
+/// * `Result<Image, RpegError>`: the image, whose denominator is the file's maxval
+///
+/// # Arguments:
+/// * `bytes`: the contents of a PPM, PGM, or PAM file
+pub fn read_pnm(bytes: &[u8]) -> Result<Image, RpegError> {
+    let mut r = PnmReader { bytes, pos: 0 };
+    let magic = r.token()?;
+    let (channels, plain) = match magic.as_str() {
+        "P2" => (1, true),
+        "P3" => (3, true),
+        "P5" => (1, false),
+        "P6" => (3, false),
+        "P7" => return read_pam(r),
+        _ => return Err(bad(format!("unsupported magic `{}`, expected P2, P3, P5, P6, or P7", magic))),
+    };
+
+    let width = r.number()?;
+    let height = r.number()?;
+    let maxval = r.number()?;
+    image_from_samples(r, width, height, maxval, channels, plain)
+}
+
+/// Reads the header of a PAM image, whose lines are `KEYWORD value` pairs ending with
+/// `ENDHDR`, and then its samples
+fn read_pam(mut r: PnmReader) -> Result<Image, RpegError> {
+    let (mut width, mut height, mut depth, mut maxval, mut tupltype) = (None, None, None, None, None);
+    loop {
+        match r.token()?.as_str() {
+            "ENDHDR" => break,
+            "WIDTH" => width = Some(r.number()?),
+            "HEIGHT" => height = Some(r.number()?),
+            "DEPTH" => depth = Some(r.number()?),
+            "MAXVAL" => maxval = Some(r.number()?),
+            "TUPLTYPE" => tupltype = Some(r.token()?),
+            keyword => return Err(bad(format!("unknown PAM header keyword `{}`", keyword))),
+        }
+    }
+
+    let missing = |name: &str| bad(format!("PAM header has no {}", name));
+    let (width, height) = (width.ok_or_else(|| missing("WIDTH"))?, height.ok_or_else(|| missing("HEIGHT"))?);
+    let depth = depth.ok_or_else(|| missing("DEPTH"))?;
+    let maxval = maxval.ok_or_else(|| missing("MAXVAL"))?;
+    let channels = match (tupltype.as_deref(), depth) {
+        (Some("GRAYSCALE") | None, 1) => 1,
+        (Some("RGB") | None, 3) => 3,
+        (Some("RGB_ALPHA") | None, 4) => 4,
+        (tupltype, depth) => {
+            return Err(bad(format!("unsupported PAM tuple type {} with depth {}", tupltype.unwrap_or("(none)"), depth)))
+        }
+    };
+    image_from_samples(r, width, height, maxval, channels, false)
+}
+
+/// Reads the samples that follow a header and groups them into pixels
+fn image_from_samples(
+    mut r: PnmReader,
+    width: u32,
+    height: u32,
+    maxval: u32,
+    channels: usize,
+    plain: bool,
+) -> Result<Image, RpegError> {
+    if maxval == 0 || maxval > 65535 {
+        return Err(bad(format!("maxval {} is outside 1..=65535", maxval)));
+    }
+    let count = (width as usize)
+        .checked_mul(height as usize)
+        .and_then(|n| n.checked_mul(channels))
+        .ok_or_else(|| bad("image is too large".to_string()))?;
+
+    let samples = if plain { r.plain_samples(count)? } else { r.raw_samples(count, maxval)? };
+    if let Some(&sample) = samples.iter().find(|&&s| s as u32 > maxval) {
+        return Err(bad(format!("sample {} is larger than maxval {}", sample, maxval)));
+    }
+
+    let denominator = maxval as u16;
+    let rgb = |p: &[u16]| Rgb { red: p[0], green: p[1], blue: p[2] };
+    Ok(match channels {
+        1 => Image::Gray(GrayImage {
+            width,
+            height,
+            denominator,
+            pixels: samples.into_iter().map(|value| Gray { value }).collect(),
+        }),
+        3 => Image::Rgb(RgbImage { width, height, denominator, pixels: samples.chunks_exact(3).map(rgb).collect() }),
+        _ => Image::Rgba(RgbaImage {
+            color: RgbImage { width, height, denominator, pixels: samples.chunks_exact(4).map(rgb).collect() },
+            alpha: samples.chunks_exact(4).map(|p| p[3]).collect(),
+        }),
+    })
+}
+
+/// Reads a PPM image, as `read_pnm` does, expanding a PGM image to colour
+/// # Returns:
+/// * `Result<RgbImage, RpegError>`: the image, whose denominator is the file's maxval
+///
+/// # Arguments:
+/// * `bytes`: the contents of a PPM or PGM file
+pub fn read_ppm(bytes: &[u8]) -> Result<RgbImage, RpegError> {
+    read_pnm(bytes).map(Image::into_rgb)
+}
+
+/// Writes a raw PPM (P6), PGM (P5), or, for images with alpha, PAM (P7) image using the
+/// image's denominator as the maxval, with two bytes per sample when it is above 255
+///
+/// # Arguments:
+/// * `out`: where to write the image
+/// * `img`: the image to write
+pub fn write_pnm<W: Write>(out: &mut W, img: &Image) -> io::Result<()> {
+    let (denominator, samples): (_, Vec<u16>) = match img {
+        Image::Rgb(img) => {
+            write!(out, "P6\n{} {}\n{}\n", img.width, img.height, img.denominator)?;
+            let samples = img.pixels.iter().flat_map(|p| [p.red, p.green, p.blue]).collect();
+            (img.denominator, samples)
+        }
+        Image::Gray(img) => {
+            write!(out, "P5\n{} {}\n{}\n", img.width, img.height, img.denominator)?;
+            (img.denominator, img.pixels.iter().map(|p| p.value).collect())
+        }
+        Image::Rgba(img) => {
+            let color = &img.color;
+            write!(
+                out,
+                "P7\nWIDTH {}\nHEIGHT {}\nDEPTH 4\nMAXVAL {}\nTUPLTYPE RGB_ALPHA\nENDHDR\n",
+                color.width, color.height, color.denominator
+            )?;
+            let samples = color
+                .pixels
+                .iter()
+                .zip(&img.alpha)
+                .flat_map(|(p, &a)| [p.red, p.green, p.blue, a])
+                .collect();
+            (color.denominator, samples)
+        }
+    };
+
+    let wide = denominator > 255;
+    let mut raster = Vec::with_capacity(samples.len() * if wide { 2 } else { 1 });
+    for sample in samples {
+        let sample = sample.min(denominator);
+        if wide {
+            raster.extend(sample.to_be_bytes());
+        } else {
+            raster.push(sample as u8);
+        }
+    }
+    out.write_all(&raster)?;
+    out.flush()
+}
+
+/// The header of a raw PPM or PGM image, read by `read_raw_header`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RawHeader {
+    pub width: u32,
+    pub height: u32,
+    pub maxval: u16,
+    /// 3 for a PPM image, 1 for a PGM image
+    pub channels: usize,
+}
+
+impl RawHeader {
+    /// Number of bytes in one row of samples
+    pub fn row_bytes(&self) -> usize {
+        let width = if self.maxval > 255 { 2 } else { 1 };
+        self.width as usize * self.channels * width
+    }
+}
+
+/// Reads the header of a raw PPM (P6) or PGM (P5) image from a stream, leaving the stream
+/// at the first sample so the rows can be read one at a time with `read_raw_row`
+/// # Returns:
+/// * `Result<RawHeader, RpegError>`: the dimensions, maxval, and channels of the image
+///
+/// # Arguments:
+/// * `input`: the stream
+pub fn read_raw_header<R: BufRead>(input: &mut R) -> Result<RawHeader, RpegError> {
+    let mut next = || -> Result<Option<u8>, RpegError> {
+        let byte = input.fill_buf()?.first().copied();
+        if byte.is_some() {
+            input.consume(1);
+        }
+        Ok(byte)
+    };
+    // reads a token along with the byte that ends it, so that after the maxval the
+    // single whitespace byte before the raster is gone too
+    let mut token = || -> Result<String, RpegError> {
+        let mut token = String::new();
+        loop {
+            match next()? {
+                Some(b'#') => {
+                    while !matches!(next()?, Some(b'\n') | None) {}
+                    if !token.is_empty() {
+                        return Ok(token);
+                    }
+                }
+                Some(b) if b.is_ascii_whitespace() => {
+                    if !token.is_empty() {
+                        return Ok(token);
+                    }
+                }
+                Some(b) => token.push(b as char),
+                None if token.is_empty() => return Err(bad("header ended unexpectedly".to_string())),
+                None => return Ok(token),
+            }
+        }
+    };
+
+    let channels = match token()?.as_str() {
+        "P5" => 1,
+        "P6" => 3,
+        magic => return Err(bad(format!("unsupported magic `{}` for streaming, expected P5 or P6", magic))),
+    };
+    let mut number = || -> Result<u32, RpegError> {
+        let token = token()?;
+        token.parse().map_err(|_| bad(format!("expected a number, found `{}`", token)))
+    };
+    let (width, height, maxval) = (number()?, number()?, number()?);
+    if maxval == 0 || maxval > 65535 {
+        return Err(bad(format!("maxval {} is outside 1..=65535", maxval)));
+    }
+    Ok(RawHeader { width, height, maxval: maxval as u16, channels })
+}
+
+/// Reads the next row of samples of a raw image whose header `read_raw_header` read
+/// # Returns:
+/// * `Result<Vec<u16>, RpegError>`: the samples of the row, `header.channels` per pixel
+///
+/// # Arguments:
+/// * `input`: the stream
+/// * `header`: the header of the image
+pub fn read_raw_row<R: Read>(input: &mut R, header: &RawHeader) -> Result<Vec<u16>, RpegError> {
+    let mut raster = vec![0; header.row_bytes()];
+    input.read_exact(&mut raster).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => bad("samples ended unexpectedly".to_string()),
+        _ => RpegError::Io(e),
+    })?;
+    let samples: Vec<u16> = if header.maxval > 255 {
+        raster.chunks_exact(2).map(|s| u16::from_be_bytes([s[0], s[1]])).collect()
+    } else {
+        raster.into_iter().map(u16::from).collect()
+    };
+    if let Some(&sample) = samples.iter().find(|&&s| s > header.maxval) {
+        return Err(bad(format!("sample {} is larger than maxval {}", sample, header.maxval)));
+    }
+    Ok(samples)
+}
+
+/// Writes the header of a raw PPM or PGM image, as `write_pnm` would
+pub fn write_raw_header<W: Write>(out: &mut W, header: &RawHeader) -> io::Result<()> {
+    let magic = if header.channels == 1 { "P5" } else { "P6" };
+    write!(out, "{}\n{} {}\n{}\n", magic, header.width, header.height, header.maxval)
+}
+
+/// Writes one row of samples of a raw image, clamping them to the maxval
+pub fn write_raw_row<W: Write>(out: &mut W, header: &RawHeader, samples: &[u16]) -> io::Result<()> {
+    let raster: Vec<u8> = if header.maxval > 255 {
+        samples.iter().flat_map(|s| s.min(&header.maxval).to_be_bytes()).collect()
+    } else {
+        samples.iter().map(|&s| s.min(header.maxval) as u8).collect()
+    };
+    out.write_all(&raster)
+}
+
+fn bad(msg: String) -> RpegError {
+    RpegError::BadImage(msg)
+}
+
+/// Reads the whitespace-separated header of a netpbm file, skipping `#` comments
+struct PnmReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl PnmReader<'_> {
+    fn skip_space(&mut self) {
+        while let Some(&b) = self.bytes.get(self.pos) {
+            if b == b'#' {
+                while self.bytes.get(self.pos).is_some_and(|&b| b != b'\n') {
+                    self.pos += 1;
+                }
+            } else if b.is_ascii_whitespace() {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn token(&mut self) -> Result<String, RpegError> {
+        self.skip_space();
+        let start = self.pos;
+        while self.bytes.get(self.pos).is_some_and(|b| !b.is_ascii_whitespace() && *b != b'#') {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Err(bad("header ended unexpectedly".to_string()));
+        }
+        Ok(String::from_utf8_lossy(&self.bytes[start..self.pos]).to_string())
+    }
+
+    fn number(&mut self) -> Result<u32, RpegError> {
+        let token = self.token()?;
+        token.parse().map_err(|_| bad(format!("expected a number, found `{}`", token)))
+    }
+
+    fn plain_samples(&mut self, count: usize) -> Result<Vec<u16>, RpegError> {
+        (0..count)
+            .map(|_| {
+                let n = self.number()?;
+                u16::try_from(n).map_err(|_| bad(format!("sample {} is too large", n)))
+            })
+            .collect()
+    }
+
+    fn raw_samples(&mut self, count: usize, maxval: u32) -> Result<Vec<u16>, RpegError> {
+        // exactly one whitespace byte separates the header from the raster
+        self.pos += 1;
+        let raster = self.bytes.get(self.pos..).unwrap_or(&[]);
+        let width = if maxval > 255 { 2 } else { 1 };
+        let expected = count.checked_mul(width).ok_or_else(|| bad("image is too large".to_string()))?;
+        if raster.len() < expected {
+            return Err(bad(format!("expected {} bytes of samples, found {}", expected, raster.len())));
+        }
+
+        let samples = raster[..expected]
+            .chunks_exact(width)
+            .map(|s| s.iter().fold(0_u16, |v, &b| v << 8 | b as u16))
+            .collect();
+        self.pos += expected;
+        Ok(samples)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sixteen_bit_round_trip() {
+        let pixels = vec![
+            Rgb { red: 0, green: 1000, blue: 65535 },
+            Rgb { red: 300, green: 40000, blue: 7 },
+        ];
+        let img = RgbImage { width: 2, height: 1, denominator: 65535, pixels };
+        let mut bytes = vec![];
+        write_pnm(&mut bytes, &Image::Rgb(img)).unwrap();
+        assert_eq!(bytes.len(), "P6\n2 1\n65535\n".len() + 12);
+
+        let read = read_ppm(&bytes).unwrap();
+        assert_eq!((read.width, read.height, read.denominator), (2, 1, 65535));
+        assert_eq!((read.pixels[1].red, read.pixels[1].green), (300, 40000));
+    }
+
+    #[test]
+    fn plain_ppm_with_comments() {
+        let img = read_ppm(b"P3\n# a comment\n2 1 # another\n15\n0 1 2\n15 14 13\n").unwrap();
+        assert_eq!(img.denominator, 15);
+        assert_eq!((img.pixels[1].red, img.pixels[1].blue), (15, 13));
+    }
+
+    #[test]
+    fn malformed_images() {
+        for bytes in [&b"P4\n1 1\n\0"[..], b"P6\n1 1\n0\n\0\0\0", b"P6\n2 1\n255\n\0\0\0", b"P3\n1 1\n9\n1 2 10\n"] {
+            assert!(matches!(read_ppm(bytes), Err(RpegError::BadImage(_))));
+        }
+        // as many samples as a usize holds, but twice as many bytes
+        assert!(matches!(read_pnm(b"P5\n4294967295 2147483649\n65535\n"), Err(RpegError::BadImage(_))));
+    }
+
+    #[test]
+    fn raw_rows_round_trip() {
+        for maxval in [255, 1000] {
+            let header = RawHeader { width: 2, height: 2, maxval, channels: 3 };
+            let rows = [vec![0, 1, 2, 3, 4, 5], vec![maxval, 7, 8, 9, 10, 11]];
+            let mut bytes = vec![];
+            write_raw_header(&mut bytes, &header).unwrap();
+            for row in &rows {
+                write_raw_row(&mut bytes, &header, row).unwrap();
+            }
+            assert!(matches!(read_pnm(&bytes), Ok(Image::Rgb(_))));
+
+            let mut input = &bytes[..];
+            assert_eq!(read_raw_header(&mut input).unwrap(), header);
+            for row in &rows {
+                assert_eq!(&read_raw_row(&mut input, &header).unwrap(), row);
+            }
+            assert!(read_raw_row(&mut input, &header).is_err());
+        }
+
+        let mut input = &b"P5 # comment\n3 1\n7\n\x01\x02\x07"[..];
+        let header = read_raw_header(&mut input).unwrap();
+        assert_eq!(header, RawHeader { width: 3, height: 1, maxval: 7, channels: 1 });
+        assert_eq!(read_raw_row(&mut input, &header).unwrap(), vec![1, 2, 7]);
+        assert!(read_raw_header(&mut &b"P3\n1 1\n255\n0 0 0\n"[..]).is_err());
+    }
+
+    #[test]
+    fn gray_round_trip() {
+        let Ok(Image::Gray(img)) = read_pnm(b"P2\n3 1\n300\n0 150 300\n") else {
+            panic!("expected a grayscale image");
+        };
+        assert_eq!(img.denominator, 300);
+
+        let mut bytes = vec![];
+        write_pnm(&mut bytes, &Image::Gray(img)).unwrap();
+        assert!(bytes.starts_with(b"P5\n3 1\n300\n\0\0\0\x96\x01\x2c"));
+        let Ok(Image::Gray(img)) = read_pnm(&bytes) else {
+            panic!("expected a grayscale image");
+        };
+        assert_eq!(img.pixels[2].value, 300);
+        assert_eq!(read_ppm(&bytes).unwrap().pixels[1].green, 150);
+    }
+
+    #[test]
+    fn pam_round_trip() {
+        let header = b"P7\nWIDTH 2\nHEIGHT 1\nDEPTH 4\nMAXVAL 255\nTUPLTYPE RGB_ALPHA\nENDHDR\n";
+        let bytes = [&header[..], &[1, 2, 3, 0, 4, 5, 6, 255]].concat();
+        let Ok(Image::Rgba(img)) = read_pnm(&bytes) else {
+            panic!("expected an image with alpha");
+        };
+        assert_eq!(img.alpha, vec![0, 255]);
+        assert_eq!(img.color.pixels[1].blue, 6);
+
+        let mut written = vec![];
+        write_pnm(&mut written, &Image::Rgba(img)).unwrap();
+        assert_eq!(written, bytes);
+
+        let rgb = b"P7\nWIDTH 1\nHEIGHT 1\nDEPTH 3\nMAXVAL 255\nTUPLTYPE RGB\nENDHDR\n\x01\x02\x03";
+        assert!(matches!(read_pnm(rgb), Ok(Image::Rgb(_))));
+        let gray_alpha = b"P7\nWIDTH 1\nHEIGHT 1\nDEPTH 2\nMAXVAL 255\nTUPLTYPE GRAYSCALE_ALPHA\nENDHDR\n\0\0";
+        assert!(matches!(read_pnm(gray_alpha), Err(RpegError::BadImage(_))));
+    }
+}
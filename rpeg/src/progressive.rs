@@ -0,0 +1,261 @@
+use std::io::Read;
+
+use array2::Array2;
+
+use crate::bitio::{BitReader, BitWriter};
+use crate::codec::{image_from_component_video, DecodeStats};
+use crate::entropy::{self, Coding};
+use crate::error::RpegError;
+use crate::format::{bytes_to_words, read_header_from, read_rpeg, split_chroma, words_to_bytes, Header};
+use crate::layout::Field;
+use crate::pnm::Image;
+use crate::to_component_video::YPbPr;
+use crate::transform::Transform;
+use crate::unpack_bits;
+
+/// Writes the raw bits of the given fields of every word, one word after another
+fn write_fields(words: &[u64], fields: [Field; 3]) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+    for &word in words {
+        for field in fields.iter().filter(|f| f.width > 0) {
+            writer.put(field.extract(word), field.width);
+        }
+    }
+    writer.finish()
+}
+
+/// Reverses `write_fields`, filling in the given fields of each word
+/// # Returns:
+/// * `Option<()>`: `None` if `bytes` ends before every word is filled in
+fn read_fields(bytes: &[u8], fields: [Field; 3], words: &mut [u64]) -> Option<()> {
+    let mut reader = BitReader::new(bytes);
+    for word in words.iter_mut() {
+        for field in fields.iter().filter(|f| f.width > 0) {
+            *word |= field.insert(reader.get(field.width)?);
+        }
+    }
+    Some(())
+}
+
+/// Rewrites fixed-size 2x2 words in progressive order. The payload becomes a coarse
+/// section holding the a, pb, and pr bits of every block, then the extra chroma samples,
+/// then a detail section holding the b, c, and d bits of every block. Each section is
+/// bit-packed with `BitWriter` and padded to a whole byte. No bits are added or dropped,
+/// so the image decodes exactly as it would from the words.
+/// # Returns:
+/// * `Result<Vec<u8>, RpegError>`: the progressive payload
+///
+/// # Arguments:
+/// * `header`: the header of the image
+/// * `blocks`: the packed words followed by any extra chroma samples, as `encode_2x2` gives
+pub(crate) fn to_progressive(header: &Header, blocks: &[u8]) -> Result<Vec<u8>, RpegError> {
+    let (packed, extra) = split_chroma(header, blocks)?;
+    let words = bytes_to_words(&header.layout, packed);
+    let mut payload = write_fields(&words, header.layout.coarse_fields());
+    payload.extend(extra);
+    payload.extend(write_fields(&words, header.layout.detail_fields()));
+    Ok(payload)
+}
+
+/// Reverses `to_progressive`
+/// # Returns:
+/// * `Result<Vec<u8>, RpegError>`: the packed words followed by any extra chroma samples
+///
+/// # Arguments:
+/// * `header`: the header of the image
+/// * `blocks`: the progressive payload
+pub(crate) fn from_progressive(header: &Header, blocks: &[u8]) -> Result<Vec<u8>, RpegError> {
+    let (coarse_len, detail_len) = header.progressive_lens();
+    let extra_len = header.extra_chroma_len();
+    // the lengths come from the header, so the payload must hold them all before the
+    // words are allocated
+    let expected = coarse_len.saturating_add(extra_len).saturating_add(detail_len);
+    if blocks.len() < expected {
+        return Err(RpegError::Truncated { expected, actual: blocks.len() });
+    }
+
+    let (coarse, rest) = blocks.split_at(coarse_len);
+    let (extra, detail) = rest.split_at(extra_len);
+    let mut words = vec![0; header.blocks_2x2()];
+    read_fields(coarse, header.layout.coarse_fields(), &mut words)
+        .and_then(|_| read_fields(detail, header.layout.detail_fields(), &mut words))
+        .ok_or(RpegError::Truncated { expected, actual: blocks.len() })?;
+    let mut payload = words_to_bytes(&header.layout, &words);
+    payload.extend(extra);
+    Ok(payload)
+}
+
+/// Checks that a file holds 2x2 blocks a preview can be made from
+fn check_previewable(header: &Header) -> Result<(), RpegError> {
+    let unsupported = |what: &str| Err(RpegError::Unsupported(format!("previews {}", what)));
+    if header.transform != Transform::Haar2x2 {
+        return unsupported("need the 2x2 transform");
+    }
+    if header.tile_size.is_some() {
+        return unsupported("cannot be made of tiled images; decode a region instead");
+    }
+    if header.has_alpha() {
+        return unsupported("cannot show alpha");
+    }
+    Ok(())
+}
+
+/// Decodes a half-scale preview of an image compressed with the 2x2 transform. Each block
+/// gives one pixel, whose luminance is the block's average a and whose chroma is its first
+/// chroma sample, so an image of odd width or height has a preview rounded up to whole
+/// blocks.
+///
+/// Of a progressive file, only the header and the coarse section are read; the rest of
+/// `input` is left unread, so the checksum, which follows the payload, is not verified.
+/// Any other file is read to the end and checked as `decompress_image` would, since the
+/// a of its last block comes after the detail of all the others.
+/// # Returns:
+/// * `Result<(Image, DecodeStats), RpegError>`: the preview, grayscale for a grayscale
+///   image and in colour otherwise, and how many of its samples were clamped
+///
+/// # Arguments:
+/// * `input`: the rpeg file
+pub fn decompress_preview<R: Read>(mut input: R) -> Result<(Image, DecodeStats), RpegError> {
+    let (header, mut bytes) = read_header_from(&mut input)?;
+    check_previewable(&header)?;
+    let layout = &header.layout;
+
+    let blocks: Vec<_> = if header.progressive {
+        let (coarse_len, _) = header.progressive_lens();
+        let mut coarse = vec![];
+        input.take(coarse_len as u64).read_to_end(&mut coarse)?;
+        // as in `from_progressive`, the section is read in full before the words are allocated
+        if coarse.len() != coarse_len {
+            return Err(RpegError::Truncated { expected: coarse_len, actual: coarse.len() });
+        }
+        let mut words = vec![0; header.blocks_2x2()];
+        read_fields(&coarse, layout.coarse_fields(), &mut words)
+            .ok_or(RpegError::Truncated { expected: coarse_len, actual: coarse.len() })?;
+        words.into_iter().map(|word| unpack_bits(layout, word)).collect()
+    } else {
+        input.read_to_end(&mut bytes)?;
+        let (_, payload) = read_rpeg(&bytes)?;
+        let (packed, _) = split_chroma(&header, payload)?;
+        match header.coding {
+            Coding::Fixed => bytes_to_words(layout, packed).into_iter().map(|word| unpack_bits(layout, word)).collect(),
+            Coding::Huffman => entropy::decode_blocks(layout, packed, header.blocks_2x2())?,
+        }
+    };
+
+    let (width, height) = (header.width.div_ceil(2), header.height.div_ceil(2));
+    let pixels = blocks
+        .into_iter()
+        .map(|block| {
+            let (y, _, _, _, pb, pr) = layout.dequantize(block);
+            YPbPr::new(y, pb, pr)
+        })
+        .collect();
+    let arr = Array2::from_row_major(width as usize, height as usize, pixels)?;
+    Ok(image_from_component_video(&Header { width, height, ..header }, &arr, 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{compress_gray_image, compress_image, decompress_image, Options};
+    use crate::format::header_bytes;
+    use crate::subsampling::Subsampling;
+    use crate::fixtures::gradient;
+    use crate::format::write_rpeg;
+    use csc411_image::{Gray, GrayImage};
+
+    #[test]
+    fn progressive_files_decode_like_the_words() {
+        let img = gradient(13, 7);
+        for subsampling in [Subsampling::S444, Subsampling::S422, Subsampling::S420] {
+            let options = Options { subsampling: Some(subsampling), quality: Some(70), ..Default::default() };
+            let words = compress_image(&img, &options).unwrap();
+            let progressive = compress_image(&img, &Options { progressive: true, ..options }).unwrap();
+
+            let (header, payload) = read_rpeg(&progressive).unwrap();
+            assert!(header.progressive);
+            let (_, packed) = read_rpeg(&words).unwrap();
+            assert!(from_progressive(&header, payload).unwrap() == packed);
+
+            let (Image::Rgb(a), _) = decompress_image(&words).unwrap() else { unreachable!() };
+            let (Image::Rgb(b), _) = decompress_image(&progressive).unwrap() else { unreachable!() };
+            assert!(a.pixels.iter().zip(&b.pixels).all(|(a, b)| (a.red, a.green, a.blue) == (b.red, b.green, b.blue)));
+        }
+    }
+
+    #[test]
+    fn previews_need_only_the_coarse_section() {
+        let img = gradient(13, 7);
+        let words = compress_image(&img, &Options::default()).unwrap();
+        let progressive = compress_image(&img, &Options { progressive: true, ..Default::default() }).unwrap();
+        let (header, _) = read_rpeg(&progressive).unwrap();
+        let coarse_end = header_bytes(&header).len() + header.progressive_lens().0;
+
+        let (Image::Rgb(full), _) = decompress_preview(&words[..]).unwrap() else { unreachable!() };
+        let (Image::Rgb(early), _) = decompress_preview(&progressive[..coarse_end]).unwrap() else { unreachable!() };
+        assert_eq!((early.width, early.height), (7, 4));
+        assert!(full.pixels.iter().zip(&early.pixels).all(|(a, b)| (a.red, a.green, a.blue) == (b.red, b.green, b.blue)));
+
+        // each preview pixel is close to the average of the block it stands for
+        let (x, y) = (3, 2);
+        let block = [(6, 4), (7, 4), (6, 5), (7, 5)].map(|(px, py)| img.pixels[py * 13 + px].green as i32);
+        let average = block.iter().sum::<i32>() / 4;
+        assert!((early.pixels[y * 7 + x].green as i32 - average).abs() < 16);
+
+        assert!(matches!(
+            decompress_preview(&progressive[..coarse_end - 1]),
+            Err(RpegError::Truncated { .. })
+        ));
+        assert!(matches!(decompress_preview(&words[..words.len() - 1]), Err(RpegError::Corrupt { .. })));
+    }
+
+    #[test]
+    fn gray_previews_stay_gray() {
+        let pixels = (0..6 * 6).map(|i| Gray { value: (i * 7) as u16 }).collect();
+        let img = GrayImage { width: 6, height: 6, denominator: 255, pixels };
+        let bytes = compress_gray_image(&img, &Options { progressive: true, ..Default::default() }).unwrap();
+        let (preview, stats) = decompress_preview(&bytes[..]).unwrap();
+        assert!(matches!(preview, Image::Gray(GrayImage { width: 3, height: 3, .. })));
+        assert_eq!(stats.samples, 9);
+    }
+
+    #[test]
+    fn unsupported_combinations_are_refused() {
+        let img = gradient(8, 8);
+        let progressive = Options { progressive: true, ..Default::default() };
+        for options in [
+            Options { coding: Coding::Huffman, ..progressive },
+            Options { transform: Transform::Dct8x8, ..progressive },
+            Options { transform: Transform::Lossless, ..progressive },
+            Options { tile_size: Some(4), ..progressive },
+        ] {
+            assert!(matches!(compress_image(&img, &options), Err(RpegError::Unsupported(_))), "{:?}", options);
+        }
+
+        let dct = compress_image(&img, &Options { transform: Transform::Dct8x8, ..Default::default() }).unwrap();
+        assert!(matches!(decompress_preview(&dct[..]), Err(RpegError::Unsupported(_))));
+    }
+
+    #[test]
+    fn forged_sizes_are_refused_before_allocating() {
+        let bytes = compress_image(&gradient(8, 8), &Options { progressive: true, ..Default::default() }).unwrap();
+        let (header, payload) = read_rpeg(&bytes).unwrap();
+        let forge = |width: u32, height: u32| {
+            let mut forged = vec![];
+            write_rpeg(&mut forged, &Header { width, height, ..header }, payload).unwrap();
+            forged
+        };
+
+        // too many bits to count
+        let uncountable = forge(u32::MAX, u32::MAX);
+        assert!(matches!(decompress_preview(&uncountable[..]), Err(RpegError::BadHeader(_))));
+        assert!(matches!(decompress_image(&uncountable), Err(RpegError::BadHeader(_))));
+
+        // countable, but far more than the file holds
+        let huge = forge(1 << 20, 1 << 20);
+        assert!(matches!(decompress_preview(&huge[..]), Err(RpegError::Truncated { .. })));
+        assert!(matches!(decompress_image(&huge), Err(RpegError::Truncated { .. })));
+        let huge_header = Header { width: 1 << 20, height: 1 << 20, ..header };
+        assert!(matches!(from_progressive(&huge_header, payload), Err(RpegError::Truncated { .. })));
+    }
+}
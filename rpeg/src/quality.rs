@@ -0,0 +1,152 @@
+use csc411_image::RgbImage;
+
+use crate::dct::DctTables;
+use crate::layout::WordLayout;
+
+/// Lowest accepted quality
+pub const MIN_QUALITY: u8 = 1;
+/// Highest accepted quality
+pub const MAX_QUALITY: u8 = 100;
+
+/// Derives the word layout used by the 2x2 transform from a quality in 1..=100.
+///
+/// Higher qualities give a and b/c/d more bits, keep more chroma precision, and raise the
+/// cosine force so fewer coefficients are clipped. Quality 50 is the classic 32-bit
+/// 9/5/5/5/4/4 layout with a cosine force of 0.3; once the fields no longer fit in 32
+/// bits (above quality 54) the layout switches to 64-bit words. Payload size therefore never
+/// decreases as quality increases, and the RMSE of the decompressed image does not grow.
+///
+/// # Arguments:
+/// * `quality`: a quality in 1..=100; values outside that range are clamped
+pub fn layout_for_quality(quality: u8) -> WordLayout {
+    let q = quality.clamp(MIN_QUALITY, MAX_QUALITY) as u32;
+
+    let a = (4 + (q + 5) / 10).clamp(5, 14);
+    let bcd = ((q + 5) / 10).clamp(2, 10);
+    let chroma = match q {
+        40.. => 4,
+        15..=39 => 3,
+        _ => 2,
+    };
+    let cosine_force = (150 + 3 * q) as f32 / 1000.0;
+
+    let total = a + 3 * bcd + 2 * chroma;
+    let word_bits = if total <= 32 { 32 } else { 64 };
+
+    WordLayout::from_widths(word_bits, [a, bcd, bcd, bcd, chroma, chroma], cosine_force).unwrap()
+}
+
+/// Derives the word layout used for grayscale images from a quality in 1..=100. Without
+/// chroma fields, a and b/c/d each get two more bits than `layout_for_quality` gives them,
+/// so quality 50 is `WordLayout::gray()`.
+///
+/// # Arguments:
+/// * `quality`: a quality in 1..=100; values outside that range are clamped
+pub fn gray_layout_for_quality(quality: u8) -> WordLayout {
+    let q = quality.clamp(MIN_QUALITY, MAX_QUALITY) as u32;
+
+    let a = (6 + (q + 5) / 10).clamp(7, 16);
+    let bcd = (2 + (q + 5) / 10).clamp(3, 12);
+    let cosine_force = (150 + 3 * q) as f32 / 1000.0;
+
+    let total = a + 3 * bcd;
+    let word_bits = if total <= 32 { 32 } else { 64 };
+
+    WordLayout::from_widths(word_bits, [a, bcd, bcd, bcd, 0, 0], cosine_force).unwrap()
+}
+
+/// Derives the DCT quantization tables from a quality in 1..=100, scaling the JPEG example
+/// tables the same way libjpeg does: quality 50 uses them as they are, lower qualities
+/// multiply every step by 50 / quality, and higher qualities by (200 - 2 * quality) / 100.
+/// Smaller steps mean more bits per coefficient, so size grows with quality.
+///
+/// # Arguments:
+/// * `quality`: a quality in 1..=100; values outside that range are clamped
+pub fn tables_for_quality(quality: u8) -> DctTables {
+    let q = quality.clamp(MIN_QUALITY, MAX_QUALITY) as f32;
+    let scale = if q < 50.0 { 50.0 / q } else { (200.0 - 2.0 * q) / 100.0 };
+    DctTables::scaled(scale.max(0.05))
+}
+
+/// Computes the root mean square difference between two images, with every sample
+/// scaled to [0, 1] by its image's denominator. Only the region the two images have in
+/// common is compared.
+/// # Returns:
+/// * `f64`: the RMSE, 0 for identical images
+///
+/// # Arguments:
+/// * `a`, `b`: the images to compare
+pub fn rmse(a: &RgbImage, b: &RgbImage) -> f64 {
+    let width = a.width.min(b.width) as usize;
+    let height = a.height.min(b.height) as usize;
+    if width == 0 || height == 0 {
+        return 0.0;
+    }
+
+    let da = a.denominator as f64;
+    let db = b.denominator as f64;
+    let mut e = 0.0;
+    for y in 0..height {
+        for x in 0..width {
+            let p = &a.pixels[y * a.width as usize + x];
+            let q = &b.pixels[y * b.width as usize + x];
+            e += (p.red as f64 / da - q.red as f64 / db).powi(2)
+                + (p.green as f64 / da - q.green as f64 / db).powi(2)
+                + (p.blue as f64 / da - q.blue as f64 / db).powi(2);
+        }
+    }
+
+    (e / (3 * width * height) as f64).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{compress_image, decompress_bytes, Options};
+    use crate::format::read_rpeg;
+    use crate::transform::Transform;
+    use crate::fixtures::scene;
+
+    #[test]
+    fn quality_50_is_the_classic_layout() {
+        assert_eq!(layout_for_quality(50), WordLayout::default());
+        assert_eq!(gray_layout_for_quality(50), WordLayout::gray());
+        assert_eq!(tables_for_quality(50), DctTables::default());
+    }
+
+    #[test]
+    fn every_quality_is_valid() {
+        for q in MIN_QUALITY..=MAX_QUALITY {
+            assert!(layout_for_quality(q).validate().is_ok());
+            assert!(gray_layout_for_quality(q).validate().is_ok());
+            assert!(tables_for_quality(q).validate().is_ok());
+        }
+    }
+
+    #[test]
+    fn size_and_error_are_monotonic() {
+        let img = scene(48, 40);
+        for transform in [Transform::Haar2x2, Transform::Dct8x8] {
+            let mut last: Option<(usize, f64)> = None;
+            for quality in [1, 10, 25, 40, 50, 60, 75, 90, 100] {
+                let options = Options { quality: Some(quality), transform, ..Default::default() };
+                let bytes = compress_image(&img, &options).unwrap();
+                let error = rmse(&img, &decompress_bytes(&bytes).unwrap());
+                // the header text varies by a few bytes with the layout, so compare payloads
+                let size = read_rpeg(&bytes).unwrap().1.len();
+
+                if let Some((last_size, last_error)) = last {
+                    assert!(size >= last_size, "{:?} size at quality {}", transform, quality);
+                    assert!(error <= last_error + 1e-3, "{:?} rmse at quality {}", transform, quality);
+                }
+                last = Some((size, error));
+            }
+        }
+    }
+
+    #[test]
+    fn rmse_of_identical_images_is_zero() {
+        let img = scene(8, 8);
+        assert_eq!(rmse(&img, &img), 0.0);
+    }
+}
@@ -1,3 +1,6 @@
+use std::fmt;
+use std::str::FromStr;
+
 
 
 /// Returns an i32.
@@ -6,7 +9,8 @@
 /// * x: the value wanted to quantize
 /// * bits: desired amount of bits and cosine force, which is 0.3 in this case 
 pub fn encodes(x: f32, bits: u32, cosine_force: f32) -> i32 {
-    (scale_sat(x, cosine_force) * smaxs(bits) as f32 + 0.5).floor() as i32
+    // in f64, since an f32 cannot tell the top levels of a field wider than 24 bits apart
+    (scale_sat(x, cosine_force) as f64 * smaxs(bits) as f64 + 0.5).floor() as i32
 }
 
 /// Returns an u32.
@@ -15,7 +19,25 @@ pub fn encodes(x: f32, bits: u32, cosine_force: f32) -> i32 {
 /// * x: the value wanted to quantize
 /// * bits: desired amount of bits and cosine force, which is 0.3 in this case 
 pub fn encodeu(x: f32, bits: u32, cosine_force: f32) -> u32 {
-    (scale_sat(x, cosine_force) * smaxu(bits) as f32 + 0.5).floor() as u32
+    (scale_sat(x, cosine_force) as f64 * umax(bits) as f64 + 0.5).floor() as u32
+}
+
+/// Returns the f32 that `encodes` quantized to `q`.
+/// 
+/// # Arguments:
+/// * q: the quantized value
+/// * bits: number of bits and cosine force that were used to quantize it
+pub fn decodes(q: i32, bits: u32, cosine_force: f32) -> f32 {
+    q as f32 / smaxs(bits) as f32 * cosine_force
+}
+
+/// Returns the f32 that `encodeu` quantized to `q`.
+/// 
+/// # Arguments:
+/// * q: the quantized value
+/// * bits: number of bits and cosine force that were used to quantize it
+pub fn decodeu(q: u32, bits: u32, cosine_force: f32) -> f32 {
+    q as f32 / umax(bits) as f32 * cosine_force
 }
 
 
@@ -26,11 +48,11 @@ pub fn encodeu(x: f32, bits: u32, cosine_force: f32) -> u32 {
 /// * max_magnitude: The highest/lowest you can go
 pub fn scale_sat(x: f32, max_magnitude: f32) -> f32 {
     if x > max_magnitude {
-        return 1.0
+        1.0
     } else if x < -max_magnitude {
-        return -1.0
+        -1.0
     } else {
-        return x / max_magnitude
+        x / max_magnitude
     }
 }
 
@@ -39,7 +61,7 @@ pub fn scale_sat(x: f32, max_magnitude: f32) -> f32 {
 /// # Arguments:
 /// * bits: number of bits 
 pub fn smaxs(bits: u32) -> i32 {
-    ((1 << bits) / 2) -1
+    (((1_i64 << bits) / 2) -1) as i32
 }
 
 /// Returns u32 which is used to multiply and scale the inputted value.
@@ -50,6 +72,105 @@ pub fn smaxu(bits: u32) -> u32 {
     ((1 << bits) / 2) -1
 }
 
+/// Returns the largest value an unsigned field of `bits` bits holds. `encodeu` scales to
+/// it rather than to `smaxu`, so an unsigned field uses all of its levels instead of the
+/// lower half.
+///
+/// # Arguments:
+/// * bits: number of bits
+pub fn umax(bits: u32) -> u32 {
+    ((1_u64 << bits) - 1) as u32
+}
+
+/// How chroma is mapped to the codes stored in the pb and pr fields of a word
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChromaCurve {
+    /// The 16-entry nonlinear table of `csc411_arith::index_of_chroma`
+    #[default]
+    Table,
+    /// Evenly spaced levels over [-0.5, 0.5]
+    Uniform,
+    /// Levels spaced by a square-root curve, so small chroma, where banding shows, gets
+    /// the finest steps
+    Companded,
+}
+
+impl ChromaCurve {
+    /// The id of the curve in the file header
+    pub fn id(&self) -> u8 {
+        match self {
+            ChromaCurve::Table => 0,
+            ChromaCurve::Uniform => 1,
+            ChromaCurve::Companded => 2,
+        }
+    }
+
+    /// The curve with the given header id, if there is one
+    pub fn from_id(id: u8) -> Option<Self> {
+        [ChromaCurve::Table, ChromaCurve::Uniform, ChromaCurve::Companded].into_iter().find(|c| c.id() == id)
+    }
+}
+
+impl fmt::Display for ChromaCurve {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChromaCurve::Table => write!(f, "table"),
+            ChromaCurve::Uniform => write!(f, "uniform"),
+            ChromaCurve::Companded => write!(f, "companded"),
+        }
+    }
+}
+
+impl FromStr for ChromaCurve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(ChromaCurve::Table),
+            "uniform" => Ok(ChromaCurve::Uniform),
+            "companded" => Ok(ChromaCurve::Companded),
+            _ => Err(format!("unknown chroma curve `{}`, expected table, uniform, or companded", s)),
+        }
+    }
+}
+
+/// Fewest bits the native chroma curves accept
+pub const MIN_CHROMA_BITS: u32 = 4;
+/// Most bits the native chroma curves accept
+pub const MAX_CHROMA_BITS: u32 = 8;
+/// Largest magnitude of Pb and Pr
+const CHROMA_RANGE: f32 = 0.5;
+
+/// Returns the unsigned code for a chroma value on a native curve. The codes are
+/// symmetric around `smaxs(bits)`, which stands for exactly zero chroma, so grays stay gray.
+///
+/// # Arguments:
+/// * x: the chroma value, clamped to [-0.5, 0.5]
+/// * bits: the width of the field, from 4 to 8
+/// * curve: `ChromaCurve::Uniform` or `ChromaCurve::Companded`
+pub fn encode_chroma(x: f32, bits: u32, curve: ChromaCurve) -> usize {
+    let s = scale_sat(x, CHROMA_RANGE);
+    let s = match curve {
+        ChromaCurve::Companded => s.signum() * s.abs().sqrt(),
+        _ => s,
+    };
+    (encodes(s, bits, 1.0) + smaxs(bits)) as usize
+}
+
+/// Returns the chroma value that `encode_chroma` coded as `q`.
+///
+/// # Arguments:
+/// * q: the code
+/// * bits: the width of the field
+/// * curve: the curve it was coded with
+pub fn decode_chroma(q: usize, bits: u32, curve: ChromaCurve) -> f32 {
+    let s = decodes(q as i32 - smaxs(bits), bits, 1.0).clamp(-1.0, 1.0);
+    let s = match curve {
+        ChromaCurve::Companded => s * s.abs(),
+        _ => s,
+    };
+    s * CHROMA_RANGE
+}
 
 #[cfg(test)]
 mod tests {
@@ -61,4 +182,53 @@ mod tests {
         assert_eq!(encodes(0.1, 5, 0.3), 5);
         assert_eq!(encodes(-0.2, 5, 0.3), -10);
     }
+
+    #[test]
+    fn test_encodeu() {
+        assert_eq!(encodeu(1.0, 9, 1.0), 511);
+        assert_eq!(encodeu(0.5, 9, 1.0), 256);
+        assert_eq!(encodeu(-0.1, 9, 1.0), 0);
+    }
+
+    #[test]
+    fn unsigned_maxima() {
+        // smaxu keeps its original meaning, the largest value of a signed field
+        assert_eq!((smaxu(5), smaxs(5)), (15, 15));
+        assert_eq!((umax(9), umax(31)), (511, 0x7fff_ffff));
+        assert_eq!(smaxs(31), 0x3fff_ffff);
+    }
+
+    #[test]
+    fn the_widest_fields_keep_their_extremes() {
+        assert_eq!((encodeu(1.0, 31, 1.0), encodeu(0.0, 31, 1.0)), (umax(31), 0));
+        assert_eq!((encodes(0.3, 31, 0.3), encodes(-0.3, 31, 0.3)), (smaxs(31), -smaxs(31)));
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decodes(encodes(0.3, 5, 0.3), 5, 0.3), 0.3);
+        assert_eq!(decodeu(encodeu(1.0, 9, 1.0), 9, 1.0), 1.0);
+    }
+
+    #[test]
+    fn native_chroma_curves() {
+        for curve in [ChromaCurve::Uniform, ChromaCurve::Companded] {
+            for bits in MIN_CHROMA_BITS..=MAX_CHROMA_BITS {
+                assert_eq!(decode_chroma(encode_chroma(0.0, bits, curve), bits, curve), 0.0);
+                assert_eq!(encode_chroma(0.5, bits, curve), (1 << bits) - 2);
+                assert_eq!(encode_chroma(-0.7, bits, curve), 0);
+
+                // every extra bit at least halves the worst error
+                let worst = (0..=100)
+                    .map(|i| i as f32 / 100.0 - 0.5)
+                    .map(|x| (decode_chroma(encode_chroma(x, bits, curve), bits, curve) - x).abs())
+                    .fold(0.0, f32::max);
+                assert!(worst <= 0.5 / (1 << (bits - 2)) as f32, "{} {}: {}", curve, bits, worst);
+            }
+        }
+
+        // companding spends its finest steps near zero
+        let smallest = |curve| decode_chroma(encode_chroma(0.0, 6, curve) + 1, 6, curve);
+        assert!(smallest(ChromaCurve::Companded) < smallest(ChromaCurve::Uniform) / 4.0);
+    }
 }
@@ -0,0 +1,151 @@
+use crate::codec::{compress_gray_image, compress_image, compress_rgba_image, decompress_image, Options};
+use crate::entropy::Coding;
+use crate::error::RpegError;
+use crate::pnm::Image;
+use crate::quality::{rmse, MAX_QUALITY, MIN_QUALITY};
+use crate::transform::Transform;
+
+/// What `compress_to_target` settled on
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateResult {
+    /// The compressed image
+    pub bytes: Vec<u8>,
+    /// The quality it was compressed at
+    pub quality: u8,
+    /// How its blocks were written
+    pub coding: Coding,
+    /// The RMSE of the decompressed image against the original
+    pub rmse: f64,
+}
+
+/// Compresses an image of any kind with `options`
+fn compress(img: &Image, options: &Options) -> Result<Vec<u8>, RpegError> {
+    match img {
+        Image::Rgb(img) => compress_image(img, options),
+        Image::Gray(img) => compress_gray_image(img, options),
+        Image::Rgba(img) => compress_rgba_image(img, options),
+    }
+}
+
+/// Finds the highest quality whose output fits in `target` bytes. With fixed-size words
+/// every block takes the bits its layout or tables give it, and those only grow with the
+/// quality, so the quality is searched for. A Huffman code can come out smaller at a
+/// higher quality than at a lower one, so there every quality is tried, from the highest
+/// down, rather than letting a search stop short of the best fit.
+/// # Returns:
+/// * `Result<Result<(u8, Vec<u8>), usize>, RpegError>`: the quality and its output, or the
+///   smallest size of any quality if none fits
+fn highest_fitting(img: &Image, options: &Options, target: usize) -> Result<Result<(u8, Vec<u8>), usize>, RpegError> {
+    let at = |quality: u8| compress(img, &Options { quality: Some(quality), ..*options });
+    if options.coding == Coding::Fixed {
+        let mut fitting = at(MIN_QUALITY)?;
+        if fitting.len() > target {
+            return Ok(Err(fitting.len()));
+        }
+        // the lowest quality fits and anything above `high` does not
+        let (mut low, mut high) = (MIN_QUALITY, MAX_QUALITY);
+        while low < high {
+            let mid = low + (high - low).div_ceil(2);
+            let bytes = at(mid)?;
+            if bytes.len() <= target {
+                (low, fitting) = (mid, bytes);
+            } else {
+                high = mid - 1;
+            }
+        }
+        return Ok(Ok((low, fitting)));
+    }
+
+    let mut smallest = usize::MAX;
+    for quality in (MIN_QUALITY..=MAX_QUALITY).rev() {
+        let bytes = at(quality)?;
+        if bytes.len() <= target {
+            return Ok(Ok((quality, bytes)));
+        }
+        smallest = smallest.min(bytes.len());
+    }
+    Ok(Err(smallest))
+}
+
+/// Compresses an image at the highest quality that fits in a byte budget. Each coding the
+/// transform allows is searched for its highest fitting quality; of those, the one whose
+/// decompressed image has the lowest RMSE wins. The quality and coding in `options` are
+/// ignored; everything else is kept.
+/// # Returns:
+/// * `Result<RateResult, RpegError>`: the compressed image and what it was compressed with,
+///   or `RpegError::TargetTooSmall` if nothing fits
+///
+/// # Arguments:
+/// * `img`: the image to compress
+/// * `options`: the settings to compress it with
+/// * `target`: the most bytes the compressed image may take, header and checksum included
+pub fn compress_to_target(img: &Image, options: &Options, target: usize) -> Result<RateResult, RpegError> {
+    if options.transform == Transform::Lossless {
+        return Err(RpegError::Unsupported("lossless images have no quality to trade for size".to_string()));
+    }
+    let codings: &[Coding] = match options.transform {
+        Transform::Haar2x2 if !options.progressive => &[Coding::Fixed, Coding::Huffman],
+        _ => &[Coding::Fixed],
+    };
+
+    let original = img.to_rgb();
+    let mut best: Option<RateResult> = None;
+    let mut smallest = usize::MAX;
+    for &coding in codings {
+        let (quality, bytes) = match highest_fitting(img, &Options { coding, ..*options }, target)? {
+            Ok(found) => found,
+            Err(size) => {
+                smallest = smallest.min(size);
+                continue;
+            }
+        };
+        let error = rmse(&original, &decompress_image(&bytes)?.0.into_rgb());
+        let better = match &best {
+            Some(b) => error < b.rmse || (error == b.rmse && bytes.len() < b.bytes.len()),
+            None => true,
+        };
+        if better {
+            best = Some(RateResult { bytes, quality, coding, rmse: error });
+        }
+    }
+    best.ok_or(RpegError::TargetTooSmall { target, smallest })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::scene;
+    use csc411_image::{Gray, GrayImage};
+
+    #[test]
+    fn the_result_fits_and_more_room_helps() {
+        let img = Image::Rgb(scene(32, 24));
+        for (transform, small, large) in [(Transform::Haar2x2, 450, 1000), (Transform::Dct8x8, 900, 1600)] {
+            let options = Options { transform, ..Default::default() };
+            let tight = compress_to_target(&img, &options, small).unwrap();
+            let roomy = compress_to_target(&img, &options, large).unwrap();
+            assert!(tight.bytes.len() <= small && roomy.bytes.len() <= large);
+            assert!(roomy.rmse < tight.rmse, "{}: {} vs {}", transform, roomy.rmse, tight.rmse);
+
+            // the reported quality and coding reproduce the output
+            let again = Options { quality: Some(roomy.quality), coding: roomy.coding, ..options };
+            assert_eq!(compress(&img, &again).unwrap(), roomy.bytes);
+            // and no higher quality fits, though the size need not grow with the quality
+            for quality in tight.quality + 1..=MAX_QUALITY {
+                let higher = Options { quality: Some(quality), coding: tight.coding, ..options };
+                assert!(compress(&img, &higher).unwrap().len() > small, "{}: {}", transform, quality);
+            }
+        }
+    }
+
+    #[test]
+    fn impossible_targets_are_reported() {
+        let pixels = (0..32 * 32).map(|i| Gray { value: (i % 200) as u16 }).collect();
+        let img = Image::Gray(GrayImage { width: 32, height: 32, denominator: 255, pixels });
+        let err = compress_to_target(&img, &Options::default(), 40).unwrap_err();
+        assert!(matches!(err, RpegError::TargetTooSmall { target: 40, smallest } if smallest > 40));
+
+        let options = Options { transform: Transform::Lossless, ..Default::default() };
+        assert!(matches!(compress_to_target(&img, &options, 1 << 20), Err(RpegError::Unsupported(_))));
+    }
+}
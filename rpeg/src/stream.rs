@@ -0,0 +1,296 @@
+use std::io::{self, BufRead, Read, Write};
+
+use array2::Array2;
+use csc411_image::{Gray, Rgb};
+
+use crate::checksum::Crc32;
+use crate::codec::{encode_2x2, gray_from_luma, lossy_header, DecodeOptions, DecodeStats, Options};
+use crate::dither::Dither;
+use crate::entropy::Coding;
+use crate::error::RpegError;
+use crate::filter::ChromaUpsampling;
+use crate::format::{bytes_to_words, header_bytes, read_header_from, Header, FLAG_CHECKSUM};
+use crate::padding::{crop, pad_to_multiple, source_index};
+use crate::parallel::map_array;
+use crate::pnm::{read_raw_header, read_raw_row, write_raw_header, write_raw_row, RawHeader};
+use crate::to_component_video::YPbPr;
+use crate::to_rgb_float::{from_rgb32_with_denominator, to_rgbf32_with_denominator};
+use crate::transform::Transform;
+use crate::{unpack_2x2_pixels_from_float_with_threads, unpack_bits};
+
+/// Checks that a file can be coded one row of blocks at a time: with fixed-size words and
+/// no sections that hold the whole image, such as alpha or extra chroma samples
+fn check_streamable(header: &Header) -> Result<(), RpegError> {
+    let unsupported = |what: &str| Err(RpegError::Unsupported(format!("streaming {}", what)));
+    if header.transform != Transform::Haar2x2 {
+        return unsupported("needs the 2x2 transform");
+    }
+    if header.coding != Coding::Fixed {
+        return unsupported("needs fixed-size words");
+    }
+    if header.has_alpha() {
+        return unsupported("cannot code alpha");
+    }
+    if header.tile_size.is_some() {
+        return unsupported("cannot code tiles");
+    }
+    if header.progressive {
+        return unsupported("cannot code progressive order");
+    }
+    if header.extra_chroma_len() != 0 {
+        return unsupported(&format!("cannot code {} subsampling", header.subsampling));
+    }
+    Ok(())
+}
+
+/// Compresses a raw PPM or PGM image two rows at a time, writing each row of packed words
+/// as soon as it is ready, so memory stays proportional to the width of the image. The
+/// output is byte for byte what `compress_to` or `compress_gray_to` would write, for the
+/// options streaming supports: the 2x2 transform with fixed-size words, the transform's
+/// native 4:2:0 subsampling for colour images, and no dithering. A grayscale image has no
+/// chroma to subsample, so any subsampling will do.
+///
+/// # Arguments:
+/// * `input`: a raw PPM (P6) or PGM (P5) image
+/// * `out`: where to write the compressed image
+/// * `options`: the settings to compress it with
+pub fn compress_stream<R: BufRead, W: Write>(mut input: R, mut out: W, options: &Options) -> Result<(), RpegError> {
+    let raw = read_raw_header(&mut input)?;
+    if raw.maxval == 0 {
+        return Err(RpegError::UnsupportedDenominator(raw.maxval));
+    }
+    if options.dither != Dither::None {
+        return Err(RpegError::Unsupported("streaming cannot dither".to_string()));
+    }
+    let header = lossy_header(raw.width, raw.height, raw.maxval, raw.channels as u8, options)?;
+    check_streamable(&header)?;
+
+    let mut crc = Crc32::new();
+    let bytes = header_bytes(&header);
+    crc.update(&bytes);
+    out.write_all(&bytes)?;
+
+    let (width, height) = (raw.width as usize, raw.height as usize);
+    // the row above the current pair, which mirror padding reflects an odd last row onto
+    let mut previous: Option<Vec<u16>> = None;
+    for y in (0..height).step_by(2) {
+        let top = read_raw_row(&mut input, &raw)?;
+        let bottom = if y + 1 < height {
+            read_raw_row(&mut input, &raw)?
+        } else {
+            match source_index(y + 1, height, options.padding) {
+                Some(i) if i == y => top.clone(),
+                Some(_) => previous.take().unwrap_or_else(|| top.clone()),
+                None => vec![0; top.len()],
+            }
+        };
+
+        let samples: Vec<u16> = top.iter().chain(&bottom).copied().collect();
+        let arr_cv = if raw.channels == 1 {
+            let pixels = samples.into_iter().map(|value| Gray { value }).collect();
+            let arr = Array2::from_row_major(width, 2, pixels)?;
+            let arr_padded = pad_to_multiple(&arr, 2, options.padding, Gray { value: 0 });
+            let scale = raw.maxval as f32;
+            map_array(&arr_padded, options.threads, |p| YPbPr::new(p.value as f32 / scale, 0.0, 0.0))
+        } else {
+            let pixels = samples.chunks_exact(3).map(|p| Rgb { red: p[0], green: p[1], blue: p[2] }).collect();
+            let arr = Array2::from_row_major(width, 2, pixels)?;
+            let arr_padded = pad_to_multiple(&arr, 2, options.padding, Rgb { red: 0, green: 0, blue: 0 });
+            let arr_f = to_rgbf32_with_denominator(&arr_padded, raw.maxval);
+            let transform = header.color.transform();
+            map_array(&arr_f, options.threads, |p| transform.forward(p))
+        };
+
+        let payload = encode_2x2(arr_cv, &header.layout, options, header.subsampling)?;
+        crc.update(&payload);
+        out.write_all(&payload)?;
+        previous = Some(bottom);
+    }
+
+    out.write_all(&crc.finish().to_be_bytes())?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Decompresses an rpeg file one row of blocks at a time, writing each pair of rows of
+/// the raw PPM or PGM image as soon as it is decoded, so memory stays proportional to the
+/// width of the image. The image is what `decompress_image` would give, but since rows are
+/// written before the checksum at the end is read, a damaged file is only reported after
+/// most of it has been written.
+/// # Returns:
+/// * `Result<DecodeStats, RpegError>`: how many samples the colour conversion clamped
+///
+/// # Arguments:
+/// * `input`: an rpeg file with the 2x2 transform, fixed-size words, and at most one
+///   chroma sample per block
+/// * `out`: where to write the image
+/// * `options`: the settings to decode with; the filters need neighbouring rows of blocks,
+///   so they are not supported
+pub fn decompress_stream<R: Read, W: Write>(
+    mut input: R,
+    mut out: W,
+    options: &DecodeOptions,
+) -> Result<DecodeStats, RpegError> {
+    let (header, bytes) = read_header_from(&mut input)?;
+    check_streamable(&header)?;
+    if options.deblock || options.chroma_upsampling != ChromaUpsampling::Replicate {
+        return Err(RpegError::Unsupported("streaming cannot filter the decoded blocks".to_string()));
+    }
+    let mut crc = Crc32::new();
+    crc.update(&bytes);
+
+    let (width, height) = (header.width as usize, header.height as usize);
+    let raw = RawHeader { width: header.width, height: header.height, maxval: header.denominator, channels: 3 };
+    let raw = if header.channels == 1 { RawHeader { channels: 1, ..raw } } else { raw };
+    write_raw_header(&mut out, &raw)?;
+
+    let layout = &header.layout;
+    let blocks_wide = width.div_ceil(2);
+    let row_len = blocks_wide
+        .checked_mul(layout.bytes_per_word())
+        .ok_or_else(|| RpegError::BadHeader("image is too large".to_string()))?;
+    // the width is not trusted until its rows arrive, so the buffer grows as they are read
+    let mut words = vec![];
+    let mut stats = DecodeStats::default();
+    for y in (0..height).step_by(2) {
+        words.clear();
+        input.by_ref().take(row_len as u64).read_to_end(&mut words)?;
+        if words.len() < row_len {
+            return Err(RpegError::Truncated {
+                expected: header.payload_len().unwrap_or_default(),
+                actual: y / 2 * row_len + words.len(),
+            });
+        }
+        crc.update(&words);
+
+        let blocks = bytes_to_words(layout, &words)
+            .into_iter()
+            .map(|word| layout.dequantize(unpack_bits(layout, word)))
+            .collect();
+        let blocks = Array2::from_row_major(blocks_wide, 1, blocks)?;
+        let unpacked = unpack_2x2_pixels_from_float_with_threads(blocks, options.threads);
+        let rows = (height - y).min(2);
+        let samples: Vec<u16> = if header.channels == 1 {
+            let (pixels, clipped) = gray_from_luma(&crop(&unpacked, width, rows), header.denominator);
+            stats.clipped += clipped;
+            pixels.into_iter().map(|p| p.value).collect()
+        } else {
+            let transform = header.color.transform();
+            let arr_f = map_array(&unpacked, options.threads, |p| transform.inverse(*p));
+            let (pixels, clipped) = from_rgb32_with_denominator(&crop(&arr_f, width, rows), header.denominator);
+            stats.clipped += clipped;
+            pixels.data().iter().flat_map(|p| [p.red, p.green, p.blue]).collect()
+        };
+        stats.samples += samples.len();
+        for row in samples.chunks_exact(width * raw.channels) {
+            write_raw_row(&mut out, &raw, row)?;
+        }
+    }
+    out.flush()?;
+
+    if u16::from_be_bytes([bytes[5], bytes[6]]) & FLAG_CHECKSUM != 0 {
+        let mut trailer = [0; 4];
+        input.read_exact(&mut trailer).map_err(|_| RpegError::BadHeader("checksum is missing".to_string()))?;
+        let (expected, actual) = (u32::from_be_bytes(trailer), crc.finish());
+        if expected != actual {
+            return Err(RpegError::Corrupt { expected, actual });
+        }
+    }
+    let trailing = io::copy(&mut input, &mut io::sink())?;
+    if trailing != 0 {
+        return Err(RpegError::TrailingData(trailing as usize));
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{compress_gray_image, compress_image, decompress_image};
+    use crate::padding::Padding;
+    use crate::subsampling::Subsampling;
+    use crate::pnm::{read_pnm, write_pnm, Image};
+
+    fn raw_image(width: u32, height: u32, maxval: u16, channels: usize) -> Vec<u8> {
+        let header = RawHeader { width, height, maxval, channels };
+        let mut bytes = vec![];
+        write_raw_header(&mut bytes, &header).unwrap();
+        for y in 0..height {
+            let row: Vec<u16> = (0..width as usize * channels)
+                .map(|i| ((i as u32 * 37 + y * 11) % (maxval as u32 + 1)) as u16)
+                .collect();
+            write_raw_row(&mut bytes, &header, &row).unwrap();
+        }
+        bytes
+    }
+
+    #[test]
+    fn streams_match_the_whole_image_codec() {
+        for (width, height) in [(1, 1), (2, 2), (7, 5), (8, 3), (12, 9)] {
+            // a grayscale image has no chroma, so it streams with any subsampling
+            for (maxval, channels, subsampling) in
+                [(255, 3, None), (1000, 3, None), (255, 1, None), (255, 1, Some(Subsampling::S444))]
+            {
+                for padding in [Padding::Edge, Padding::Mirror, Padding::Zero] {
+                    let original = raw_image(width, height, maxval, channels);
+                    let options = Options { padding, subsampling, ..Default::default() };
+                    let whole = match read_pnm(&original).unwrap() {
+                        Image::Rgb(img) => compress_image(&img, &options).unwrap(),
+                        Image::Gray(img) => compress_gray_image(&img, &options).unwrap(),
+                        Image::Rgba(_) => unreachable!(),
+                    };
+                    let mut streamed = vec![];
+                    compress_stream(&original[..], &mut streamed, &options).unwrap();
+                    assert!(streamed == whole, "{}x{} / {} {:?}", width, height, maxval, padding);
+
+                    let mut expected = vec![];
+                    write_pnm(&mut expected, &decompress_image(&whole).unwrap().0).unwrap();
+                    let mut decoded = vec![];
+                    decompress_stream(&streamed[..], &mut decoded, &DecodeOptions::default()).unwrap();
+                    assert!(decoded == expected, "{}x{} / {}", width, height, maxval);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unstreamable_files_are_refused() {
+        let original = raw_image(6, 4, 255, 3);
+        let refused = [
+            Options { coding: Coding::Huffman, ..Default::default() },
+            Options { transform: Transform::Dct8x8, ..Default::default() },
+            Options { subsampling: Some(Subsampling::S444), ..Default::default() },
+            Options { dither: Dither::Ordered, ..Default::default() },
+            Options { tile_size: Some(4), ..Default::default() },
+        ];
+        for options in refused {
+            let result = compress_stream(&original[..], vec![], &options);
+            assert!(matches!(result, Err(RpegError::Unsupported(_))), "{:?}", options);
+        }
+
+        let mut compressed = vec![];
+        compress_stream(&original[..], &mut compressed, &Options::default()).unwrap();
+        let deblock = DecodeOptions { deblock: true, ..Default::default() };
+        assert!(matches!(decompress_stream(&compressed[..], vec![], &deblock), Err(RpegError::Unsupported(_))));
+
+        let decode = |bytes: &[u8]| decompress_stream(bytes, vec![], &DecodeOptions::default());
+        let last = compressed.len() - 1;
+        compressed[last] ^= 1;
+        assert!(matches!(decode(&compressed), Err(RpegError::Corrupt { .. })));
+        compressed[last] ^= 1;
+        compressed.push(0);
+        assert!(matches!(decode(&compressed), Err(RpegError::TrailingData(1))));
+        compressed.truncate(50);
+        assert!(matches!(decode(&compressed), Err(RpegError::Truncated { .. })));
+    }
+
+    #[test]
+    fn forged_widths_are_truncated_rather_than_allocated() {
+        let mut compressed = vec![];
+        compress_stream(&raw_image(6, 4, 255, 3)[..], &mut compressed, &Options::default()).unwrap();
+        // a row of blocks this wide would take gigabytes
+        compressed[7..11].copy_from_slice(&0xffff_fff0_u32.to_be_bytes());
+        let result = decompress_stream(&compressed[..], vec![], &DecodeOptions::default());
+        assert!(matches!(result, Err(RpegError::Truncated { .. })));
+    }
+}
@@ -0,0 +1,218 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::bitio::{BitReader, BitWriter};
+use crate::error::RpegError;
+use crate::layout::WordLayout;
+use crate::to_component_video::YPbPr;
+use crate::transform::Transform;
+
+/// How many chroma samples each 2x2 block keeps
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Subsampling {
+    /// One chroma sample per pixel
+    S444,
+    /// One chroma sample per column of a block, averaged vertically
+    S422,
+    /// One chroma sample per block, the original scheme
+    S420,
+}
+
+impl Subsampling {
+    /// The scheme a transform uses unless told otherwise: 4:2:0 for the 2x2 transform,
+    /// whose words have room for one chroma sample, and 4:4:4 for the others, which are
+    /// the only scheme they support
+    pub fn native(transform: Transform) -> Self {
+        match transform {
+            Transform::Haar2x2 => Subsampling::S420,
+            Transform::Dct8x8 | Transform::Lossless => Subsampling::S444,
+        }
+    }
+
+    /// The id of the scheme in the file header
+    pub fn id(&self) -> u8 {
+        match self {
+            Subsampling::S444 => 0,
+            Subsampling::S422 => 1,
+            Subsampling::S420 => 2,
+        }
+    }
+
+    /// The scheme with the given header id, if there is one
+    pub fn from_id(id: u8) -> Option<Self> {
+        [Subsampling::S444, Subsampling::S422, Subsampling::S420].into_iter().find(|s| s.id() == id)
+    }
+
+    /// Number of chroma samples in each 2x2 block
+    pub fn samples_per_block(&self) -> usize {
+        match self {
+            Subsampling::S444 => 4,
+            Subsampling::S422 => 2,
+            Subsampling::S420 => 1,
+        }
+    }
+
+    /// How the samples of a block are arranged
+    /// # Returns:
+    /// * `(usize, usize)`: the number of columns and rows of samples
+    pub fn grid(&self) -> (usize, usize) {
+        match self {
+            Subsampling::S444 => (2, 2),
+            Subsampling::S422 => (2, 1),
+            Subsampling::S420 => (1, 1),
+        }
+    }
+
+    /// Averages the chroma of a 2x2 block into this scheme's samples
+    /// # Returns:
+    /// * `Vec<(f32, f32)>`: pb and pr of each sample, left to right and then top to bottom
+    ///
+    /// # Arguments:
+    /// * `group`: the top-left, top-right, bottom-left, and bottom-right pixels
+    pub fn sample(&self, group: [&YPbPr; 4]) -> Vec<(f32, f32)> {
+        let mut samples = Vec::with_capacity(self.samples_per_block());
+        self.sample_into(group, &mut samples);
+        samples
+    }
+
+    /// Does what `sample` does, appending the samples to `out` instead of allocating
+    pub fn sample_into(&self, group: [&YPbPr; 4], out: &mut Vec<(f32, f32)>) {
+        let average = |pixels: &[usize]| {
+            let n = pixels.len() as f32;
+            let pb = pixels.iter().map(|&i| group[i].pb).sum::<f32>() / n;
+            let pr = pixels.iter().map(|&i| group[i].pr).sum::<f32>() / n;
+            (pb, pr)
+        };
+        match self {
+            Subsampling::S444 => out.extend((0..4).map(|i| average(&[i]))),
+            Subsampling::S422 => out.extend([average(&[0, 2]), average(&[1, 3])]),
+            Subsampling::S420 => out.push(average(&[0, 1, 2, 3])),
+        }
+    }
+
+    /// Reverses `sample`, giving each pixel of a block the chroma of the sample it was
+    /// averaged into
+    /// # Returns:
+    /// * `[(f32, f32); 4]`: pb and pr of the top-left, top-right, bottom-left, and
+    ///   bottom-right pixels
+    ///
+    /// # Arguments:
+    /// * `samples`: pb and pr of the block's samples
+    pub fn spread(&self, samples: &[(f32, f32)]) -> [(f32, f32); 4] {
+        let chroma = |i: usize| samples[i];
+        match self {
+            Subsampling::S444 => [chroma(0), chroma(1), chroma(2), chroma(3)],
+            Subsampling::S422 => [chroma(0), chroma(1), chroma(0), chroma(1)],
+            Subsampling::S420 => [chroma(0); 4],
+        }
+    }
+
+    /// Number of bytes `encode_extra_chroma` writes for `blocks` blocks
+    pub fn extra_chroma_len(&self, layout: &WordLayout, blocks: usize) -> Option<usize> {
+        let bits = (layout.pb.width + layout.pr.width) as usize;
+        Some(blocks.checked_mul((self.samples_per_block() - 1) * bits)?.div_ceil(8))
+    }
+}
+
+impl fmt::Display for Subsampling {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Subsampling::S444 => write!(f, "4:4:4"),
+            Subsampling::S422 => write!(f, "4:2:2"),
+            Subsampling::S420 => write!(f, "4:2:0"),
+        }
+    }
+}
+
+impl FromStr for Subsampling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "4:4:4" | "444" => Ok(Subsampling::S444),
+            "4:2:2" | "422" => Ok(Subsampling::S422),
+            "4:2:0" | "420" => Ok(Subsampling::S420),
+            _ => Err(format!("unknown subsampling `{}`, expected 444, 422, or 420", s)),
+        }
+    }
+}
+
+/// Packs the chroma samples that do not fit in the blocks' words. The first sample of each
+/// block travels in its word; the rest follow the blocks, quantized to the widths of pb and
+/// pr and packed back to back.
+/// # Returns:
+/// * `Vec<u8>`: the packed samples
+///
+/// # Arguments:
+/// * `layout`: the layout the blocks are packed with
+/// * `extra`: pb and pr of every sample but the first, block by block
+pub fn encode_extra_chroma(layout: &WordLayout, extra: &[(f32, f32)]) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+    for &(pb, pr) in extra {
+        let (pb, pr) = layout.quantize_chroma(pb, pr);
+        writer.put(pb as u64, layout.pb.width);
+        writer.put(pr as u64, layout.pr.width);
+    }
+    writer.finish()
+}
+
+/// Reverses `encode_extra_chroma`
+/// # Returns:
+/// * `Result<Vec<(f32, f32)>, RpegError>`: pb and pr of the samples, as quantized
+///
+/// # Arguments:
+/// * `layout`: the layout the blocks are packed with
+/// * `bytes`: the output of `encode_extra_chroma`
+/// * `count`: the number of samples
+pub fn decode_extra_chroma(layout: &WordLayout, bytes: &[u8], count: usize) -> Result<Vec<(f32, f32)>, RpegError> {
+    let mut reader = BitReader::new(bytes);
+    (0..count)
+        .map(|_| {
+            let pb = reader.get(layout.pb.width)?;
+            let pr = reader.get(layout.pr.width)?;
+            Some(layout.dequantize_chroma(pb as usize, pr as usize))
+        })
+        .collect::<Option<_>>()
+        .ok_or_else(|| RpegError::BadPayload("chroma samples ended unexpectedly".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use csc411_arith::chroma_of_index;
+
+    #[test]
+    fn samples_follow_the_scheme() {
+        let (left, right) = (YPbPr::new(0.5, -0.3, 0.2), YPbPr::new(0.5, 0.3, -0.2));
+        let group = [&left, &right, &left, &right];
+
+        let full = Subsampling::S444.sample(group);
+        assert_eq!(full.len(), 4);
+        assert_eq!(Subsampling::S422.sample(group), vec![full[0], full[1]]);
+        // the two columns cancel out
+        let average = Subsampling::S420.sample(group);
+        assert_eq!(average, vec![(0.0, 0.0)]);
+
+        let spread = Subsampling::S422.spread(&Subsampling::S422.sample(group));
+        assert_eq!((spread[0], spread[1]), (spread[2], spread[3]));
+        assert!(spread[0].0 < 0.0 && spread[1].0 > 0.0);
+
+        for subsampling in [Subsampling::S444, Subsampling::S422, Subsampling::S420] {
+            let (columns, rows) = subsampling.grid();
+            assert_eq!(columns * rows, subsampling.samples_per_block());
+        }
+    }
+
+    #[test]
+    fn extra_chroma_round_trip() {
+        let layout = WordLayout::default();
+        let extra: Vec<_> = [(0, 15), (7, 8), (3, 12)]
+            .into_iter()
+            .map(|(pb, pr)| (chroma_of_index(pb), chroma_of_index(pr)))
+            .collect();
+        let bytes = encode_extra_chroma(&layout, &extra);
+        assert_eq!(bytes.len(), Subsampling::S444.extra_chroma_len(&layout, 1).unwrap());
+        assert_eq!(decode_extra_chroma(&layout, &bytes, 3).unwrap(), extra);
+        assert!(decode_extra_chroma(&layout, &bytes, 4).is_err());
+    }
+}
@@ -0,0 +1,188 @@
+use csc411_image::{Gray, GrayImage, Rgb, RgbImage};
+
+use crate::pnm::{Image, RgbaImage};
+
+/// A rectangle of pixels within an image
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    /// The part of this region that is also in `other`, or `None` if they do not overlap
+    pub fn intersect(&self, other: &Region) -> Option<Region> {
+        let (x, y) = (self.x.max(other.x), self.y.max(other.y));
+        let right = (self.x + self.width).min(other.x + other.width);
+        let bottom = (self.y + self.height).min(other.y + other.height);
+        (x < right && y < bottom).then(|| Region { x, y, width: right - x, height: bottom - y })
+    }
+}
+
+/// Splits an image into square tiles; those along the right and bottom edges are cut short
+/// where the image ends
+/// # Returns:
+/// * `Vec<Region>`: the tiles, left to right and then top to bottom
+///
+/// # Arguments:
+/// * `width`, `height`: the dimensions of the image
+/// * `size`: the width and height of a whole tile, which must not be 0
+pub fn tiles(width: u32, height: u32, size: u32) -> Vec<Region> {
+    let whole = Region { x: 0, y: 0, width, height };
+    tiles_over(width, height, size, whole).map(|(_, tile)| tile).collect()
+}
+
+/// Counts the tiles `tiles` splits an image into
+/// # Returns:
+/// * `Option<usize>`: the number of tiles, or `None` if it does not fit in a usize
+pub fn tile_count(width: u32, height: u32, size: u32) -> Option<usize> {
+    (width.div_ceil(size) as usize).checked_mul(height.div_ceil(size) as usize)
+}
+
+/// Finds the tiles that overlap a region, visiting only the rows and columns of tiles the
+/// region spans, so a small region of a huge image costs no more than one of a small image
+/// # Returns:
+/// * `impl Iterator<Item = (usize, Region)>`: each tile's position in the order of `tiles`,
+///   and the tile
+///
+/// # Arguments:
+/// * `width`, `height`: the dimensions of the image
+/// * `size`: the width and height of a whole tile, which must not be 0
+/// * `region`: the rectangle, which must lie within the image
+pub fn tiles_over(width: u32, height: u32, size: u32, region: Region) -> impl Iterator<Item = (usize, Region)> {
+    let columns = width.div_ceil(size) as usize;
+    let span = |start: u32, len: u32| if len == 0 { 0..0 } else { start / size..(start + len).div_ceil(size) };
+    let (rows, cols) = (span(region.y, region.height), span(region.x, region.width));
+    rows.flat_map(move |row| {
+        cols.clone().map(move |col| {
+            let (x, y) = (col * size, row * size);
+            let tile = Region { x, y, width: size.min(width - x), height: size.min(height - y) };
+            (row as usize * columns + col as usize, tile)
+        })
+    })
+}
+
+/// Copies the pixels of a region out of a row-major image `width` pixels wide
+fn crop_pixels<T: Clone>(pixels: &[T], width: u32, region: Region) -> Vec<T> {
+    let (width, x) = (width as usize, region.x as usize);
+    (region.y as usize..(region.y + region.height) as usize)
+        .flat_map(|y| pixels[y * width + x..y * width + x + region.width as usize].iter().cloned())
+        .collect()
+}
+
+/// Copies a region of a colour image into an image of its own
+pub fn crop_rgb(img: &RgbImage, region: Region) -> RgbImage {
+    let pixels = crop_pixels(&img.pixels, img.width, region);
+    RgbImage { width: region.width, height: region.height, denominator: img.denominator, pixels }
+}
+
+/// Copies a region of a grayscale image into an image of its own
+pub fn crop_gray(img: &GrayImage, region: Region) -> GrayImage {
+    let pixels = crop_pixels(&img.pixels, img.width, region);
+    GrayImage { width: region.width, height: region.height, denominator: img.denominator, pixels }
+}
+
+/// Copies a region of a transparent image into an image of its own
+pub fn crop_rgba(img: &RgbaImage, region: Region) -> RgbaImage {
+    RgbaImage { color: crop_rgb(&img.color, region), alpha: crop_pixels(&img.alpha, img.color.width, region) }
+}
+
+/// Copies the pixels of `src`, which covers `from`, that are also in `to` into `dst`,
+/// which covers `to`
+fn paste_pixels<T: Clone>(dst: &mut [T], to: Region, src: &[T], from: Region) {
+    let Some(overlap) = from.intersect(&to) else {
+        return;
+    };
+    for y in overlap.y..overlap.y + overlap.height {
+        let row = |region: Region| {
+            let start = ((y - region.y) * region.width + overlap.x - region.x) as usize;
+            start..start + overlap.width as usize
+        };
+        dst[row(to)].clone_from_slice(&src[row(from)]);
+    }
+}
+
+/// Copies the decoded pixels of a tile into the part of an image they overlap. Both
+/// images must be of the same kind.
+///
+/// # Arguments:
+/// * `dst`: the image being assembled, which covers `to`
+/// * `to`: the region of the whole image `dst` holds
+/// * `src`: the decoded tile, which covers `from`
+/// * `from`: where the tile is in the whole image
+pub fn paste(dst: &mut Image, to: Region, src: &Image, from: Region) {
+    match (dst, src) {
+        (Image::Rgb(dst), Image::Rgb(src)) => paste_pixels(&mut dst.pixels, to, &src.pixels, from),
+        (Image::Gray(dst), Image::Gray(src)) => paste_pixels(&mut dst.pixels, to, &src.pixels, from),
+        (Image::Rgba(dst), Image::Rgba(src)) => {
+            paste_pixels(&mut dst.color.pixels, to, &src.color.pixels, from);
+            paste_pixels(&mut dst.alpha, to, &src.alpha, from);
+        }
+        _ => unreachable!("every tile of an image is of the same kind"),
+    }
+}
+
+/// A black, and for transparent images clear, image to paste decoded tiles into
+/// # Returns:
+/// * `Image`: a grayscale image for 1 channel, a colour image for 3, or a transparent one for 4
+///
+/// # Arguments:
+/// * `channels`: the number of channels of the compressed image
+/// * `denominator`: the denominator of the compressed image
+/// * `width`, `height`: the dimensions of the image
+pub fn blank_image(channels: u8, denominator: u16, width: u32, height: u32) -> Image {
+    let count = width as usize * height as usize;
+    let color = || RgbImage { width, height, denominator, pixels: vec![Rgb { red: 0, green: 0, blue: 0 }; count] };
+    match channels {
+        1 => Image::Gray(GrayImage { width, height, denominator, pixels: vec![Gray { value: 0 }; count] }),
+        4 => Image::Rgba(RgbaImage { color: color(), alpha: vec![0; count] }),
+        _ => Image::Rgb(color()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiles_cover_the_image_once() {
+        let all = tiles(10, 7, 4);
+        assert_eq!(all.len(), 3 * 2);
+        assert_eq!(all[2], Region { x: 8, y: 0, width: 2, height: 4 });
+        assert_eq!(all[5], Region { x: 8, y: 4, width: 2, height: 3 });
+        assert_eq!(all.iter().map(|t| t.width * t.height).sum::<u32>(), 70);
+        assert!(tiles(0, 7, 4).is_empty());
+        assert_eq!(tile_count(10, 7, 4), Some(6));
+
+        let region = Region { x: 3, y: 5, width: 6, height: 2 };
+        let over: Vec<_> = all.iter().copied().enumerate().filter(|(_, t)| t.intersect(&region).is_some()).collect();
+        assert_eq!(tiles_over(10, 7, 4, region).collect::<Vec<_>>(), over);
+        // only the tiles around a small region of a huge image are visited
+        let corner = Region { x: u32::MAX - 3, y: u32::MAX - 1, width: 3, height: 1 };
+        let far: Vec<_> = tiles_over(u32::MAX, u32::MAX, 2, corner).collect();
+        assert_eq!(far.len(), 2);
+        assert_eq!(far[0].1, Region { x: u32::MAX - 3, y: u32::MAX - 1, width: 2, height: 1 });
+        assert_eq!(far[1].1, Region { x: u32::MAX - 1, y: u32::MAX - 1, width: 1, height: 1 });
+
+        let a = Region { x: 2, y: 2, width: 4, height: 4 };
+        let b = Region { x: 5, y: 0, width: 9, height: 3 };
+        assert_eq!(a.intersect(&b), Some(Region { x: 5, y: 2, width: 1, height: 1 }));
+        assert_eq!(a.intersect(&Region { x: 6, y: 2, width: 1, height: 1 }), None);
+    }
+
+    #[test]
+    fn cropped_tiles_paste_back() {
+        let pixels = (0..6 * 5).map(|value| Gray { value }).collect();
+        let img = GrayImage { width: 6, height: 5, denominator: 255, pixels };
+        let whole = Region { x: 0, y: 0, width: 6, height: 5 };
+
+        let mut copy = blank_image(1, 255, 6, 5);
+        for tile in tiles(6, 5, 4) {
+            paste(&mut copy, whole, &Image::Gray(crop_gray(&img, tile)), tile);
+        }
+        let Image::Gray(copy) = copy else { unreachable!() };
+        assert!(copy.pixels.iter().map(|p| p.value).eq(img.pixels.iter().map(|p| p.value)));
+    }
+}
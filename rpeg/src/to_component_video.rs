@@ -1,7 +1,7 @@
 use array2::Array2;
 use std::fmt;
+use std::str::FromStr;
 
-use csc411_image::Rgb;
 
 use crate::to_rgb_float::RgbF32;
 
@@ -53,57 +53,245 @@ impl YPbPr {
     }
 }
 
-/// Returns array2 of type ypbpr, used for compression
-/// 
-/// # Arguments:
-/// * An array2 of rgbf32
-pub fn to_ypbpr(arr: &Array2<RgbF32>) -> Array2<YPbPr> {
-    let new_data: Vec<YPbPr> = arr
-    .iter_row_major()
-    .map(|(_, _, element)| {
-        let r = element.red();
-        let g = element.green();
-        let b = element.blue();
+/// Converts between RGB and a luminance/chroma colour space. Samples are in [0, 1] and
+/// the chroma components in roughly [-0.5, 0.5], which is what the quantizers expect.
+/// Conversions run on several threads at once, hence `Sync`.
+pub trait ColorTransform: Sync {
+    /// Converts one RGB pixel to luminance and chroma
+    fn forward(&self, rgb: &RgbF32) -> YPbPr;
+
+    /// Converts luminance and chroma back to an RGB pixel
+    fn inverse(&self, cv: YPbPr) -> RgbF32;
+}
+
+/// The BT.601 YPbPr matrix used for standard-definition video and JPEG
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Bt601;
+
+impl ColorTransform for Bt601 {
+    fn forward(&self, rgb: &RgbF32) -> YPbPr {
+        let (r, g, b) = (rgb.red(), rgb.green(), rgb.blue());
 
         let y = 0.299 * r + 0.587 * g + 0.114 * b;
         let pb = -0.168736 * r - 0.331264 * g + 0.5 * b;
         let pr = 0.5 * r - 0.418688 * g - 0.081312 * b;
 
         YPbPr { y, pb, pr }
-    })
-    .collect();
+    }
 
-    return Array2::from_row_major(arr.width(), arr.height(), new_data).unwrap();
-}
-
-/// Returns array2 of rgbf32, used for decompression
-/// 
-/// # Arguments:
-/// * array2 of ypbpr 
-pub fn from_ypbpr(arr: &Array2<YPbPr>) -> Array2<RgbF32> {
-    let new_data: Vec<RgbF32> = arr
-    .iter_row_major()
-    .map(|(_, _, element)| {
-        let y = element.y();
-        let pb = element.pb();
-        let pr = element.pr();
+    fn inverse(&self, cv: YPbPr) -> RgbF32 {
+        let (y, pb, pr) = (cv.y(), cv.pb(), cv.pr());
 
         let red = 1.0 * y + 0.0 * pb + 1.402 * pr;
         let green = 1.0 * y - 0.344136 * pb - 0.714136 * pr;
         let blue = 1.0 * y + 1.772 * pb + 0.0 * pr;
-        
-        RgbF32 {
-            red,
-            green,
-            blue,
+
+        RgbF32 { red, green, blue }
+    }
+}
+
+/// The BT.709 YPbPr matrix used for high-definition video
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Bt709;
+
+impl ColorTransform for Bt709 {
+    fn forward(&self, rgb: &RgbF32) -> YPbPr {
+        let (r, g, b) = (rgb.red(), rgb.green(), rgb.blue());
+
+        let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+        let pb = (b - y) / 1.8556;
+        let pr = (r - y) / 1.5748;
+
+        YPbPr { y, pb, pr }
+    }
+
+    fn inverse(&self, cv: YPbPr) -> RgbF32 {
+        let (y, pb, pr) = (cv.y(), cv.pb(), cv.pr());
+
+        let red = y + 1.5748 * pr;
+        let green = y - 0.187324 * pb - 0.468124 * pr;
+        let blue = y + 1.8556 * pb;
+
+        RgbF32 { red, green, blue }
+    }
+}
+
+/// YCoCg-R, whose lifting steps are exactly reversible on integers. Through the
+/// `ColorTransform` trait it acts on [0, 1] samples, with Co and Cg halved so they share
+/// the range of Pb and Pr; `forward_int` and `inverse_int` are the integer lifting itself.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct YCoCgR;
+
+impl YCoCgR {
+    /// Converts integer RGB samples to Y, Co, and Cg. Co and Cg need one more bit than the
+    /// samples do.
+    pub fn forward_int(r: i32, g: i32, b: i32) -> (i32, i32, i32) {
+        let co = r - b;
+        let t = b + (co >> 1);
+        let cg = g - t;
+        let y = t + (cg >> 1);
+        (y, co, cg)
+    }
+
+    /// Exactly reverses `forward_int`
+    pub fn inverse_int(y: i32, co: i32, cg: i32) -> (i32, i32, i32) {
+        let t = y - (cg >> 1);
+        let g = cg + t;
+        let b = t - (co >> 1);
+        let r = b + co;
+        (r, g, b)
+    }
+}
+
+impl ColorTransform for YCoCgR {
+    fn forward(&self, rgb: &RgbF32) -> YPbPr {
+        let (r, g, b) = (rgb.red(), rgb.green(), rgb.blue());
+
+        let y = 0.25 * r + 0.5 * g + 0.25 * b;
+        let co = 0.5 * (r - b);
+        let cg = 0.5 * g - 0.25 * (r + b);
+
+        YPbPr { y, pb: co, pr: cg }
+    }
+
+    fn inverse(&self, cv: YPbPr) -> RgbF32 {
+        let (y, co, cg) = (cv.y(), cv.pb(), cv.pr());
+
+        let t = y - cg;
+        RgbF32 { red: t + co, green: y + cg, blue: t - co }
+    }
+}
+
+/// The colour transforms a file can record in its header
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorSpace {
+    /// BT.601 YPbPr, the original colour path
+    #[default]
+    Bt601,
+    /// BT.709 YPbPr
+    Bt709,
+    /// The reversible YCoCg-R
+    YCoCgR,
+}
+
+impl ColorSpace {
+    /// The id of the colour space in the file header
+    pub fn id(&self) -> u8 {
+        match self {
+            ColorSpace::Bt601 => 0,
+            ColorSpace::Bt709 => 1,
+            ColorSpace::YCoCgR => 2,
+        }
+    }
+
+    /// The colour space with the given header id, if there is one
+    pub fn from_id(id: u8) -> Option<Self> {
+        [ColorSpace::Bt601, ColorSpace::Bt709, ColorSpace::YCoCgR].into_iter().find(|c| c.id() == id)
+    }
+
+    /// The transform that converts to and from this colour space
+    pub fn transform(&self) -> &'static dyn ColorTransform {
+        match self {
+            ColorSpace::Bt601 => &Bt601,
+            ColorSpace::Bt709 => &Bt709,
+            ColorSpace::YCoCgR => &YCoCgR,
+        }
+    }
+}
+
+impl fmt::Display for ColorSpace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ColorSpace::Bt601 => write!(f, "bt601"),
+            ColorSpace::Bt709 => write!(f, "bt709"),
+            ColorSpace::YCoCgR => write!(f, "ycocg-r"),
         }
+    }
+}
+
+impl FromStr for ColorSpace {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bt601" | "601" => Ok(ColorSpace::Bt601),
+            "bt709" | "709" => Ok(ColorSpace::Bt709),
+            "ycocg-r" | "ycocg" => Ok(ColorSpace::YCoCgR),
+            _ => Err(format!("unknown colour space `{}`, expected bt601, bt709, or ycocg", s)),
+        }
+    }
+}
+
+/// Returns array2 of component video in the given colour space, used for compression
+///
+/// # Arguments:
+/// * `arr`: an array2 of rgbf32
+/// * `transform`: the colour transform to apply
+pub fn to_component_video(arr: &Array2<RgbF32>, transform: &dyn ColorTransform) -> Array2<YPbPr> {
+    let new_data: Vec<YPbPr> = arr.iter_row_major().map(|(_, _, element)| transform.forward(element)).collect();
 
-    })
-    .collect();
+    Array2::from_row_major(arr.width(), arr.height(), new_data).unwrap()
+}
+
+/// Returns array2 of rgbf32 from component video in the given colour space, used for
+/// decompression
+///
+/// # Arguments:
+/// * `arr`: an array2 of component video
+/// * `transform`: the colour transform that produced it
+pub fn from_component_video(arr: &Array2<YPbPr>, transform: &dyn ColorTransform) -> Array2<RgbF32> {
+    let new_data: Vec<RgbF32> = arr.iter_row_major().map(|(_, _, element)| transform.inverse(*element)).collect();
+
+    Array2::from_row_major(arr.width(), arr.height(), new_data).unwrap()
+}
+
+/// Returns array2 of type ypbpr, used for compression
+/// 
+/// # Arguments:
+/// * An array2 of rgbf32
+pub fn to_ypbpr(arr: &Array2<RgbF32>) -> Array2<YPbPr> {
+    to_component_video(arr, &Bt601)
+}
 
-    return Array2::from_row_major(arr.width(), arr.height(), new_data).unwrap();
+/// Returns array2 of rgbf32, used for decompression
+/// 
+/// # Arguments:
+/// * array2 of ypbpr 
+pub fn from_ypbpr(arr: &Array2<YPbPr>) -> Array2<RgbF32> {
+    from_component_video(arr, &Bt601)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    #[test]
+    fn transforms_invert() {
+        let samples = [0.0, 0.2, 0.5, 0.9, 1.0];
+        for space in [ColorSpace::Bt601, ColorSpace::Bt709, ColorSpace::YCoCgR] {
+            assert_eq!(ColorSpace::from_id(space.id()), Some(space));
+            assert_eq!(space.to_string().parse(), Ok(space));
 
+            let t = space.transform();
+            for (r, g, b) in samples.iter().flat_map(|&r| samples.iter().flat_map(move |&g| samples.map(|b| (r, g, b)))) {
+                let cv = t.forward(&RgbF32::new(r, g, b));
+                assert!(cv.pb.abs() <= 0.5 + 1e-6 && cv.pr.abs() <= 0.5 + 1e-6, "{} {}", space, cv);
+                let rgb = t.inverse(cv);
+                let error = (rgb.red - r).abs() + (rgb.green - g).abs() + (rgb.blue - b).abs();
+                assert!(error < 1e-4, "{}: {:?} became {}", space, (r, g, b), rgb);
+            }
+        }
+    }
 
+    #[test]
+    fn ycocg_r_is_reversible_on_integers() {
+        for r in (0..256).step_by(5) {
+            for g in (0..256).step_by(3) {
+                for b in (0..256).step_by(7) {
+                    let (y, co, cg) = YCoCgR::forward_int(r, g, b);
+                    assert_eq!(YCoCgR::inverse_int(y, co, cg), (r, g, b));
+                }
+            }
+        }
+    }
+}
@@ -41,14 +41,24 @@ impl RgbF32 {
 /// Returns array2 of type rgbf32, used for compression
 /// 
 /// # Arguments:
-/// * array2 of type rg
+/// * array2 of type rg with denominator 255
 pub fn to_rgbf32(arr: &Array2<Rgb>) -> Array2<RgbF32> {
+    to_rgbf32_with_denominator(arr, 255)
+}
+
+/// Returns array2 of type rgbf32 with every sample normalised to [0, 1]
+///
+/// # Arguments:
+/// * `arr`: array2 of type rgb
+/// * `denominator`: the largest sample value of the input image
+pub fn to_rgbf32_with_denominator(arr: &Array2<Rgb>, denominator: u16) -> Array2<RgbF32> {
+    let scale = denominator as f32;
     let new_data: Vec<RgbF32> = arr
     .iter_row_major()
     .map(|(_, _, element)| {
-        let r = element.red as f32 / 255.0;
-        let g = element.green as f32 / 255.0;
-        let b = element.blue as f32 / 255.0;
+        let r = element.red as f32 / scale;
+        let g = element.green as f32 / scale;
+        let b = element.blue as f32 / scale;
         RgbF32 {
             red: r,
             green: g,
@@ -57,27 +67,64 @@ pub fn to_rgbf32(arr: &Array2<Rgb>) -> Array2<RgbF32> {
     })
     .collect();
 
-    return Array2::from_row_major(arr.width(), arr.height(), new_data).unwrap();
+    Array2::from_row_major(arr.width(), arr.height(), new_data).unwrap()
 }
 
-/// Returns array2 of type rgb, used for decompression
-/// 
+/// Returns array2 of type rgb with denominator 255, used for decompression
+///
 /// # Arguments:
 /// * array2 of type rgbf32
 pub fn from_rgb32(arr: &Array2<RgbF32>) -> Array2<Rgb> {
-    let new_data: Vec<Rgb> = arr
-    .iter_row_major()
-    .map(|(_, _, element)| {
-        let r = element.red * 255.0;
-        let g = element.green * 255.0;
-        let b = element.blue * 255.0;
-        Rgb {
-            red: r as u16,
-            green: g as u16,
-            blue: b as u16,
+    from_rgb32_with_denominator(arr, 255).0
+}
+
+/// Converts pixels in [0, 1] to integer samples in [0, `denominator`], rounding to the
+/// nearest integer. Colour-space conversions can overshoot, so values outside [0, 1] are
+/// clamped, and the number of samples that had to be clamped is returned.
+/// # Returns:
+/// * `(Array2<Rgb>, usize)`: the pixels, and the number of clipped samples
+///
+/// # Arguments:
+/// * `arr`: array2 of type rgbf32
+/// * `denominator`: the largest sample value of the output image
+pub fn from_rgb32_with_denominator(arr: &Array2<RgbF32>, denominator: u16) -> (Array2<Rgb>, usize) {
+    let scale = denominator as f32;
+    let mut clipped = 0;
+    let mut to_sample = |value: f32| {
+        let scaled = (value * scale).round();
+        if !(0.0..=scale).contains(&scaled) {
+            clipped += 1;
         }
-    })
-    .collect();
+        scaled.clamp(0.0, scale) as u16
+    };
+
+    let new_data: Vec<Rgb> = arr
+        .iter_row_major()
+        .map(|(_, _, element)| Rgb {
+            red: to_sample(element.red),
+            green: to_sample(element.green),
+            blue: to_sample(element.blue),
+        })
+        .collect();
+
+    (Array2::from_row_major(arr.width(), arr.height(), new_data).unwrap(), clipped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rounds_and_clamps() {
+        let pixels = vec![RgbF32::new(0.999, -0.2, 1.3), RgbF32::new(0.5, 0.0, 1.0)];
+        let arr = Array2::from_row_major(2, 1, pixels).unwrap();
 
-    return Array2::from_row_major(arr.width(), arr.height(), new_data).unwrap();
+        let (out, clipped) = from_rgb32_with_denominator(&arr, 255);
+        let samples: Vec<_> = out.data().iter().map(|p| (p.red, p.green, p.blue)).collect();
+        assert_eq!(samples, vec![(255, 0, 255), (128, 0, 255)]);
+        assert_eq!(clipped, 2);
+
+        let (out, _) = from_rgb32_with_denominator(&arr, 65535);
+        assert_eq!(out.data()[1].red, 32768);
+    }
 }
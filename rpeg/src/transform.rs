@@ -0,0 +1,48 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// The block transform applied to the luminance (and, for the DCT, the chroma) of an image
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Transform {
+    /// The original 2x2 transform: one word of a, b, c, d, pb, pr per 2x2 block
+    #[default]
+    Haar2x2,
+    /// An 8x8 DCT-II of each plane, quantized with per-coefficient tables
+    Dct8x8,
+    /// Integer 2x2 lifting of YCoCg-R planes with entropy-coded residuals, which
+    /// reproduces the image exactly
+    Lossless,
+}
+
+impl Transform {
+    /// Width and height of the blocks the transform works on
+    pub fn block_size(&self) -> usize {
+        match self {
+            Transform::Haar2x2 | Transform::Lossless => 2,
+            Transform::Dct8x8 => crate::dct::N,
+        }
+    }
+}
+
+impl fmt::Display for Transform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Transform::Haar2x2 => write!(f, "haar2x2"),
+            Transform::Dct8x8 => write!(f, "dct8x8"),
+            Transform::Lossless => write!(f, "lossless"),
+        }
+    }
+}
+
+impl FromStr for Transform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "haar2x2" | "2x2" => Ok(Transform::Haar2x2),
+            "dct8x8" | "dct" => Ok(Transform::Dct8x8),
+            "lossless" => Ok(Transform::Lossless),
+            _ => Err(format!("unknown transform `{}`, expected 2x2, dct, or lossless", s)),
+        }
+    }
+}